@@ -0,0 +1,82 @@
+use axum::http::StatusCode;
+use common::{
+    auth::{hash_token, Scope, BOT_TOKEN_PREFIX, PERSONAL_TOKEN_PREFIX},
+    models::{ApiToken, ApiTokenKind},
+};
+use mongodb::{
+    bson::{doc, oid::ObjectId},
+    Collection, Database,
+};
+
+use crate::auth::generate_refresh_token;
+
+// Bots one user may own
+pub const MAX_BOTS_PER_OWNER: u64 = 25;
+// Personal access tokens one user may hold
+pub const MAX_PERSONAL_TOKENS: u64 = 50;
+
+// Bots get addresses nobody can receive mail at (RFC 2606), so no email flow reaches them
+pub fn bot_email(bot_id: &ObjectId) -> String {
+    format!("{}@bots.invalid", bot_id.to_hex())
+}
+
+// A new token of `kind`: its prefix followed by 32 random bytes, hex encoded
+pub fn generate(kind: ApiTokenKind) -> String {
+    let prefix = match kind {
+        ApiTokenKind::Bot => BOT_TOKEN_PREFIX,
+        ApiTokenKind::Personal => PERSONAL_TOKEN_PREFIX,
+    };
+    format!("{}{}", prefix, generate_refresh_token())
+}
+
+fn internal_error(e: impl ToString) -> (StatusCode, String) {
+    (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+}
+
+// Stores a new token for `user_id` and returns it along with its value, which is only
+// stored hashed and can't be shown again
+pub async fn create(
+    db: &Database,
+    user_id: ObjectId,
+    kind: ApiTokenKind,
+    name: String,
+    scopes: Vec<Scope>,
+    expires_at: Option<chrono::DateTime<chrono::Utc>>,
+) -> Result<(ApiToken, String), (StatusCode, String)> {
+    let value = generate(kind);
+    let mut token = ApiToken {
+        id: None,
+        user_id,
+        kind,
+        name,
+        token_hash: hash_token(&value),
+        scopes,
+        created_at: chrono::Utc::now(),
+        last_used_at: None,
+        expires_at,
+    };
+
+    let tokens: Collection<ApiToken> = db.collection("api_tokens");
+    let result = tokens
+        .insert_one(&token, None)
+        .await
+        .map_err(internal_error)?;
+    token.id = result.inserted_id.as_object_id();
+    Ok((token, value))
+}
+
+// Deletes every token the user has of `kind`; core-service stops accepting them at once,
+// including on open gateway connections at their next check
+pub async fn delete_all(
+    db: &Database,
+    user_id: ObjectId,
+    kind: ApiTokenKind,
+) -> Result<(), (StatusCode, String)> {
+    let tokens: Collection<ApiToken> = db.collection("api_tokens");
+    let kind = mongodb::bson::to_bson(&kind).map_err(internal_error)?;
+    tokens
+        .delete_many(doc! { "user_id": user_id, "kind": kind }, None)
+        .await
+        .map_err(internal_error)?;
+    Ok(())
+}
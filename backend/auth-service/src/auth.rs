@@ -1,16 +1,15 @@
 use argon2::{
-    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    password_hash::{
+        rand_core::{OsRng, RngCore},
+        PasswordHash, PasswordHasher, PasswordVerifier, SaltString,
+    },
     Argon2,
 };
-use jsonwebtoken::{encode, EncodingKey, Header};
-use serde::{Deserialize, Serialize};
-use std::time::{SystemTime, UNIX_EPOCH};
+use common::{auth::Claims, Error};
+use jsonwebtoken::{encode, Header};
+use mongodb::bson::oid::ObjectId;
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Claims {
-    pub sub: String, // user id
-    pub exp: usize,
-}
+use crate::keys::key_set;
 
 pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
     let salt = SaltString::generate(&mut OsRng);
@@ -26,20 +25,27 @@ pub fn verify_password(password: &str, hash: &str) -> Result<bool, argon2::passw
         .is_ok())
 }
 
-pub fn create_jwt(user_id: &str) -> Result<String, jsonwebtoken::errors::Error> {
-    let expiration = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs() as usize
-        + 3600; // 1 hour
-    let claims = Claims {
-        sub: user_id.to_owned(),
-        exp: expiration,
-    };
-    let secret = std::env::var("JWT_SECRET").expect("JWT_SECRET must be set");
-    encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(secret.as_ref()),
-    )
+pub fn create_jwt(user_id: &ObjectId, session_id: &ObjectId) -> Result<String, jsonwebtoken::errors::Error> {
+    let claims = Claims::new(user_id, session_id);
+    let key = key_set().signing_key();
+    let mut header = Header::new(key.algorithm);
+    header.kid = Some(key.kid.clone());
+    encode(&header, &claims, key.encoding_key())
+}
+
+// Validates an access token signed by any of this service's keys and returns its claims
+pub fn verify_jwt(token: &str) -> Result<Claims, Error> {
+    let kid = common::auth::token_kid(token)?;
+    let key = key_set().find(&kid).ok_or(Error::InvalidToken)?;
+    key.verifying_key().verify(token)
+}
+
+// How long a refresh token can be used before the user has to log in again
+pub const REFRESH_TOKEN_TTL_DAYS: i64 = 30;
+
+// Opaque refresh token: 32 random bytes, hex encoded
+pub fn generate_refresh_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    hex::encode(bytes)
 }
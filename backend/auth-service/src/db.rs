@@ -1,5 +1,10 @@
-use mongodb::{options::IndexOptions, Client, Database, IndexModel};
-use mongodb::bson::doc;
+use futures_util::TryStreamExt;
+use mongodb::{
+    bson::{doc, Document},
+    error::{ErrorKind, WriteFailure},
+    options::{FindOptions, IndexOptions, UpdateOptions},
+    Client, Collection, Database, IndexModel,
+};
 
 pub async fn get_database() -> mongodb::error::Result<Database> {
     let mongo_uri = std::env::var("MONGO_URI").expect("MONGO_URI must be set");
@@ -8,6 +13,7 @@ pub async fn get_database() -> mongodb::error::Result<Database> {
     
     // Create indexes for performance
     create_indexes(&db).await?;
+    migrate_emails(&db).await?;
     
     Ok(db)
 }
@@ -22,6 +28,134 @@ async fn create_indexes(db: &Database) -> mongodb::error::Result<()> {
             .build(),
         None
     ).await?;
+
+    // Refresh tokens are looked up by hash, and revoked by family
+    let refresh_tokens = db.collection::<mongodb::bson::Document>("refresh_tokens");
+    refresh_tokens.create_index(
+        IndexModel::builder()
+            .keys(doc! { "token_hash": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build(),
+        None
+    ).await?;
+    refresh_tokens.create_index(
+        IndexModel::builder()
+            .keys(doc! { "family_id": 1 })
+            .build(),
+        None
+    ).await?;
+
+    // One authenticator per user
+    let totp_factors = db.collection::<mongodb::bson::Document>("totp_factors");
+    totp_factors.create_index(
+        IndexModel::builder()
+            .keys(doc! { "user_id": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build(),
+        None
+    ).await?;
+
+    // Passkeys are looked up by credential id at login, and listed per user
+    let webauthn_credentials = db.collection::<mongodb::bson::Document>("webauthn_credentials");
+    webauthn_credentials.create_index(
+        IndexModel::builder()
+            .keys(doc! { "credential_id": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build(),
+        None
+    ).await?;
+    webauthn_credentials.create_index(
+        IndexModel::builder()
+            .keys(doc! { "user_id": 1 })
+            .build(),
+        None
+    ).await?;
+
+    // One link per provider account
+    let oidc_identities = db.collection::<mongodb::bson::Document>("oidc_identities");
+    oidc_identities.create_index(
+        IndexModel::builder()
+            .keys(doc! { "provider": 1, "subject": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build(),
+        None
+    ).await?;
+
+    // Bot tokens and personal access tokens are looked up by hash, and listed per user
+    let api_tokens = db.collection::<mongodb::bson::Document>("api_tokens");
+    api_tokens.create_index(
+        IndexModel::builder()
+            .keys(doc! { "token_hash": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build(),
+        None
+    ).await?;
+    api_tokens.create_index(
+        IndexModel::builder()
+            .keys(doc! { "user_id": 1 })
+            .build(),
+        None
+    ).await?;
+
+    // Bots are listed per owner
+    users.create_index(
+        IndexModel::builder()
+            .keys(doc! { "owner_id": 1 })
+            .build(),
+        None
+    ).await?;
+
+    // Sessions are listed per user
+    let sessions = db.collection::<mongodb::bson::Document>("sessions");
+    sessions.create_index(
+        IndexModel::builder()
+            .keys(doc! { "user_id": 1 })
+            .build(),
+        None
+    ).await?;
     
     Ok(())
 }
+
+// Emails used to be stored as typed, so the same address could register once per spelling.
+// Lowercases them, once. An address whose lowercase form another user already has is left
+// alone and reported, as merging the two accounts is for an admin to decide.
+async fn migrate_emails(db: &Database) -> mongodb::error::Result<()> {
+    let migrations: Collection<Document> = db.collection("migrations");
+    if migrations.find_one(doc! { "_id": "emails" }, None).await?.is_some() {
+        return Ok(());
+    }
+
+    let users: Collection<Document> = db.collection("users");
+    let options = FindOptions::builder().projection(doc! { "email": 1 }).build();
+    let mut cursor = users.find(doc! { "email": { "$regex": "[A-Z]" } }, options).await?;
+    let mut migrated = 0;
+    while let Some(user) = cursor.try_next().await? {
+        let (Ok(user_id), Ok(email)) = (user.get_object_id("_id"), user.get_str("email")) else {
+            continue;
+        };
+        let result = users
+            .update_one(doc! { "_id": user_id }, doc! { "$set": { "email": email.to_lowercase() } }, None)
+            .await;
+        match result {
+            Ok(_) => migrated += 1,
+            Err(e) if matches!(&*e.kind, ErrorKind::Write(WriteFailure::WriteError(error)) if error.code == 11000) => {
+                eprintln!("⚠️ Could not lowercase the email of user {}: another user has the same address", user_id);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    let now = mongodb::bson::to_bson(&chrono::Utc::now())?;
+    // Upserted, as another instance may be finishing the same migration
+    migrations
+        .update_one(
+            doc! { "_id": "emails" },
+            doc! { "$set": { "completed_at": now } },
+            UpdateOptions::builder().upsert(true).build(),
+        )
+        .await?;
+    println!("✅ Lowercased {} email addresses", migrated);
+
+    Ok(())
+}
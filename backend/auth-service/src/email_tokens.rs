@@ -0,0 +1,118 @@
+use axum::http::StatusCode;
+use common::Error;
+use jsonwebtoken::{decode, encode, Header, Validation};
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{keys::key_set, models::User};
+
+// What an emailed token allows. Each purpose is its own JWT audience, so a token for
+// one can't be used for the other, nor as an access token.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Purpose {
+    VerifyEmail,
+    ResetPassword,
+    UnlockAccount,
+}
+
+impl Purpose {
+    fn audience(self) -> &'static str {
+        match self {
+            Purpose::VerifyEmail => "webchat:verify-email",
+            Purpose::ResetPassword => "webchat:reset-password",
+            Purpose::UnlockAccount => "webchat:unlock-account",
+        }
+    }
+
+    pub fn ttl(self) -> chrono::Duration {
+        match self {
+            Purpose::VerifyEmail => chrono::Duration::hours(48),
+            Purpose::ResetPassword => chrono::Duration::minutes(30),
+            Purpose::UnlockAccount => chrono::Duration::hours(1),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EmailTokenClaims {
+    pub sub: String,
+    pub aud: String,
+    // Unique id, recorded when the token is used so it works once
+    pub jti: String,
+    pub exp: usize,
+    // The address the link was sent to
+    pub email: String,
+    // Fingerprint of the password hash a reset token was issued against
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pwd: Option<String>,
+}
+
+impl EmailTokenClaims {
+    pub fn user_id(&self) -> Result<ObjectId, Error> {
+        ObjectId::parse_str(&self.sub).map_err(|_| Error::InvalidToken)
+    }
+}
+
+// Changes whenever the password does, so a reset link stops working once it was used
+// or the password was changed some other way
+pub fn password_fingerprint(password_hash: &str) -> String {
+    hex::encode(&Sha256::digest(password_hash.as_bytes())[..16])
+}
+
+// Signs a token for `purpose` with the current access token signing key
+pub fn issue(user: &User, purpose: Purpose) -> Result<String, jsonwebtoken::errors::Error> {
+    let claims = EmailTokenClaims {
+        sub: user.id.unwrap().to_hex(),
+        aud: purpose.audience().to_string(),
+        jti: ObjectId::new().to_hex(),
+        exp: (chrono::Utc::now() + purpose.ttl()).timestamp() as usize,
+        email: user.email.clone(),
+        pwd: (purpose == Purpose::ResetPassword).then(|| password_fingerprint(&user.password_hash)),
+    };
+    let key = key_set().signing_key();
+    let mut header = Header::new(key.algorithm);
+    header.kid = Some(key.kid.clone());
+    encode(&header, &claims, key.encoding_key())
+}
+
+// Checks the signature, expiry and purpose of a token
+pub fn verify(token: &str, purpose: Purpose) -> Result<EmailTokenClaims, Error> {
+    let kid = common::auth::token_kid(token)?;
+    let key = key_set().find(&kid).ok_or(Error::InvalidToken)?.verifying_key();
+    let mut validation = Validation::new(key.algorithm);
+    validation.set_audience(&[purpose.audience()]);
+    validation.set_required_spec_claims(&["exp", "aud", "sub"]);
+    decode::<EmailTokenClaims>(token, &key.key, &validation)
+        .map(|data| data.claims)
+        .map_err(|_| Error::InvalidToken)
+}
+
+// Records the token as used; false if it already was
+pub async fn consume(
+    redis: &redis::Client,
+    claims: &EmailTokenClaims,
+) -> Result<bool, (StatusCode, String)> {
+    let ttl = (claims.exp as i64 - chrono::Utc::now().timestamp()).max(1);
+    let mut conn = redis
+        .get_multiplexed_async_connection()
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let stored: Option<String> = redis::cmd("SET")
+        .arg(format!("email_token:used:{}", claims.jti))
+        .arg(1)
+        .arg("NX")
+        .arg("EX")
+        .arg(ttl)
+        .query_async(&mut conn)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(stored.is_some())
+}
+
+// Whether logins must wait until the email address is verified (REQUIRE_EMAIL_VERIFICATION)
+pub fn verification_required() -> bool {
+    std::env::var("REQUIRE_EMAIL_VERIFICATION")
+        .map(|value| value == "true" || value == "1")
+        .unwrap_or(false)
+}
@@ -1,25 +1,48 @@
-use axum::{extract::State, http::StatusCode, Json};
-use mongodb::{bson::doc, Collection};
+use axum::{
+    extract::{ConnectInfo, Path, State},
+    http::{header, HeaderMap, StatusCode},
+    Extension, Json,
+};
+use common::auth::hash_token;
+use futures_util::TryStreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId},
+    options::{FindOptions, ReplaceOptions},
+    Collection,
+};
+use std::{net::SocketAddr, sync::Arc};
 use validator::Validate;
 
 use crate::{
-    auth::{create_jwt, hash_password, verify_password},
+    api_tokens,
+    auth::{create_jwt, generate_refresh_token, hash_password, REFRESH_TOKEN_TTL_DAYS},
+    email_tokens::{self, Purpose},
+    keys::key_set,
+    mailer::{self, Mailer},
+    mfa::{self, MfaTicket},
     models::*,
+    oidc::{self, ExternalIdentity, ProviderSummary, Providers},
+    sessions::{client_details, create_session, revoke_sessions, touch_session, AuthUser},
+    throttle::{self, LoginAttempt},
+    webauthn::{self, relying_party, CeremonyError, PendingCeremony},
 };
 
 pub async fn register(
-    State(db): State<mongodb::Database>,
+    State((db, _redis)): State<(mongodb::Database, redis::Client)>,
+    Extension(mailer): Extension<Arc<dyn Mailer>>,
     Json(payload): Json<RegisterRequest>,
 ) -> Result<Json<&'static str>, (StatusCode, String)> {
     // Validate input
-    payload.validate()
+    payload
+        .validate()
         .map_err(|e| (StatusCode::BAD_REQUEST, format!("Validation error: {}", e)))?;
 
     let users: Collection<User> = db.collection("users");
+    let email = normalize_email(&payload.email);
 
     // Check if user exists
     if users
-        .find_one(doc! { "email": &payload.email }, None)
+        .find_one(doc! { "email": &email }, None)
         .await
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
         .is_some()
@@ -30,53 +53,1499 @@ pub async fn register(
     let password_hash = hash_password(&payload.password)
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
 
-    let user = User {
+    let mut user = User {
         id: None,
         username: payload.username,
-        email: payload.email,
+        email,
         password_hash,
         created_at: chrono::Utc::now(),
+        last_seen: None,
+        email_verified_at: None,
+        bot: false,
+        owner_id: None,
     };
 
-    users
-        .insert_one(user, None)
+    let result = users
+        .insert_one(&user, None)
         .await
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    user.id = result.inserted_id.as_object_id();
+
+    send_verification_email(&mailer, &user)?;
 
     Ok(Json("User registered successfully"))
 }
 
 pub async fn login(
-    State(db): State<mongodb::Database>,
+    State((db, redis)): State<(mongodb::Database, redis::Client)>,
+    Extension(mailer): Extension<Arc<dyn Mailer>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Json(payload): Json<LoginRequest>,
-) -> Result<Json<AuthResponse>, (StatusCode, String)> {
+) -> Result<Json<LoginResponse>, (StatusCode, String)> {
     // Validate input
-    payload.validate()
+    payload
+        .validate()
         .map_err(|e| (StatusCode::BAD_REQUEST, format!("Validation error: {}", e)))?;
 
+    let (_, ip) = client_details(&headers, peer);
+    let attempt = LoginAttempt::new(&ip, &payload.email);
+    throttle::check(&redis, &attempt).await?;
+
     let users: Collection<User> = db.collection("users");
 
+    // Bots have no password to log in with
     let user = users
-        .find_one(doc! { "email": &payload.email }, None)
+        .find_one(
+            doc! { "email": normalize_email(&payload.email), "bot": { "$ne": true } },
+            None,
+        )
         .await
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
-        .ok_or((StatusCode::UNAUTHORIZED, "Invalid credentials".to_string()))?;
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    // Unknown addresses and wrong passwords take the same path, and the same time
+    let password_hash = user.as_ref().map(|user| user.password_hash.as_str());
+    let valid = throttle::verify_password_or_dummy(&payload.password, password_hash)?;
+    let user = match user {
+        Some(user) if valid => user,
+        user => {
+            record_login_failure(&redis, mailer, &attempt, user.as_ref(), &ip).await?;
+            return Err((StatusCode::UNAUTHORIZED, "Invalid credentials".to_string()));
+        }
+    };
+    throttle::clear(&redis, &attempt).await?;
 
-    if !verify_password(&payload.password, &user.password_hash)
+    Ok(Json(
+        finish_login(&db, &redis, user, payload.device_name, &headers, peer).await?,
+    ))
+}
+
+// Counts a failed login and, if that locks the account, emails its owner an unlock link
+async fn record_login_failure(
+    redis: &redis::Client,
+    mailer: Arc<dyn Mailer>,
+    attempt: &LoginAttempt,
+    user: Option<&User>,
+    ip: &str,
+) -> Result<(), (StatusCode, String)> {
+    if !throttle::record_failure(redis, attempt).await? {
+        return Ok(());
+    }
+    eprintln!("⚠️ Locked account {} after repeated failed logins, last from {}", attempt.account(), ip);
+    if let Some(user) = user {
+        let token = email_tokens::issue(user, Purpose::UnlockAccount)
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        mailer::send_in_background(
+            mailer,
+            mailer::unlock_email(&user.email, &user.username, &token),
+        );
+    }
+    Ok(())
+}
+
+// With two-factor authentication on, the first factor only earns a ticket for /login/mfa;
+// otherwise the login starts a session
+async fn finish_login(
+    db: &mongodb::Database,
+    redis: &redis::Client,
+    user: User,
+    device_name: Option<String>,
+    headers: &HeaderMap,
+    peer: SocketAddr,
+) -> Result<LoginResponse, (StatusCode, String)> {
+    if mfa::confirmed_factor(db, user.id.unwrap()).await?.is_some() {
+        let pending = MfaTicket {
+            user_id: user.id.unwrap(),
+            device_name,
+        };
+        let ticket = mfa::create_ticket(redis, &pending).await?;
+        return Ok(LoginResponse::MfaRequired(MfaChallenge {
+            mfa_required: true,
+            ticket,
+            methods: vec!["totp", "recovery_code"],
+            expires_in: mfa::MFA_TICKET_TTL_SECONDS,
+        }));
+    }
+
+    let response = start_session(db, user, device_name, headers, peer).await?;
+    Ok(LoginResponse::Authenticated(response))
+}
+
+// Second login step: trades the ticket from /login and a TOTP or recovery code for tokens
+pub async fn login_mfa(
+    State((db, redis)): State<(mongodb::Database, redis::Client)>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(payload): Json<MfaLoginRequest>,
+) -> Result<Json<AuthResponse>, (StatusCode, String)> {
+    payload
+        .validate()
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Validation error: {}", e)))?;
+
+    let invalid_ticket = || {
+        (
+            StatusCode::UNAUTHORIZED,
+            "Invalid or expired ticket".to_string(),
+        )
+    };
+    let ticket = mfa::load_ticket(&redis, &payload.ticket)
+        .await?
+        .ok_or_else(invalid_ticket)?;
+
+    let accepted = match (&payload.code, &payload.recovery_code) {
+        (Some(code), None) => match mfa::confirmed_factor(&db, ticket.user_id).await? {
+            Some(factor) => mfa::accept_totp_code(&db, &factor, code).await?,
+            None => false,
+        },
+        (None, Some(recovery_code)) => {
+            mfa::accept_recovery_code(&db, ticket.user_id, recovery_code).await?
+        }
+        _ => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Provide either code or recovery_code".to_string(),
+            ))
+        }
+    };
+    if !accepted {
+        mfa::record_failed_attempt(&redis, &payload.ticket).await?;
+        return Err((StatusCode::UNAUTHORIZED, "Invalid code".to_string()));
+    }
+    if !mfa::consume_ticket(&redis, &payload.ticket).await? {
+        return Err(invalid_ticket());
+    }
+
+    let users: Collection<User> = db.collection("users");
+    let user = users
+        .find_one(doc! { "_id": ticket.user_id }, None)
+        .await
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
-    {
-        return Err((StatusCode::UNAUTHORIZED, "Invalid credentials".to_string()));
+        .ok_or_else(invalid_ticket)?;
+
+    Ok(Json(
+        start_session(&db, user, ticket.device_name, &headers, peer).await?,
+    ))
+}
+
+// Records the login as a session and issues its first access and refresh tokens
+async fn start_session(
+    db: &mongodb::Database,
+    user: User,
+    device_name: Option<String>,
+    headers: &HeaderMap,
+    peer: SocketAddr,
+) -> Result<AuthResponse, (StatusCode, String)> {
+    if user.email_verified_at.is_none() && email_tokens::verification_required() {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Email address not verified".to_string(),
+        ));
     }
 
-    let token = create_jwt(&user.id.unwrap().to_string())
+    let user_id = user.id.unwrap();
+    let (user_agent, ip) = client_details(headers, peer);
+    let session_id = create_session(db, user_id, device_name, user_agent, ip).await?;
+
+    let token = create_jwt(&user_id, &session_id)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    // Every login starts a new refresh token family, identified by the session
+    let refresh_token = issue_refresh_token(db, user_id, session_id).await?;
+
+    Ok(AuthResponse {
+        token,
+        refresh_token,
+        user: UserResponse {
+            id: user_id.to_string(),
+            email_verified: user.email_verified_at.is_some(),
+            bot: user.bot,
+            username: user.username,
+            email: user.email,
+        },
+    })
+}
+
+pub async fn refresh_token(
+    State((db, redis)): State<(mongodb::Database, redis::Client)>,
+    Json(payload): Json<RefreshRequest>,
+) -> Result<Json<AuthResponse>, (StatusCode, String)> {
+    payload
+        .validate()
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Validation error: {}", e)))?;
+
+    let refresh_tokens: Collection<RefreshToken> = db.collection("refresh_tokens");
+    let token_hash = hash_token(&payload.refresh_token);
+    let now = chrono::Utc::now();
+    let used_at = mongodb::bson::to_bson(&now)
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
 
+    // Claim the token atomically, so two concurrent refreshes can't both rotate it
+    let claimed = refresh_tokens
+        .find_one_and_update(
+            doc! { "token_hash": &token_hash, "used_at": null, "revoked": false },
+            doc! { "$set": { "used_at": used_at } },
+            None,
+        )
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    let Some(current) = claimed else {
+        // A known token that was already used means it was replayed: whoever holds it may
+        // have stolen it, so end the login it came from along with all of its tokens
+        if let Some(replayed) = refresh_tokens
+            .find_one(doc! { "token_hash": &token_hash }, None)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        {
+            refresh_tokens
+                .update_many(
+                    doc! { "family_id": replayed.family_id },
+                    doc! { "$set": { "revoked": true } },
+                    None,
+                )
+                .await
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+            revoke_sessions(
+                &db,
+                &redis,
+                replayed.user_id,
+                doc! { "_id": replayed.family_id },
+            )
+            .await?;
+            if !replayed.revoked {
+                eprintln!(
+                    "⚠️ Refresh token reuse detected for user {}, revoked token family {}",
+                    replayed.user_id, replayed.family_id
+                );
+            }
+        }
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            "Invalid refresh token".to_string(),
+        ));
+    };
+
+    if current.expires_at <= now {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            "Refresh token expired".to_string(),
+        ));
+    }
+    if !touch_session(&db, current.family_id).await? {
+        return Err((StatusCode::UNAUTHORIZED, "Session revoked".to_string()));
+    }
+
+    let users: Collection<User> = db.collection("users");
+    let user = users
+        .find_one(doc! { "_id": current.user_id }, None)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or((
+            StatusCode::UNAUTHORIZED,
+            "Invalid refresh token".to_string(),
+        ))?;
+
+    let token = create_jwt(&current.user_id, &current.family_id)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let refresh_token = issue_refresh_token(&db, current.user_id, current.family_id).await?;
+
     Ok(Json(AuthResponse {
         token,
+        refresh_token,
         user: UserResponse {
-            id: user.id.unwrap().to_string(),
+            id: current.user_id.to_string(),
+            email_verified: user.email_verified_at.is_some(),
+            bot: user.bot,
             username: user.username,
             email: user.email,
         },
     }))
 }
+
+// Stores a new refresh token in the family and returns it; only its hash is kept
+async fn issue_refresh_token(
+    db: &mongodb::Database,
+    user_id: ObjectId,
+    family_id: ObjectId,
+) -> Result<String, (StatusCode, String)> {
+    let token = generate_refresh_token();
+    let now = chrono::Utc::now();
+    let refresh_token = RefreshToken {
+        id: None,
+        user_id,
+        family_id,
+        token_hash: hash_token(&token),
+        created_at: now,
+        expires_at: now + chrono::Duration::days(REFRESH_TOKEN_TTL_DAYS),
+        used_at: None,
+        revoked: false,
+    };
+
+    let refresh_tokens: Collection<RefreshToken> = db.collection("refresh_tokens");
+    refresh_tokens
+        .insert_one(refresh_token, None)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(token)
+}
+
+pub async fn list_sessions(
+    State((db, _redis)): State<(mongodb::Database, redis::Client)>,
+    user: AuthUser,
+) -> Result<Json<Vec<SessionResponse>>, (StatusCode, String)> {
+    let sessions: Collection<Session> = db.collection("sessions");
+    let options = FindOptions::builder()
+        .sort(doc! { "last_used_at": -1 })
+        .build();
+    let cursor = sessions
+        .find(
+            doc! { "user_id": user.user_id, "revoked_at": null },
+            Some(options),
+        )
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let sessions: Vec<Session> = cursor
+        .try_collect()
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(
+        sessions
+            .into_iter()
+            .map(|session| SessionResponse {
+                id: session.id.unwrap().to_string(),
+                current: session.id == Some(user.session_id),
+                name: session.name,
+                user_agent: session.user_agent,
+                ip: session.ip,
+                created_at: session.created_at,
+                last_used_at: session.last_used_at,
+            })
+            .collect(),
+    ))
+}
+
+pub async fn rename_session(
+    State((db, _redis)): State<(mongodb::Database, redis::Client)>,
+    Path(session_id): Path<String>,
+    user: AuthUser,
+    Json(payload): Json<RenameSessionRequest>,
+) -> Result<Json<&'static str>, (StatusCode, String)> {
+    payload
+        .validate()
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Validation error: {}", e)))?;
+    let session_oid = ObjectId::parse_str(&session_id)
+        .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid session id".to_string()))?;
+
+    let sessions: Collection<Session> = db.collection("sessions");
+    let result = sessions
+        .update_one(
+            doc! { "_id": session_oid, "user_id": user.user_id, "revoked_at": null },
+            doc! { "$set": { "name": payload.name } },
+            None,
+        )
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if result.matched_count == 0 {
+        return Err((StatusCode::NOT_FOUND, "Session not found".to_string()));
+    }
+
+    Ok(Json("Session renamed"))
+}
+
+pub async fn revoke_session(
+    State((db, redis)): State<(mongodb::Database, redis::Client)>,
+    Path(session_id): Path<String>,
+    user: AuthUser,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let session_oid = ObjectId::parse_str(&session_id)
+        .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid session id".to_string()))?;
+
+    if revoke_sessions(&db, &redis, user.user_id, doc! { "_id": session_oid }).await? == 0 {
+        return Err((StatusCode::NOT_FOUND, "Session not found".to_string()));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Logs out everywhere, including the session making the request
+pub async fn revoke_all_sessions(
+    State((db, redis)): State<(mongodb::Database, redis::Client)>,
+    user: AuthUser,
+) -> Result<StatusCode, (StatusCode, String)> {
+    revoke_sessions(&db, &redis, user.user_id, doc! {}).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Starts TOTP enrollment with a new secret; it guards logins once confirmed
+pub async fn enroll_totp(
+    State((db, _redis)): State<(mongodb::Database, redis::Client)>,
+    user: AuthUser,
+) -> Result<Json<TotpEnrollmentResponse>, (StatusCode, String)> {
+    if mfa::confirmed_factor(&db, user.user_id).await?.is_some() {
+        return Err((
+            StatusCode::CONFLICT,
+            "Two-factor authentication is already enabled".to_string(),
+        ));
+    }
+
+    let users: Collection<User> = db.collection("users");
+    let account = users
+        .find_one(doc! { "_id": user.user_id }, None)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or((StatusCode::NOT_FOUND, "User not found".to_string()))?;
+
+    // Starting over replaces any enrollment that was never confirmed
+    let secret = mfa::generate_secret();
+    let factor = TotpFactor {
+        id: None,
+        user_id: user.user_id,
+        secret: secret.clone(),
+        confirmed_at: None,
+        last_used_step: None,
+        recovery_codes: Vec::new(),
+        created_at: chrono::Utc::now(),
+    };
+    let factors: Collection<TotpFactor> = db.collection("totp_factors");
+    factors
+        .replace_one(
+            doc! { "user_id": user.user_id, "confirmed_at": null },
+            factor,
+            ReplaceOptions::builder().upsert(true).build(),
+        )
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(TotpEnrollmentResponse {
+        otpauth_uri: mfa::otpauth_uri(&mfa::issuer(), &account.email, &secret),
+        secret,
+    }))
+}
+
+// Confirms enrollment with a code from the authenticator and returns the recovery codes
+pub async fn confirm_totp(
+    State((db, _redis)): State<(mongodb::Database, redis::Client)>,
+    user: AuthUser,
+    Json(payload): Json<TotpCodeRequest>,
+) -> Result<Json<RecoveryCodesResponse>, (StatusCode, String)> {
+    payload
+        .validate()
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Validation error: {}", e)))?;
+
+    let factors: Collection<TotpFactor> = db.collection("totp_factors");
+    let pending = factors
+        .find_one(doc! { "user_id": user.user_id, "confirmed_at": null }, None)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or((StatusCode::NOT_FOUND, "No pending enrollment".to_string()))?;
+
+    if !mfa::accept_totp_code(&db, &pending, &payload.code).await? {
+        return Err((StatusCode::BAD_REQUEST, "Invalid code".to_string()));
+    }
+
+    let (codes, stored) = mfa::new_recovery_codes();
+    let now = mongodb::bson::to_bson(&chrono::Utc::now())
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let stored = mongodb::bson::to_bson(&stored)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    factors
+        .update_one(
+            doc! { "_id": pending.id, "confirmed_at": null },
+            doc! { "$set": { "confirmed_at": now, "recovery_codes": stored } },
+            None,
+        )
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(RecoveryCodesResponse {
+        recovery_codes: codes,
+    }))
+}
+
+// Replaces the recovery codes, e.g. after using some; requires a current TOTP code
+pub async fn regenerate_recovery_codes(
+    State((db, _redis)): State<(mongodb::Database, redis::Client)>,
+    user: AuthUser,
+    Json(payload): Json<TotpCodeRequest>,
+) -> Result<Json<RecoveryCodesResponse>, (StatusCode, String)> {
+    payload
+        .validate()
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Validation error: {}", e)))?;
+
+    let factor = mfa::confirmed_factor(&db, user.user_id).await?.ok_or((
+        StatusCode::NOT_FOUND,
+        "Two-factor authentication is not enabled".to_string(),
+    ))?;
+    if !mfa::accept_totp_code(&db, &factor, &payload.code).await? {
+        return Err((StatusCode::BAD_REQUEST, "Invalid code".to_string()));
+    }
+
+    let (codes, stored) = mfa::new_recovery_codes();
+    let stored = mongodb::bson::to_bson(&stored)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let factors: Collection<TotpFactor> = db.collection("totp_factors");
+    factors
+        .update_one(
+            doc! { "_id": factor.id },
+            doc! { "$set": { "recovery_codes": stored } },
+            None,
+        )
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(RecoveryCodesResponse {
+        recovery_codes: codes,
+    }))
+}
+
+// Turns two-factor authentication off; requires a TOTP or recovery code
+pub async fn disable_totp(
+    State((db, _redis)): State<(mongodb::Database, redis::Client)>,
+    user: AuthUser,
+    Json(payload): Json<TotpCodeRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    payload
+        .validate()
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Validation error: {}", e)))?;
+
+    let factor = mfa::confirmed_factor(&db, user.user_id).await?.ok_or((
+        StatusCode::NOT_FOUND,
+        "Two-factor authentication is not enabled".to_string(),
+    ))?;
+    let accepted = mfa::accept_totp_code(&db, &factor, &payload.code).await?
+        || mfa::accept_recovery_code(&db, user.user_id, &payload.code).await?;
+    if !accepted {
+        return Err((StatusCode::BAD_REQUEST, "Invalid code".to_string()));
+    }
+
+    let factors: Collection<TotpFactor> = db.collection("totp_factors");
+    factors
+        .delete_many(doc! { "user_id": user.user_id }, None)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Starts registering a passkey for the signed-in user; pass `publicKey` to
+// navigator.credentials.create()
+pub async fn start_passkey_registration(
+    State((db, redis)): State<(mongodb::Database, redis::Client)>,
+    user: AuthUser,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let users: Collection<User> = db.collection("users");
+    let account = users
+        .find_one(doc! { "_id": user.user_id }, None)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or((StatusCode::NOT_FOUND, "User not found".to_string()))?;
+    let existing = user_passkeys(&db, user.user_id).await?;
+
+    let challenge = webauthn::generate_challenge();
+    let pending = PendingCeremony::Registration {
+        user_id: user.user_id,
+    };
+    webauthn::store_challenge(&redis, &challenge, &pending).await?;
+
+    let options = webauthn::creation_options(relying_party(), &account, &challenge, &existing);
+    Ok(Json(serde_json::json!({ "publicKey": options })))
+}
+
+// Verifies the authenticator's response and stores the new passkey
+pub async fn finish_passkey_registration(
+    State((db, redis)): State<(mongodb::Database, redis::Client)>,
+    user: AuthUser,
+    Json(payload): Json<WebauthnRegisterRequest>,
+) -> Result<Json<WebauthnCredentialResponse>, (StatusCode, String)> {
+    payload
+        .validate()
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Validation error: {}", e)))?;
+
+    let verified = webauthn::verify_registration(relying_party(), &payload.credential)
+        .map_err(|e| e.rejection())?;
+    // The challenge must have been issued to this user, for a registration
+    let expected = PendingCeremony::Registration {
+        user_id: user.user_id,
+    };
+    if webauthn::take_challenge(&redis, &verified.challenge).await? != Some(expected) {
+        return Err(CeremonyError::ChallengeMismatch.rejection());
+    }
+
+    let credentials: Collection<WebauthnCredential> = db.collection("webauthn_credentials");
+    if credentials
+        .find_one(doc! { "credential_id": &verified.credential_id }, None)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .is_some()
+    {
+        return Err((
+            StatusCode::CONFLICT,
+            "Passkey already registered".to_string(),
+        ));
+    }
+
+    let mut credential = WebauthnCredential {
+        id: None,
+        user_id: user.user_id,
+        credential_id: verified.credential_id,
+        public_key: verified.public_key,
+        sign_count: verified.sign_count,
+        name: payload.name,
+        aaguid: verified.aaguid,
+        transports: verified.transports,
+        created_at: chrono::Utc::now(),
+        last_used_at: None,
+    };
+    let result = credentials
+        .insert_one(&credential, None)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    credential.id = result.inserted_id.as_object_id();
+
+    Ok(Json(passkey_response(credential)))
+}
+
+// Starts a passwordless login; pass `publicKey` to navigator.credentials.get()
+pub async fn start_passkey_login(
+    State((_db, redis)): State<(mongodb::Database, redis::Client)>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let challenge = webauthn::generate_challenge();
+    webauthn::store_challenge(&redis, &challenge, &PendingCeremony::Authentication).await?;
+
+    let options = webauthn::request_options(relying_party(), &challenge);
+    Ok(Json(serde_json::json!({ "publicKey": options })))
+}
+
+// Completes a passwordless login. A passkey proves possession and user verification
+// on its own, so it is not followed by a TOTP step. Failed assertions count towards the
+// same lockout as failed passwords.
+pub async fn finish_passkey_login(
+    State((db, redis)): State<(mongodb::Database, redis::Client)>,
+    Extension(mailer): Extension<Arc<dyn Mailer>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(payload): Json<WebauthnLoginRequest>,
+) -> Result<Json<AuthResponse>, (StatusCode, String)> {
+    payload
+        .validate()
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Validation error: {}", e)))?;
+
+    let raw_id = payload.credential.raw_id.trim_end_matches('=');
+    let credentials: Collection<WebauthnCredential> = db.collection("webauthn_credentials");
+    let stored = credentials
+        .find_one(doc! { "credential_id": raw_id }, None)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let user = match &stored {
+        Some(stored) => {
+            let users: Collection<User> = db.collection("users");
+            users
+                .find_one(doc! { "_id": stored.user_id }, None)
+                .await
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        }
+        None => None,
+    };
+
+    // Unknown passkeys have no account to lock, so they are counted against a key of
+    // their own and, more to the point, against the IP
+    let (_, ip) = client_details(&headers, peer);
+    let attempt = match &user {
+        Some(user) => LoginAttempt::new(&ip, &user.email),
+        None => LoginAttempt::new(&ip, &format!("passkey:{}", raw_id)),
+    };
+    throttle::check(&redis, &attempt).await?;
+
+    let (Some(stored), Some(user)) = (stored, user) else {
+        record_login_failure(&redis, mailer, &attempt, None, &ip).await?;
+        return Err((StatusCode::UNAUTHORIZED, "Unknown passkey".to_string()));
+    };
+
+    let assertion = match webauthn::verify_assertion(relying_party(), &stored, &payload.credential) {
+        Ok(assertion) => assertion,
+        Err(e) => {
+            record_login_failure(&redis, mailer, &attempt, Some(&user), &ip).await?;
+            return Err(e.rejection());
+        }
+    };
+    if webauthn::take_challenge(&redis, &assertion.challenge).await?
+        != Some(PendingCeremony::Authentication)
+    {
+        record_login_failure(&redis, mailer, &attempt, Some(&user), &ip).await?;
+        return Err(CeremonyError::ChallengeMismatch.rejection());
+    }
+
+    if !webauthn::accept_sign_count(&db, &stored, assertion.sign_count).await? {
+        eprintln!(
+            "⚠️ Passkey {} of user {} presented signature counter {} (stored {}), possible cloned authenticator",
+            stored.credential_id, stored.user_id, assertion.sign_count, stored.sign_count
+        );
+        record_login_failure(&redis, mailer, &attempt, Some(&user), &ip).await?;
+        return Err((StatusCode::UNAUTHORIZED, "Passkey rejected".to_string()));
+    }
+    throttle::clear(&redis, &attempt).await?;
+
+    Ok(Json(
+        start_session(&db, user, payload.device_name, &headers, peer).await?,
+    ))
+}
+
+pub async fn list_passkeys(
+    State((db, _redis)): State<(mongodb::Database, redis::Client)>,
+    user: AuthUser,
+) -> Result<Json<Vec<WebauthnCredentialResponse>>, (StatusCode, String)> {
+    let passkeys = user_passkeys(&db, user.user_id).await?;
+    Ok(Json(passkeys.into_iter().map(passkey_response).collect()))
+}
+
+pub async fn delete_passkey(
+    State((db, _redis)): State<(mongodb::Database, redis::Client)>,
+    Path(credential_id): Path<String>,
+    user: AuthUser,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let credential_oid = ObjectId::parse_str(&credential_id)
+        .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid passkey id".to_string()))?;
+
+    let credentials: Collection<WebauthnCredential> = db.collection("webauthn_credentials");
+    let result = credentials
+        .delete_one(
+            doc! { "_id": credential_oid, "user_id": user.user_id },
+            None,
+        )
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if result.deleted_count == 0 {
+        return Err((StatusCode::NOT_FOUND, "Passkey not found".to_string()));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn user_passkeys(
+    db: &mongodb::Database,
+    user_id: ObjectId,
+) -> Result<Vec<WebauthnCredential>, (StatusCode, String)> {
+    let credentials: Collection<WebauthnCredential> = db.collection("webauthn_credentials");
+    let options = FindOptions::builder()
+        .sort(doc! { "created_at": 1 })
+        .build();
+    credentials
+        .find(doc! { "user_id": user_id }, Some(options))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .try_collect()
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+fn passkey_response(credential: WebauthnCredential) -> WebauthnCredentialResponse {
+    WebauthnCredentialResponse {
+        id: credential.id.map(|id| id.to_string()).unwrap_or_default(),
+        name: credential.name,
+        created_at: credential.created_at,
+        last_used_at: credential.last_used_at,
+    }
+}
+
+pub async fn create_bot(
+    State((db, _redis)): State<(mongodb::Database, redis::Client)>,
+    user: AuthUser,
+    Json(payload): Json<CreateBotRequest>,
+) -> Result<Json<BotTokenResponse>, (StatusCode, String)> {
+    payload
+        .validate()
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Validation error: {}", e)))?;
+
+    let users: Collection<User> = db.collection("users");
+    let owned = users
+        .count_documents(doc! { "owner_id": user.user_id, "bot": true }, None)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if owned >= api_tokens::MAX_BOTS_PER_OWNER {
+        return Err((
+            StatusCode::CONFLICT,
+            format!(
+                "You can own at most {} bots",
+                api_tokens::MAX_BOTS_PER_OWNER
+            ),
+        ));
+    }
+
+    // Bots authenticate with their token only; the password is random and unknown to anyone
+    let password_hash = hash_password(&generate_refresh_token())
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let bot_id = ObjectId::new();
+    let bot = User {
+        id: Some(bot_id),
+        username: payload.username,
+        email: api_tokens::bot_email(&bot_id),
+        password_hash,
+        created_at: chrono::Utc::now(),
+        last_seen: None,
+        email_verified_at: None,
+        bot: true,
+        owner_id: Some(user.user_id),
+    };
+    users
+        .insert_one(&bot, None)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    let (_, token) = api_tokens::create(
+        &db,
+        bot_id,
+        ApiTokenKind::Bot,
+        "Bot token".to_string(),
+        Vec::new(),
+        None,
+    )
+    .await?;
+
+    Ok(Json(BotTokenResponse {
+        bot: bot_response(bot),
+        token,
+    }))
+}
+
+pub async fn list_bots(
+    State((db, _redis)): State<(mongodb::Database, redis::Client)>,
+    user: AuthUser,
+) -> Result<Json<Vec<BotResponse>>, (StatusCode, String)> {
+    let users: Collection<User> = db.collection("users");
+    let options = FindOptions::builder()
+        .sort(doc! { "created_at": 1 })
+        .build();
+    let bots: Vec<User> = users
+        .find(
+            doc! { "owner_id": user.user_id, "bot": true },
+            Some(options),
+        )
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .try_collect()
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(bots.into_iter().map(bot_response).collect()))
+}
+
+// Replaces the bot's token; the old one stops working at once
+pub async fn reset_bot_token(
+    State((db, _redis)): State<(mongodb::Database, redis::Client)>,
+    Path(bot_id): Path<String>,
+    user: AuthUser,
+) -> Result<Json<BotTokenResponse>, (StatusCode, String)> {
+    let bot = owned_bot(&db, user.user_id, &bot_id).await?;
+    let bot_id = bot.id.unwrap();
+
+    api_tokens::delete_all(&db, bot_id, ApiTokenKind::Bot).await?;
+    let (_, token) = api_tokens::create(
+        &db,
+        bot_id,
+        ApiTokenKind::Bot,
+        "Bot token".to_string(),
+        Vec::new(),
+        None,
+    )
+    .await?;
+
+    Ok(Json(BotTokenResponse {
+        bot: bot_response(bot),
+        token,
+    }))
+}
+
+pub async fn delete_bot(
+    State((db, _redis)): State<(mongodb::Database, redis::Client)>,
+    Path(bot_id): Path<String>,
+    user: AuthUser,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let bot = owned_bot(&db, user.user_id, &bot_id).await?;
+    let bot_id = bot.id.unwrap();
+
+    // Tokens first, so the bot can't act while the rest goes. Its server memberships go
+    // with it; clients drop the bot from member lists when they next load them.
+    api_tokens::delete_all(&db, bot_id, ApiTokenKind::Bot).await?;
+    let members: Collection<mongodb::bson::Document> = db.collection("members");
+    members
+        .delete_many(doc! { "user_id": bot_id }, None)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let users: Collection<User> = db.collection("users");
+    users
+        .delete_one(doc! { "_id": bot_id, "bot": true }, None)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn owned_bot(
+    db: &mongodb::Database,
+    owner_id: ObjectId,
+    bot_id: &str,
+) -> Result<User, (StatusCode, String)> {
+    let bot_oid = ObjectId::parse_str(bot_id)
+        .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid bot id".to_string()))?;
+
+    let users: Collection<User> = db.collection("users");
+    users
+        .find_one(
+            doc! { "_id": bot_oid, "owner_id": owner_id, "bot": true },
+            None,
+        )
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "Bot not found".to_string()))
+}
+
+fn bot_response(bot: User) -> BotResponse {
+    BotResponse {
+        id: bot.id.map(|id| id.to_string()).unwrap_or_default(),
+        username: bot.username,
+        owner_id: bot.owner_id.map(|id| id.to_string()).unwrap_or_default(),
+        created_at: bot.created_at,
+    }
+}
+
+pub async fn create_personal_token(
+    State((db, _redis)): State<(mongodb::Database, redis::Client)>,
+    user: AuthUser,
+    Json(payload): Json<CreatePersonalTokenRequest>,
+) -> Result<Json<PersonalTokenResponse>, (StatusCode, String)> {
+    payload
+        .validate()
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Validation error: {}", e)))?;
+
+    let tokens: Collection<ApiToken> = db.collection("api_tokens");
+    let held = tokens
+        .count_documents(doc! { "user_id": user.user_id, "kind": "personal" }, None)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if held >= api_tokens::MAX_PERSONAL_TOKENS {
+        return Err((
+            StatusCode::CONFLICT,
+            format!(
+                "You can have at most {} personal access tokens",
+                api_tokens::MAX_PERSONAL_TOKENS
+            ),
+        ));
+    }
+
+    let mut scopes = payload.scopes;
+    scopes.sort_by_key(|scope| *scope as u8);
+    scopes.dedup();
+    let expires_at = payload
+        .expires_in_days
+        .map(|days| chrono::Utc::now() + chrono::Duration::days(days));
+    let (token, value) = api_tokens::create(
+        &db,
+        user.user_id,
+        ApiTokenKind::Personal,
+        payload.name,
+        scopes,
+        expires_at,
+    )
+    .await?;
+
+    let mut response = personal_token_response(token);
+    response.token = Some(value);
+    Ok(Json(response))
+}
+
+pub async fn list_personal_tokens(
+    State((db, _redis)): State<(mongodb::Database, redis::Client)>,
+    user: AuthUser,
+) -> Result<Json<Vec<PersonalTokenResponse>>, (StatusCode, String)> {
+    let tokens: Collection<ApiToken> = db.collection("api_tokens");
+    let options = FindOptions::builder()
+        .sort(doc! { "created_at": 1 })
+        .build();
+    let held: Vec<ApiToken> = tokens
+        .find(
+            doc! { "user_id": user.user_id, "kind": "personal" },
+            Some(options),
+        )
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .try_collect()
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(
+        held.into_iter().map(personal_token_response).collect(),
+    ))
+}
+
+pub async fn delete_personal_token(
+    State((db, _redis)): State<(mongodb::Database, redis::Client)>,
+    Path(token_id): Path<String>,
+    user: AuthUser,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let token_oid = ObjectId::parse_str(&token_id)
+        .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid token id".to_string()))?;
+
+    let tokens: Collection<ApiToken> = db.collection("api_tokens");
+    let result = tokens
+        .delete_one(
+            doc! { "_id": token_oid, "user_id": user.user_id, "kind": "personal" },
+            None,
+        )
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if result.deleted_count == 0 {
+        return Err((StatusCode::NOT_FOUND, "Token not found".to_string()));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn personal_token_response(token: ApiToken) -> PersonalTokenResponse {
+    PersonalTokenResponse {
+        id: token.id.map(|id| id.to_string()).unwrap_or_default(),
+        name: token.name,
+        scopes: token.scopes,
+        created_at: token.created_at,
+        last_used_at: token.last_used_at,
+        expires_at: token.expires_at,
+        token: None,
+    }
+}
+
+// Sends a new verification link. Always 202, so the response doesn't reveal which
+// addresses have accounts.
+pub async fn resend_verification_email(
+    State((db, _redis)): State<(mongodb::Database, redis::Client)>,
+    Extension(mailer): Extension<Arc<dyn Mailer>>,
+    Json(payload): Json<EmailRequest>,
+) -> Result<(StatusCode, Json<&'static str>), (StatusCode, String)> {
+    payload
+        .validate()
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Validation error: {}", e)))?;
+
+    let users: Collection<User> = db.collection("users");
+    let user = users
+        .find_one(
+            doc! { "email": normalize_email(&payload.email), "email_verified_at": null, "bot": { "$ne": true } },
+            None,
+        )
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if let Some(user) = user {
+        send_verification_email(&mailer, &user)?;
+    }
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json("If the address has an account, an email is on its way"),
+    ))
+}
+
+// Marks the address verified with the token from a verification email
+pub async fn verify_email(
+    State((db, redis)): State<(mongodb::Database, redis::Client)>,
+    Json(payload): Json<VerifyEmailRequest>,
+) -> Result<Json<&'static str>, (StatusCode, String)> {
+    payload
+        .validate()
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Validation error: {}", e)))?;
+
+    let invalid_token = || {
+        (
+            StatusCode::BAD_REQUEST,
+            "Invalid or expired token".to_string(),
+        )
+    };
+    let claims =
+        email_tokens::verify(&payload.token, Purpose::VerifyEmail).map_err(|_| invalid_token())?;
+    let user_id = claims.user_id().map_err(|_| invalid_token())?;
+    if !email_tokens::consume(&redis, &claims).await? {
+        return Err(invalid_token());
+    }
+
+    // Only the address the link was sent to; a token for a previous address does nothing
+    let users: Collection<User> = db.collection("users");
+    let user = users
+        .find_one(doc! { "_id": user_id, "email": &claims.email }, None)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or_else(invalid_token)?;
+    if user.email_verified_at.is_none() {
+        mark_email_verified(&db, user_id).await?;
+    }
+
+    Ok(Json("Email verified"))
+}
+
+// Emails a password reset link if the address has an account. Always 202, like
+// resend_verification_email.
+pub async fn forgot_password(
+    State((db, _redis)): State<(mongodb::Database, redis::Client)>,
+    Extension(mailer): Extension<Arc<dyn Mailer>>,
+    Json(payload): Json<EmailRequest>,
+) -> Result<(StatusCode, Json<&'static str>), (StatusCode, String)> {
+    payload
+        .validate()
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Validation error: {}", e)))?;
+
+    let users: Collection<User> = db.collection("users");
+    let user = users
+        .find_one(
+            doc! { "email": normalize_email(&payload.email), "bot": { "$ne": true } },
+            None,
+        )
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if let Some(user) = user {
+        let token = email_tokens::issue(&user, Purpose::ResetPassword)
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        mailer::send_in_background(
+            mailer,
+            mailer::password_reset_email(&user.email, &user.username, &token),
+        );
+    }
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json("If the address has an account, an email is on its way"),
+    ))
+}
+
+// Sets a new password with the token from a reset email and logs out every session
+pub async fn reset_password(
+    State((db, redis)): State<(mongodb::Database, redis::Client)>,
+    Json(payload): Json<ResetPasswordRequest>,
+) -> Result<Json<&'static str>, (StatusCode, String)> {
+    payload
+        .validate()
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Validation error: {}", e)))?;
+
+    let invalid_token = || {
+        (
+            StatusCode::BAD_REQUEST,
+            "Invalid or expired token".to_string(),
+        )
+    };
+    let claims = email_tokens::verify(&payload.token, Purpose::ResetPassword)
+        .map_err(|_| invalid_token())?;
+    let user_id = claims.user_id().map_err(|_| invalid_token())?;
+
+    let users: Collection<User> = db.collection("users");
+    let user = users
+        .find_one(doc! { "_id": user_id, "email": &claims.email }, None)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or_else(invalid_token)?;
+    // Issued against the current password, so it stops working once the password changes
+    if claims.pwd.as_deref()
+        != Some(email_tokens::password_fingerprint(&user.password_hash).as_str())
+    {
+        return Err(invalid_token());
+    }
+    if !email_tokens::consume(&redis, &claims).await? {
+        return Err(invalid_token());
+    }
+
+    let password_hash = hash_password(&payload.password)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let result = users
+        .update_one(
+            doc! { "_id": user_id, "password_hash": &user.password_hash },
+            doc! { "$set": { "password_hash": password_hash } },
+            None,
+        )
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if result.matched_count == 0 {
+        return Err(invalid_token());
+    }
+
+    // Receiving the email also proves the address belongs to the user
+    if user.email_verified_at.is_none() {
+        mark_email_verified(&db, user_id).await?;
+    }
+
+    // Whoever knew the old password is logged out
+    revoke_sessions(&db, &redis, user_id, doc! {}).await?;
+
+    Ok(Json("Password updated"))
+}
+
+// Lifts a lockout with the token from the unlock email
+pub async fn unlock_account(
+    State((_db, redis)): State<(mongodb::Database, redis::Client)>,
+    Json(payload): Json<VerifyEmailRequest>,
+) -> Result<Json<&'static str>, (StatusCode, String)> {
+    payload
+        .validate()
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Validation error: {}", e)))?;
+
+    let invalid_token = || {
+        (
+            StatusCode::BAD_REQUEST,
+            "Invalid or expired token".to_string(),
+        )
+    };
+    let claims = email_tokens::verify(&payload.token, Purpose::UnlockAccount)
+        .map_err(|_| invalid_token())?;
+    if !email_tokens::consume(&redis, &claims).await? {
+        return Err(invalid_token());
+    }
+    throttle::unlock(&redis, &claims.email).await?;
+
+    Ok(Json("Account unlocked"))
+}
+
+pub async fn list_oidc_providers(
+    Extension(providers): Extension<Arc<Providers>>,
+) -> Json<Vec<ProviderSummary>> {
+    Json(providers.list())
+}
+
+// Starts logging in with an identity provider: send the browser to `authorization_url`
+pub async fn start_oidc_login(
+    State((_db, redis)): State<(mongodb::Database, redis::Client)>,
+    Extension(providers): Extension<Arc<Providers>>,
+    Path(provider_id): Path<String>,
+) -> Result<Json<OidcStartResponse>, (StatusCode, String)> {
+    let provider = providers.get(&provider_id).ok_or((
+        StatusCode::NOT_FOUND,
+        "Unknown identity provider".to_string(),
+    ))?;
+    let authorization_url = providers.start(&redis, provider).await?;
+    Ok(Json(OidcStartResponse { authorization_url }))
+}
+
+// Completes the login with the code and state the provider redirected back with. The
+// provider account logs into the user it was linked to before, or else the user with its
+// verified email address; failing both, a new user is created. An account registered
+// with the address but never verified is taken over before it is linked.
+pub async fn finish_oidc_login(
+    State((db, redis)): State<(mongodb::Database, redis::Client)>,
+    Extension(providers): Extension<Arc<Providers>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(payload): Json<OidcCallbackRequest>,
+) -> Result<Json<LoginResponse>, (StatusCode, String)> {
+    payload
+        .validate()
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Validation error: {}", e)))?;
+
+    let invalid_state = || {
+        (
+            StatusCode::BAD_REQUEST,
+            "Invalid or expired login state".to_string(),
+        )
+    };
+    let pending = Providers::take_pending(&redis, &payload.state)
+        .await?
+        .ok_or_else(invalid_state)?;
+    let provider = providers.get(&pending.provider).ok_or_else(invalid_state)?;
+    let identity = providers.finish(provider, &pending, &payload.code).await?;
+
+    let now = chrono::Utc::now();
+    let now_bson = mongodb::bson::to_bson(&now)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let identities: Collection<OidcIdentity> = db.collection("oidc_identities");
+    let users: Collection<User> = db.collection("users");
+
+    let linked = identities
+        .find_one_and_update(
+            doc! { "provider": &provider.id, "subject": &identity.subject },
+            doc! { "$set": { "last_login_at": now_bson, "email": &identity.email } },
+            None,
+        )
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    let user = match linked {
+        Some(link) => users
+            .find_one(doc! { "_id": link.user_id }, None)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+            .ok_or((
+                StatusCode::UNAUTHORIZED,
+                "Linked account no longer exists".to_string(),
+            ))?,
+        None => {
+            // Linking by email is only safe when the provider vouches for the address
+            let email = identity
+                .email
+                .as_deref()
+                .map(normalize_email)
+                .filter(|_| identity.email_verified)
+                .ok_or((
+                    StatusCode::FORBIDDEN,
+                    "The identity provider did not share a verified email address".to_string(),
+                ))?;
+            let mut user = match find_user_by_email(&db, &email).await? {
+                Some(user) if user.email_verified_at.is_some() => user,
+                // Whoever signed up with the address never proved it was theirs, so it may
+                // have been registered in advance to hijack the owner's provider login
+                Some(user) => take_over_unverified_user(&db, &redis, user).await?,
+                None => create_external_user(&db, &identity, &email).await?,
+            };
+            if user.email_verified_at.is_none() {
+                mark_email_verified(&db, user.id.unwrap()).await?;
+                user.email_verified_at = Some(now);
+            }
+
+            let link = OidcIdentity {
+                id: None,
+                user_id: user.id.unwrap(),
+                provider: provider.id.clone(),
+                subject: identity.subject.clone(),
+                email: Some(email),
+                created_at: now,
+                last_login_at: now,
+            };
+            identities
+                .insert_one(link, None)
+                .await
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+            println!(
+                "🔗 Linked {} account {} to user {}",
+                provider.name,
+                identity.subject,
+                user.id.unwrap()
+            );
+            user
+        }
+    };
+
+    Ok(Json(
+        finish_login(&db, &redis, user, payload.device_name, &headers, peer).await?,
+    ))
+}
+
+// Emails are stored lowercase, so an address matches however it was typed
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+async fn find_user_by_email(
+    db: &mongodb::Database,
+    email: &str,
+) -> Result<Option<User>, (StatusCode, String)> {
+    let users: Collection<User> = db.collection("users");
+    users
+        .find_one(doc! { "email": normalize_email(email) }, None)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+// Hands an account whose email was never verified to the provider identity that vouches
+// for the address. Everything its registrant set up to keep a way in goes: the password
+// becomes random, and sessions, second factors, passkeys, personal access tokens and other
+// linked providers are revoked.
+async fn take_over_unverified_user(
+    db: &mongodb::Database,
+    redis: &redis::Client,
+    mut user: User,
+) -> Result<User, (StatusCode, String)> {
+    let user_id = user.id.unwrap();
+    let password_hash = hash_password(&generate_refresh_token())
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let users: Collection<User> = db.collection("users");
+    let result = users
+        .update_one(
+            doc! { "_id": user_id, "email_verified_at": null },
+            doc! { "$set": { "password_hash": &password_hash } },
+            None,
+        )
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if result.matched_count == 0 {
+        return Err((
+            StatusCode::CONFLICT,
+            "The account changed during login, try again".to_string(),
+        ));
+    }
+    user.password_hash = password_hash;
+
+    revoke_sessions(db, redis, user_id, doc! {}).await?;
+    api_tokens::delete_all(db, user_id, ApiTokenKind::Personal).await?;
+    for collection in ["totp_factors", "webauthn_credentials", "oidc_identities"] {
+        db.collection::<mongodb::bson::Document>(collection)
+            .delete_many(doc! { "user_id": user_id }, None)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    }
+    println!("⚠️ Took over unverified account {} on a provider login for its address", user_id);
+    Ok(user)
+}
+
+// A user for a first login through an identity provider. Its password is random and
+// unknown to anyone; the user can set one through the password reset flow.
+async fn create_external_user(
+    db: &mongodb::Database,
+    identity: &ExternalIdentity,
+    email: &str,
+) -> Result<User, (StatusCode, String)> {
+    let password_hash = hash_password(&generate_refresh_token())
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let now = chrono::Utc::now();
+    let mut user = User {
+        id: None,
+        username: oidc::suggested_username(&[identity.username.as_deref(), Some(email)]),
+        email: email.to_string(),
+        password_hash,
+        created_at: now,
+        last_seen: None,
+        email_verified_at: Some(now),
+        bot: false,
+        owner_id: None,
+    };
+
+    let users: Collection<User> = db.collection("users");
+    let result = users
+        .insert_one(&user, None)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    user.id = result.inserted_id.as_object_id();
+    Ok(user)
+}
+
+async fn mark_email_verified(
+    db: &mongodb::Database,
+    user_id: ObjectId,
+) -> Result<(), (StatusCode, String)> {
+    let now = mongodb::bson::to_bson(&chrono::Utc::now())
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let users: Collection<User> = db.collection("users");
+    users
+        .update_one(
+            doc! { "_id": user_id, "email_verified_at": null },
+            doc! { "$set": { "email_verified_at": now } },
+            None,
+        )
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(())
+}
+
+fn send_verification_email(
+    mailer: &Arc<dyn Mailer>,
+    user: &User,
+) -> Result<(), (StatusCode, String)> {
+    let token = email_tokens::issue(user, Purpose::VerifyEmail)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    mailer::send_in_background(
+        mailer.clone(),
+        mailer::verification_email(&user.email, &user.username, &token),
+    );
+    Ok(())
+}
+
+// Public keys for verifying access tokens, so other services never hold signing material
+pub async fn jwks() -> (
+    [(header::HeaderName, &'static str); 1],
+    Json<jsonwebtoken::jwk::JwkSet>,
+) {
+    (
+        [(header::CACHE_CONTROL, "public, max-age=300")],
+        Json(key_set().jwks()),
+    )
+}
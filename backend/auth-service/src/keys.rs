@@ -0,0 +1,184 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{
+    jwk::{
+        AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm,
+        OctetKeyPairParameters, OctetKeyPairType, PublicKeyUse, RSAKeyParameters, RSAKeyType,
+    },
+    Algorithm, EncodingKey,
+};
+use ring::{
+    rand::SystemRandom,
+    rsa::PublicKeyComponents,
+    signature::{Ed25519KeyPair, KeyPair, RsaKeyPair},
+};
+use std::{path::Path, sync::OnceLock};
+
+use common::auth::VerifyingKey;
+
+// A private key this service signs with, and the public half it publishes
+pub struct SigningKey {
+    pub kid: String,
+    pub algorithm: Algorithm,
+    encoding_key: EncodingKey,
+    verifying_key: VerifyingKey,
+    jwk: Jwk,
+}
+
+impl SigningKey {
+    // Reads an Ed25519 or RSA private key from a PKCS#8 PEM file
+    pub fn from_pem(kid: &str, pem: &[u8]) -> Result<Self, String> {
+        let parsed = pem::parse(pem).map_err(|e| format!("key {} is not valid PEM: {}", kid, e))?;
+        if parsed.tag() != "PRIVATE KEY" {
+            return Err(format!("key {} must be a PKCS#8 PRIVATE KEY, got {}", kid, parsed.tag()));
+        }
+        let der = parsed.contents();
+
+        if let Ok(key_pair) = Ed25519KeyPair::from_pkcs8_maybe_unchecked(der) {
+            return Self::ed25519(kid, &key_pair, EncodingKey::from_ed_der(der));
+        }
+
+        let key_pair = RsaKeyPair::from_pkcs8(der)
+            .map_err(|e| format!("key {} is neither Ed25519 nor RSA: {}", kid, e))?;
+        let public = PublicKeyComponents::<Vec<u8>>::from(key_pair.public());
+        let params = AlgorithmParameters::RSA(RSAKeyParameters {
+            key_type: RSAKeyType::RSA,
+            n: URL_SAFE_NO_PAD.encode(public.n),
+            e: URL_SAFE_NO_PAD.encode(public.e),
+        });
+        let encoding_key = EncodingKey::from_rsa_pem(pem).map_err(|e| format!("key {}: {}", kid, e))?;
+        Self::new(kid, Algorithm::RS256, encoding_key, params)
+    }
+
+    // A fresh Ed25519 key, for development setups without configured keys
+    pub fn generate(kid: &str) -> Self {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .expect("system randomness is available");
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).expect("generated key is valid");
+        Self::ed25519(kid, &key_pair, EncodingKey::from_ed_der(pkcs8.as_ref()))
+            .expect("generated key is valid")
+    }
+
+    fn ed25519(kid: &str, key_pair: &Ed25519KeyPair, encoding_key: EncodingKey) -> Result<Self, String> {
+        let params = AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+            key_type: OctetKeyPairType::OctetKeyPair,
+            curve: EllipticCurve::Ed25519,
+            x: URL_SAFE_NO_PAD.encode(key_pair.public_key().as_ref()),
+        });
+        Self::new(kid, Algorithm::EdDSA, encoding_key, params)
+    }
+
+    fn new(
+        kid: &str,
+        algorithm: Algorithm,
+        encoding_key: EncodingKey,
+        params: AlgorithmParameters,
+    ) -> Result<Self, String> {
+        let jwk = Jwk {
+            common: CommonParameters {
+                public_key_use: Some(PublicKeyUse::Signature),
+                key_algorithm: Some(match algorithm {
+                    Algorithm::EdDSA => KeyAlgorithm::EdDSA,
+                    _ => KeyAlgorithm::RS256,
+                }),
+                key_id: Some(kid.to_owned()),
+                ..Default::default()
+            },
+            algorithm: params,
+        };
+        let verifying_key = VerifyingKey::from_jwk(&jwk)
+            .ok_or_else(|| format!("key {} cannot verify its own tokens", kid))?;
+        Ok(Self {
+            kid: kid.to_owned(),
+            algorithm,
+            encoding_key,
+            verifying_key,
+            jwk,
+        })
+    }
+
+    pub fn encoding_key(&self) -> &EncodingKey {
+        &self.encoding_key
+    }
+
+    pub fn verifying_key(&self) -> &VerifyingKey {
+        &self.verifying_key
+    }
+}
+
+// Every key whose tokens are still accepted; one of them signs new tokens.
+// Rotation: add the new key, wait for verifiers to refresh the JWKS, switch
+// JWT_SIGNING_KID to it, then remove the old key once its tokens have expired.
+pub struct KeySet {
+    keys: Vec<SigningKey>,
+    signing: usize,
+}
+
+impl KeySet {
+    pub fn new(keys: Vec<SigningKey>, signing_kid: &str) -> Result<Self, String> {
+        let signing = keys
+            .iter()
+            .position(|key| key.kid == signing_kid)
+            .ok_or_else(|| format!("signing key {} is not loaded", signing_kid))?;
+        Ok(Self { keys, signing })
+    }
+
+    // Loads `<kid>.pem` files from JWT_KEYS_DIR and signs with JWT_SIGNING_KID,
+    // defaulting to the last kid in sort order so date-named keys rotate by themselves.
+    // Without JWT_KEYS_DIR a throwaway key is generated, which only suits a single instance.
+    pub fn from_env() -> Result<Self, String> {
+        let Ok(dir) = std::env::var("JWT_KEYS_DIR") else {
+            eprintln!("⚠️ JWT_KEYS_DIR is not set, signing with an ephemeral key");
+            let key = SigningKey::generate("ephemeral");
+            return Ok(Self { keys: vec![key], signing: 0 });
+        };
+
+        let keys = load_dir(Path::new(&dir))?;
+        let signing_kid = match std::env::var("JWT_SIGNING_KID") {
+            Ok(kid) => kid,
+            Err(_) => keys
+                .last()
+                .map(|key| key.kid.clone())
+                .ok_or_else(|| format!("no keys found in {}", dir))?,
+        };
+        Self::new(keys, &signing_kid)
+    }
+
+    pub fn signing_key(&self) -> &SigningKey {
+        &self.keys[self.signing]
+    }
+
+    pub fn find(&self, kid: &str) -> Option<&SigningKey> {
+        self.keys.iter().find(|key| key.kid == kid)
+    }
+
+    // Public keys published at /.well-known/jwks.json
+    pub fn jwks(&self) -> JwkSet {
+        JwkSet {
+            keys: self.keys.iter().map(|key| key.jwk.clone()).collect(),
+        }
+    }
+}
+
+fn load_dir(dir: &Path) -> Result<Vec<SigningKey>, String> {
+    let entries = std::fs::read_dir(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    let mut keys = Vec::new();
+    for entry in entries {
+        let path = entry.map_err(|e| e.to_string())?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("pem") {
+            continue;
+        }
+        let Some(kid) = path.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+        let pem = std::fs::read(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        keys.push(SigningKey::from_pem(kid, &pem)?);
+    }
+    keys.sort_by(|a, b| a.kid.cmp(&b.kid));
+    Ok(keys)
+}
+
+// The process-wide key set, loaded on first use
+pub fn key_set() -> &'static KeySet {
+    static KEYS: OnceLock<KeySet> = OnceLock::new();
+    KEYS.get_or_init(|| KeySet::from_env().unwrap_or_else(|e| panic!("invalid JWT keys: {}", e)))
+}
@@ -1,18 +1,27 @@
 mod handlers;
 mod models;
+mod api_tokens;
 mod auth;
 mod db;
+mod email_tokens;
+mod keys;
+mod mailer;
+mod mfa;
+mod oidc;
+mod sessions;
+mod throttle;
+mod webauthn;
 mod tests;
 
 use axum::{
-    routing::post,
-    Router,
+    routing::{delete, get, post},
+    Extension, Router,
     http::StatusCode,
 };
 use dotenv::dotenv;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::Duration;
-use tower::ServiceBuilder;
 use tower_http::{
     cors::CorsLayer,
     limit::RequestBodyLimitLayer,
@@ -26,21 +35,68 @@ async fn main() {
     dotenv().ok();
 
     let db = db::get_database().await.expect("DB connection failed");
+    let redis_url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://redis:6379".to_string());
+    let redis_client = redis::Client::open(redis_url).expect("Redis connection failed");
+
+    // Load the signing keys up front so a bad key fails at startup, not on the first login
+    let signing_kid = &keys::key_set().signing_key().kid;
+    println!("🔑 Signing access tokens with key {}", signing_kid);
+    // Hashed up front too, or the first login for an unknown address would stand out
+    throttle::dummy_hash();
+    // Read up front so a bad proxy list fails at startup
+    sessions::trusted_proxies();
+
+    let mailer = mailer::from_env();
+    let providers = oidc::Providers::from_env().expect("Invalid OIDC provider configuration");
+    for provider in providers.list() {
+        println!("🔗 Login with {} enabled", provider.name);
+    }
 
     let cors = CorsLayer::permissive();
 
     let app = Router::new()
+        .route("/.well-known/jwks.json", get(handlers::jwks))
         .route("/register", post(handlers::register))
         .route("/login", post(handlers::login))
+        .route("/login/mfa", post(handlers::login_mfa))
+        .route("/login/unlock", post(handlers::unlock_account))
+        .route("/oidc/providers", get(handlers::list_oidc_providers))
+        .route("/oidc/:provider/start", post(handlers::start_oidc_login))
+        .route("/oidc/callback", post(handlers::finish_oidc_login))
+        .route("/email/verify", post(handlers::verify_email))
+        .route("/email/verify/resend", post(handlers::resend_verification_email))
+        .route("/password/forgot", post(handlers::forgot_password))
+        .route("/password/reset", post(handlers::reset_password))
+        .route("/mfa/totp", post(handlers::enroll_totp).delete(handlers::disable_totp))
+        .route("/mfa/totp/confirm", post(handlers::confirm_totp))
+        .route("/mfa/recovery-codes", post(handlers::regenerate_recovery_codes))
+        .route("/webauthn/register/start", post(handlers::start_passkey_registration))
+        .route("/webauthn/register/finish", post(handlers::finish_passkey_registration))
+        .route("/webauthn/login/start", post(handlers::start_passkey_login))
+        .route("/webauthn/login/finish", post(handlers::finish_passkey_login))
+        .route("/webauthn/credentials", get(handlers::list_passkeys))
+        .route("/webauthn/credentials/:credential_id", delete(handlers::delete_passkey))
+        .route("/bots", get(handlers::list_bots).post(handlers::create_bot))
+        .route("/bots/:bot_id", delete(handlers::delete_bot))
+        .route("/bots/:bot_id/token", post(handlers::reset_bot_token))
+        .route("/tokens", get(handlers::list_personal_tokens).post(handlers::create_personal_token))
+        .route("/tokens/:token_id", delete(handlers::delete_personal_token))
+        .route("/token/refresh", post(handlers::refresh_token))
+        .route("/sessions", get(handlers::list_sessions).delete(handlers::revoke_all_sessions))
+        .route("/sessions/:session_id", delete(handlers::revoke_session).patch(handlers::rename_session))
+        .layer(Extension(mailer))
+        .layer(Extension(Arc::new(providers)))
         .layer(TraceLayer::new_for_http())
         .layer(TimeoutLayer::with_status_code(StatusCode::REQUEST_TIMEOUT, Duration::from_secs(30)))
         .layer(RequestBodyLimitLayer::new(1024 * 1024))
         .layer(cors)
-        .with_state(db);
+        .with_state((db, redis_client));
 
     let addr = SocketAddr::from(([0, 0, 0, 0], 8081));
     println!("🔐 Auth service running on {}", addr);
     
     let listener = TcpListener::bind(&addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+        .await
+        .unwrap();
 }
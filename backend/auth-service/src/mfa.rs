@@ -0,0 +1,256 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use axum::http::StatusCode;
+use common::auth::hash_token;
+use data_encoding::BASE32_NOPAD;
+use mongodb::{
+    bson::{doc, oid::ObjectId},
+    Collection, Database,
+};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use redis::AsyncCommands;
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+
+use crate::models::{RecoveryCode, TotpFactor};
+
+// RFC 6238 parameters every authenticator app supports
+pub const TOTP_STEP_SECONDS: i64 = 30;
+pub const TOTP_DIGITS: u32 = 6;
+// Codes from one step either side are accepted to allow for clock drift
+pub const TOTP_SKEW_STEPS: i64 = 1;
+
+pub const RECOVERY_CODE_COUNT: usize = 10;
+
+// How long a password-verified login waits for its second factor
+pub const MFA_TICKET_TTL_SECONDS: u64 = 300;
+// Wrong codes allowed per ticket before the login has to start over
+pub const MFA_TICKET_MAX_ATTEMPTS: u64 = 5;
+
+// 160-bit shared secret, base32 encoded as authenticator apps expect
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; 20];
+    OsRng.fill_bytes(&mut bytes);
+    BASE32_NOPAD.encode(&bytes)
+}
+
+// URI rendered as a QR code for the authenticator app to scan
+pub fn otpauth_uri(issuer: &str, account: &str, secret: &str) -> String {
+    let issuer = utf8_percent_encode(issuer, NON_ALPHANUMERIC).to_string();
+    let account = utf8_percent_encode(account, NON_ALPHANUMERIC);
+    format!(
+        "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={TOTP_DIGITS}&period={TOTP_STEP_SECONDS}"
+    )
+}
+
+// Name shown in authenticator apps, from MFA_ISSUER
+pub fn issuer() -> String {
+    std::env::var("MFA_ISSUER").unwrap_or_else(|_| "WebChat".to_string())
+}
+
+// The code for one time step (RFC 4226 dynamic truncation)
+pub fn totp_at_step(secret: &[u8], step: i64) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+    let digest = hmac::sign(&key, &step.to_be_bytes());
+    let digest = digest.as_ref();
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    format!("{:0width$}", binary % 10u32.pow(TOTP_DIGITS), width = TOTP_DIGITS as usize)
+}
+
+// Time step the code matches, if any, within the allowed skew of `unix_time`.
+// Callers must reject steps at or before the last one used, so a code works once.
+pub fn verify_totp(secret: &str, code: &str, unix_time: i64) -> Option<i64> {
+    let secret = BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+    let code = code.trim();
+    if code.len() != TOTP_DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let current = unix_time.div_euclid(TOTP_STEP_SECONDS);
+    (current - TOTP_SKEW_STEPS..=current + TOTP_SKEW_STEPS).find(|&step| {
+        let expected = totp_at_step(&secret, step);
+        bool::from(expected.as_bytes().ct_eq(code.as_bytes()))
+    })
+}
+
+// One-time codes for when the authenticator is lost, formatted xxxx-xxxx-xxxx
+pub fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let mut bytes = [0u8; 8];
+            OsRng.fill_bytes(&mut bytes);
+            let chars = BASE32_NOPAD.encode(&bytes).to_lowercase();
+            format!("{}-{}-{}", &chars[0..4], &chars[4..8], &chars[8..12])
+        })
+        .collect()
+}
+
+// Recovery codes are hashed like any other random token. Case and dashes are ignored so codes can be typed back however they were written down.
+pub fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    hash_token(&normalized)
+}
+
+// A login that passed the password check and waits for its second factor
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MfaTicket {
+    pub user_id: ObjectId,
+    pub device_name: Option<String>,
+}
+
+fn ticket_key(ticket: &str) -> String {
+    // Stored by hash so a Redis dump cannot be replayed against /login/mfa
+    format!("mfa:ticket:{}", hash_token(ticket))
+}
+
+fn internal_error(e: impl ToString) -> (StatusCode, String) {
+    (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+}
+
+// Stores the pending login and returns the opaque ticket the client presents with its code
+pub async fn create_ticket(
+    redis: &redis::Client,
+    ticket: &MfaTicket,
+) -> Result<String, (StatusCode, String)> {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    let id = hex::encode(bytes);
+
+    let value = serde_json::to_string(ticket).map_err(internal_error)?;
+    let mut conn = redis.get_multiplexed_async_connection().await.map_err(internal_error)?;
+    conn.set_ex::<_, _, ()>(ticket_key(&id), value, MFA_TICKET_TTL_SECONDS)
+        .await
+        .map_err(internal_error)?;
+    Ok(id)
+}
+
+// The pending login for a ticket, if it is still valid and has attempts left
+pub async fn load_ticket(
+    redis: &redis::Client,
+    ticket: &str,
+) -> Result<Option<MfaTicket>, (StatusCode, String)> {
+    let mut conn = redis.get_multiplexed_async_connection().await.map_err(internal_error)?;
+    let value: Option<String> = conn.get(ticket_key(ticket)).await.map_err(internal_error)?;
+    Ok(value.and_then(|v| serde_json::from_str(&v).ok()))
+}
+
+// Counts a wrong code; the ticket is dropped once it runs out of attempts
+pub async fn record_failed_attempt(
+    redis: &redis::Client,
+    ticket: &str,
+) -> Result<(), (StatusCode, String)> {
+    let key = ticket_key(ticket);
+    let attempts_key = format!("{}:attempts", key);
+    let mut conn = redis.get_multiplexed_async_connection().await.map_err(internal_error)?;
+    let (attempts,): (u64,) = redis::pipe()
+        .incr(&attempts_key, 1)
+        .expire(&attempts_key, MFA_TICKET_TTL_SECONDS as i64)
+        .ignore()
+        .query_async(&mut conn)
+        .await
+        .map_err(internal_error)?;
+    if attempts >= MFA_TICKET_MAX_ATTEMPTS {
+        conn.del::<_, ()>(&[key, attempts_key]).await.map_err(internal_error)?;
+    }
+    Ok(())
+}
+
+// Consumes the ticket; false if another request already completed the login with it
+pub async fn consume_ticket(
+    redis: &redis::Client,
+    ticket: &str,
+) -> Result<bool, (StatusCode, String)> {
+    let key = ticket_key(ticket);
+    let attempts_key = format!("{}:attempts", key);
+    let mut conn = redis.get_multiplexed_async_connection().await.map_err(internal_error)?;
+    let value: Option<String> = redis::cmd("GETDEL")
+        .arg(&key)
+        .query_async(&mut conn)
+        .await
+        .map_err(internal_error)?;
+    conn.del::<_, ()>(attempts_key).await.map_err(internal_error)?;
+    Ok(value.is_some())
+}
+
+// The user's confirmed authenticator, if they enabled two-factor authentication
+pub async fn confirmed_factor(
+    db: &Database,
+    user_id: ObjectId,
+) -> Result<Option<TotpFactor>, (StatusCode, String)> {
+    let factors: Collection<TotpFactor> = db.collection("totp_factors");
+    factors
+        .find_one(doc! { "user_id": user_id, "confirmed_at": { "$ne": null } }, None)
+        .await
+        .map_err(internal_error)
+}
+
+// Checks a code from the factor's authenticator and marks its time step used.
+// False for wrong codes and for codes that were already used.
+pub async fn accept_totp_code(
+    db: &Database,
+    factor: &TotpFactor,
+    code: &str,
+) -> Result<bool, (StatusCode, String)> {
+    let Some(step) = verify_totp(&factor.secret, code, chrono::Utc::now().timestamp()) else {
+        return Ok(false);
+    };
+
+    // Conditional on the step, so the same code can't be accepted twice concurrently
+    let factors: Collection<TotpFactor> = db.collection("totp_factors");
+    let result = factors
+        .update_one(
+            doc! {
+                "_id": factor.id,
+                "$or": [{ "last_used_step": null }, { "last_used_step": { "$lt": step } }],
+            },
+            doc! { "$set": { "last_used_step": step } },
+            None,
+        )
+        .await
+        .map_err(internal_error)?;
+    Ok(result.modified_count > 0)
+}
+
+// Marks one of the user's unused recovery codes used; false if it matches none
+pub async fn accept_recovery_code(
+    db: &Database,
+    user_id: ObjectId,
+    code: &str,
+) -> Result<bool, (StatusCode, String)> {
+    let now = mongodb::bson::to_bson(&chrono::Utc::now()).map_err(internal_error)?;
+    let factors: Collection<TotpFactor> = db.collection("totp_factors");
+    let result = factors
+        .update_one(
+            doc! {
+                "user_id": user_id,
+                "confirmed_at": { "$ne": null },
+                "recovery_codes": {
+                    "$elemMatch": { "code_hash": hash_recovery_code(code), "used_at": null }
+                },
+            },
+            doc! { "$set": { "recovery_codes.$.used_at": now } },
+            None,
+        )
+        .await
+        .map_err(internal_error)?;
+    Ok(result.modified_count > 0)
+}
+
+// Fresh recovery codes for the user, and the stored form that replaces the old ones
+pub fn new_recovery_codes() -> (Vec<String>, Vec<RecoveryCode>) {
+    let codes = generate_recovery_codes();
+    let stored = codes
+        .iter()
+        .map(|code| RecoveryCode { code_hash: hash_recovery_code(code), used_at: None })
+        .collect();
+    (codes, stored)
+}
@@ -2,15 +2,7 @@ use mongodb::bson::oid::ObjectId;
 use serde::{Deserialize, Serialize};
 use validator::Validate;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct User {
-    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
-    pub id: Option<ObjectId>,
-    pub username: String,
-    pub email: String,
-    pub password_hash: String,
-    pub created_at: chrono::DateTime<chrono::Utc>,
-}
+pub use common::models::{ApiToken, ApiTokenKind, AuthResponse, User, UserResponse};
 
 #[derive(Debug, Deserialize, Validate)]
 pub struct RegisterRequest {
@@ -28,17 +20,278 @@ pub struct LoginRequest {
     pub email: String,
     #[validate(length(min = 1, message = "Password is required"))]
     pub password: String,
+    // Shown in the session list, e.g. "Work laptop"
+    #[serde(default)]
+    #[validate(length(max = 64, message = "Device name must be at most 64 characters"))]
+    pub device_name: Option<String>,
+}
+
+// A refresh token, stored by hash. Each use rotates it: the token is marked used
+// and replaced by a new one in the same family.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RefreshToken {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user_id: ObjectId,
+    // Shared by every token rotated from the same login: the id of the login's session
+    pub family_id: ObjectId,
+    pub token_hash: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+    pub used_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub revoked: bool,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct RefreshRequest {
+    #[validate(length(min = 1, message = "Refresh token is required"))]
+    pub refresh_token: String,
+}
+
+// A login on one device. Access tokens carry its id in the `sid` claim and stop
+// being accepted once it is revoked.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Session {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user_id: ObjectId,
+    pub name: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub last_used_at: chrono::DateTime<chrono::Utc>,
+    pub revoked_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionResponse {
+    pub id: String,
+    pub name: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub last_used_at: chrono::DateTime<chrono::Utc>,
+    // Whether this is the session making the request
+    pub current: bool,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct RenameSessionRequest {
+    #[validate(length(min = 1, max = 64, message = "Name must be between 1 and 64 characters"))]
+    pub name: String,
+}
+
+// A user's TOTP authenticator. It only guards logins once confirmed with a code.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TotpFactor {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user_id: ObjectId,
+    // Base32 shared secret
+    pub secret: String,
+    pub confirmed_at: Option<chrono::DateTime<chrono::Utc>>,
+    // Time step of the last accepted code, so each code works only once
+    pub last_used_step: Option<i64>,
+    pub recovery_codes: Vec<RecoveryCode>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+// A one-time recovery code, stored by hash
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RecoveryCode {
+    pub code_hash: String,
+    pub used_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TotpEnrollmentResponse {
+    pub secret: String,
+    // Render as a QR code for the authenticator app
+    pub otpauth_uri: String,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct TotpCodeRequest {
+    #[validate(length(min = 1, max = 32, message = "Code is required"))]
+    pub code: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RecoveryCodesResponse {
+    // Shown once; only hashes are kept
+    pub recovery_codes: Vec<String>,
+}
+
+// Password accepted, second factor required: complete the login at /login/mfa
+#[derive(Debug, Serialize)]
+pub struct MfaChallenge {
+    pub mfa_required: bool,
+    pub ticket: String,
+    pub methods: Vec<&'static str>,
+    pub expires_in: u64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum LoginResponse {
+    Authenticated(AuthResponse),
+    MfaRequired(MfaChallenge),
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct MfaLoginRequest {
+    #[validate(length(min = 1, message = "Ticket is required"))]
+    pub ticket: String,
+    // A code from the authenticator app
+    #[serde(default)]
+    pub code: Option<String>,
+    // Or one of the recovery codes, when the authenticator is lost
+    #[serde(default)]
+    pub recovery_code: Option<String>,
+}
+
+// A passkey registered by a user. Only the public key is stored; the authenticator keeps
+// the private key and signs a fresh challenge at each login.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WebauthnCredential {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user_id: ObjectId,
+    // Base64url credential id, as the browser reports it
+    pub credential_id: String,
+    // Base64url COSE_Key
+    pub public_key: String,
+    // Authenticator's signature counter, used to detect cloned keys
+    pub sign_count: u32,
+    pub name: String,
+    pub aaguid: String,
+    #[serde(default)]
+    pub transports: Vec<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub last_used_at: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 #[derive(Debug, Serialize)]
-pub struct AuthResponse {
+pub struct WebauthnCredentialResponse {
+    pub id: String,
+    pub name: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub last_used_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct WebauthnRegisterRequest {
+    // navigator.credentials.create() result, serialized with toJSON()
+    pub credential: crate::webauthn::RegistrationCredential,
+    #[validate(length(min = 1, max = 64, message = "Name must be between 1 and 64 characters"))]
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct WebauthnLoginRequest {
+    // navigator.credentials.get() result, serialized with toJSON()
+    pub credential: crate::webauthn::AuthenticationCredential,
+    #[serde(default)]
+    #[validate(length(max = 64, message = "Device name must be at most 64 characters"))]
+    pub device_name: Option<String>,
+}
+
+// Asks for an email to be sent to an address: a new verification link or a password reset
+#[derive(Debug, Deserialize, Validate)]
+pub struct EmailRequest {
+    #[validate(email(message = "Invalid email address"))]
+    pub email: String,
+}
+
+// A token from an emailed link: email verification or account unlock
+#[derive(Debug, Deserialize, Validate)]
+pub struct VerifyEmailRequest {
+    #[validate(length(min = 1, message = "Token is required"))]
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct ResetPasswordRequest {
+    #[validate(length(min = 1, message = "Token is required"))]
     pub token: String,
-    pub user: UserResponse,
+    #[validate(length(min = 8, max = 128, message = "Password must be between 8 and 128 characters"))]
+    pub password: String,
+}
+
+// An account at an external identity provider, linked to a user
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct OidcIdentity {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user_id: ObjectId,
+    // Provider id from OIDC_PROVIDERS
+    pub provider: String,
+    // The provider's stable id for the account (`sub`)
+    pub subject: String,
+    pub email: Option<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub last_login_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OidcStartResponse {
+    // Send the browser here; the provider redirects back to OIDC_REDIRECT_URI
+    pub authorization_url: String,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct OidcCallbackRequest {
+    #[validate(length(min = 1, max = 2048, message = "Code is required"))]
+    pub code: String,
+    #[validate(length(min = 1, max = 128, message = "State is required"))]
+    pub state: String,
+    #[serde(default)]
+    #[validate(length(max = 64, message = "Device name must be at most 64 characters"))]
+    pub device_name: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct CreateBotRequest {
+    #[validate(length(min = 3, max = 30, message = "Username must be between 3 and 30 characters"))]
+    pub username: String,
 }
 
 #[derive(Debug, Serialize)]
-pub struct UserResponse {
+pub struct BotResponse {
     pub id: String,
     pub username: String,
-    pub email: String,
+    pub owner_id: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+// Returned when a bot is created or its token reset; the token is not shown again
+#[derive(Debug, Serialize)]
+pub struct BotTokenResponse {
+    pub bot: BotResponse,
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct CreatePersonalTokenRequest {
+    #[validate(length(min = 1, max = 64, message = "Name must be between 1 and 64 characters"))]
+    pub name: String,
+    #[validate(length(min = 1, message = "At least one scope is required"))]
+    pub scopes: Vec<common::auth::Scope>,
+    // Without one the token is valid until deleted
+    #[serde(default)]
+    #[validate(range(min = 1, max = 365, message = "Tokens can be valid for 1 to 365 days"))]
+    pub expires_in_days: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PersonalTokenResponse {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<common::auth::Scope>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub last_used_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    // Only when the token is created
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
 }
@@ -0,0 +1,563 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use axum::http::StatusCode;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{
+    decode, decode_header,
+    jwk::{Jwk, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{str::FromStr, time::Duration};
+use tokio::sync::{OnceCell, RwLock};
+
+use crate::mailer::app_url;
+
+// How long the user has to finish logging in at the identity provider
+pub const STATE_TTL_SECONDS: u64 = 600;
+
+// Signature algorithms accepted on ID tokens. Symmetric ones would make the client
+// secret a signing key, so they are never accepted.
+pub const ID_TOKEN_ALGORITHMS: [Algorithm; 9] = [
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::EdDSA,
+];
+
+const GITHUB_AUTHORIZE_URL: &str = "https://github.com/login/oauth/authorize";
+const GITHUB_TOKEN_URL: &str = "https://github.com/login/oauth/access_token";
+const GITHUB_API_URL: &str = "https://api.github.com";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProviderKind {
+    // Any OpenID Connect provider, configured through its discovery document
+    Oidc { issuer: String },
+    // GitHub only speaks plain OAuth2: the account comes from its REST API
+    Github,
+}
+
+// An identity provider users can log in with
+#[derive(Debug)]
+pub struct Provider {
+    pub id: String,
+    pub name: String,
+    pub kind: ProviderKind,
+    client_id: String,
+    client_secret: String,
+    scopes: String,
+    metadata: OnceCell<Metadata>,
+    keys: RwLock<JwkSet>,
+}
+
+// The parts of the discovery document we use
+#[derive(Debug, Clone, Deserialize)]
+pub struct Metadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    #[serde(default)]
+    pub userinfo_endpoint: Option<String>,
+    #[serde(default)]
+    pub jwks_uri: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProviderSummary {
+    pub id: String,
+    pub name: String,
+}
+
+// The account the identity provider vouched for
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExternalIdentity {
+    pub subject: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub username: Option<String>,
+}
+
+// A login waiting for the user to come back from the identity provider, keyed by `state`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PendingLogin {
+    pub provider: String,
+    pub nonce: String,
+    pub code_verifier: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IdTokenClaims {
+    pub sub: String,
+    #[serde(default)]
+    pub nonce: Option<String>,
+    #[serde(default)]
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: Option<Verified>,
+    #[serde(default)]
+    pub preferred_username: Option<String>,
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+// Some providers send email_verified as a string
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Verified {
+    Bool(bool),
+    Text(String),
+}
+
+impl Verified {
+    fn is_true(&self) -> bool {
+        match self {
+            Verified::Bool(value) => *value,
+            Verified::Text(value) => value == "true",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    #[serde(default)]
+    id_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct UserInfo {
+    sub: String,
+    #[serde(default)]
+    email: Option<String>,
+    #[serde(default)]
+    email_verified: Option<Verified>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GithubUser {
+    id: u64,
+    login: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct GithubEmail {
+    email: String,
+    primary: bool,
+    verified: bool,
+}
+
+fn upstream_error(e: impl ToString) -> (StatusCode, String) {
+    (StatusCode::BAD_GATEWAY, format!("Identity provider error: {}", e.to_string()))
+}
+
+fn rejected(reason: &str) -> (StatusCode, String) {
+    (StatusCode::UNAUTHORIZED, format!("Identity provider login rejected: {}", reason))
+}
+
+fn internal_error(e: impl ToString) -> (StatusCode, String) {
+    (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+}
+
+fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+// PKCE S256 challenge for a code verifier (RFC 7636)
+pub fn code_challenge(verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+// Checks an ID token's signature, issuer, audience, expiry and nonce
+pub fn validate_id_token(
+    token: &str,
+    keys: &JwkSet,
+    issuer: &str,
+    client_id: &str,
+    nonce: &str,
+) -> Result<IdTokenClaims, (StatusCode, String)> {
+    let header = decode_header(token).map_err(|_| rejected("malformed ID token"))?;
+    let jwk = find_key(keys, header.kid.as_deref()).ok_or_else(|| rejected("unknown signing key"))?;
+
+    // A key that names its algorithm only verifies that algorithm
+    let algorithm = match jwk.common.key_algorithm {
+        Some(alg) => Algorithm::from_str(&alg.to_string()).map_err(|_| rejected("unsupported algorithm"))?,
+        None => header.alg,
+    };
+    if header.alg != algorithm || !ID_TOKEN_ALGORITHMS.contains(&algorithm) {
+        return Err(rejected("unsupported algorithm"));
+    }
+    let key = DecodingKey::from_jwk(jwk).map_err(|_| rejected("unsupported signing key"))?;
+
+    let mut validation = Validation::new(algorithm);
+    validation.set_issuer(&[issuer]);
+    validation.set_audience(&[client_id]);
+    validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+    let claims = decode::<IdTokenClaims>(token, &key, &validation)
+        .map_err(|e| rejected(&format!("invalid ID token ({})", e)))?
+        .claims;
+
+    if claims.nonce.as_deref() != Some(nonce) {
+        return Err(rejected("nonce mismatch"));
+    }
+    Ok(claims)
+}
+
+// The key a token names, or the only key when it names none
+fn find_key<'a>(keys: &'a JwkSet, kid: Option<&str>) -> Option<&'a Jwk> {
+    match kid {
+        Some(kid) => keys.find(kid),
+        None if keys.keys.len() == 1 => keys.keys.first(),
+        None => None,
+    }
+}
+
+// A name for accounts created on first login, within the 3 to 30 characters
+// registration allows
+pub fn suggested_username(candidates: &[Option<&str>]) -> String {
+    let name = candidates
+        .iter()
+        .flatten()
+        .map(|candidate| {
+            let base = candidate.split('@').next().unwrap_or_default();
+            base.chars()
+                .filter(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '.' | ' '))
+                .take(30)
+                .collect::<String>()
+                .trim()
+                .to_string()
+        })
+        .find(|name| name.chars().count() >= 3);
+    name.unwrap_or_else(|| "user".to_string() + &random_token()[..6])
+}
+
+impl Provider {
+    pub fn new(
+        id: &str,
+        name: &str,
+        kind: ProviderKind,
+        client_id: &str,
+        client_secret: &str,
+        scopes: Option<&str>,
+    ) -> Self {
+        let default_scopes = match kind {
+            ProviderKind::Oidc { .. } => "openid email profile",
+            ProviderKind::Github => "read:user user:email",
+        };
+        Self {
+            id: id.to_string(),
+            name: name.to_string(),
+            kind,
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+            scopes: scopes.unwrap_or(default_scopes).to_string(),
+            metadata: OnceCell::new(),
+            keys: RwLock::new(JwkSet { keys: Vec::new() }),
+        }
+    }
+}
+
+// The configured identity providers
+pub struct Providers {
+    providers: Vec<Provider>,
+    redirect_uri: String,
+    client: reqwest::Client,
+}
+
+impl Providers {
+    pub fn new(providers: Vec<Provider>, redirect_uri: String) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .user_agent("WebChat")
+            .build()
+            .expect("HTTP client configuration is valid");
+        Self { providers, redirect_uri, client }
+    }
+
+    // OIDC_PROVIDERS lists provider ids; each is configured by OIDC_<ID>_ISSUER,
+    // OIDC_<ID>_CLIENT_ID and OIDC_<ID>_CLIENT_SECRET, plus optional OIDC_<ID>_NAME and
+    // OIDC_<ID>_SCOPES. OIDC_<ID>_TYPE=github uses GitHub's OAuth2 API instead of an issuer.
+    pub fn from_env() -> Result<Self, String> {
+        Self::from_vars(|name| std::env::var(name).ok().filter(|value| !value.is_empty()))
+    }
+
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let mut providers = Vec::new();
+        for id in var("OIDC_PROVIDERS").unwrap_or_default().split(',') {
+            let id = id.trim().to_lowercase();
+            if id.is_empty() {
+                continue;
+            }
+            let prefix = format!("OIDC_{}_", id.to_uppercase().replace('-', "_"));
+            let required = |name: &str| {
+                var(&format!("{}{}", prefix, name))
+                    .ok_or_else(|| format!("{}{} must be set", prefix, name))
+            };
+
+            let kind = match var(&format!("{}TYPE", prefix)).as_deref() {
+                Some("github") => ProviderKind::Github,
+                None | Some("oidc") => ProviderKind::Oidc {
+                    issuer: required("ISSUER")?.trim_end_matches('/').to_string(),
+                },
+                Some(other) => return Err(format!("{}TYPE: unknown provider type {}", prefix, other)),
+            };
+            let name = var(&format!("{}NAME", prefix)).unwrap_or_else(|| id.clone());
+            let scopes = var(&format!("{}SCOPES", prefix));
+            providers.push(Provider::new(
+                &id,
+                &name,
+                kind,
+                &required("CLIENT_ID")?,
+                &required("CLIENT_SECRET")?,
+                scopes.as_deref(),
+            ));
+        }
+
+        let redirect_uri = var("OIDC_REDIRECT_URI").unwrap_or_else(|| format!("{}/oidc/callback", app_url()));
+        Ok(Self::new(providers, redirect_uri))
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Provider> {
+        self.providers.iter().find(|provider| provider.id == id)
+    }
+
+    pub fn list(&self) -> Vec<ProviderSummary> {
+        self.providers
+            .iter()
+            .map(|provider| ProviderSummary { id: provider.id.clone(), name: provider.name.clone() })
+            .collect()
+    }
+
+    // Discovery document, fetched on first use
+    async fn metadata<'a>(&self, provider: &'a Provider) -> Result<&'a Metadata, (StatusCode, String)> {
+        let issuer = match &provider.kind {
+            ProviderKind::Oidc { issuer } => issuer,
+            ProviderKind::Github => return Err(internal_error("GitHub has no discovery document")),
+        };
+        provider
+            .metadata
+            .get_or_try_init(|| async {
+                let metadata: Metadata = self
+                    .client
+                    .get(format!("{}/.well-known/openid-configuration", issuer))
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status())
+                    .map_err(upstream_error)?
+                    .json()
+                    .await
+                    .map_err(upstream_error)?;
+                // Guards against a discovery document serving another issuer's endpoints
+                if metadata.issuer.trim_end_matches('/') != issuer {
+                    return Err(upstream_error(format!("discovery document is for {}", metadata.issuer)));
+                }
+                Ok(metadata)
+            })
+            .await
+    }
+
+    // Where to send the user's browser, with a fresh state, nonce and PKCE verifier
+    pub async fn start(
+        &self,
+        redis: &redis::Client,
+        provider: &Provider,
+    ) -> Result<String, (StatusCode, String)> {
+        let authorization_endpoint = match provider.kind {
+            ProviderKind::Oidc { .. } => self.metadata(provider).await?.authorization_endpoint.clone(),
+            ProviderKind::Github => GITHUB_AUTHORIZE_URL.to_string(),
+        };
+
+        let state = random_token();
+        let pending = PendingLogin {
+            provider: provider.id.clone(),
+            nonce: random_token(),
+            code_verifier: random_token(),
+        };
+        let value = serde_json::to_string(&pending).map_err(internal_error)?;
+        let mut conn = redis.get_multiplexed_async_connection().await.map_err(internal_error)?;
+        conn.set_ex::<_, _, ()>(format!("oidc:state:{}", state), value, STATE_TTL_SECONDS)
+            .await
+            .map_err(internal_error)?;
+
+        let url = reqwest::Url::parse_with_params(
+            &authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", provider.client_id.as_str()),
+                ("redirect_uri", self.redirect_uri.as_str()),
+                ("scope", provider.scopes.as_str()),
+                ("state", state.as_str()),
+                ("nonce", pending.nonce.as_str()),
+                ("code_challenge", code_challenge(&pending.code_verifier).as_str()),
+                ("code_challenge_method", "S256"),
+            ],
+        )
+        .map_err(upstream_error)?;
+        Ok(url.to_string())
+    }
+
+    // Takes the login a `state` was issued for; each can be completed once
+    pub async fn take_pending(
+        redis: &redis::Client,
+        state: &str,
+    ) -> Result<Option<PendingLogin>, (StatusCode, String)> {
+        let mut conn = redis.get_multiplexed_async_connection().await.map_err(internal_error)?;
+        let value: Option<String> = redis::cmd("GETDEL")
+            .arg(format!("oidc:state:{}", state))
+            .query_async(&mut conn)
+            .await
+            .map_err(internal_error)?;
+        Ok(value.and_then(|v| serde_json::from_str(&v).ok()))
+    }
+
+    // Trades the authorization code for the identity it was issued for
+    pub async fn finish(
+        &self,
+        provider: &Provider,
+        pending: &PendingLogin,
+        code: &str,
+    ) -> Result<ExternalIdentity, (StatusCode, String)> {
+        let token_endpoint = match provider.kind {
+            ProviderKind::Oidc { .. } => self.metadata(provider).await?.token_endpoint.clone(),
+            ProviderKind::Github => GITHUB_TOKEN_URL.to_string(),
+        };
+        let response = self
+            .client
+            .post(token_endpoint)
+            .header(reqwest::header::ACCEPT, "application/json")
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", self.redirect_uri.as_str()),
+                ("client_id", provider.client_id.as_str()),
+                ("client_secret", provider.client_secret.as_str()),
+                ("code_verifier", pending.code_verifier.as_str()),
+            ])
+            .send()
+            .await
+            .map_err(upstream_error)?;
+        if !response.status().is_success() {
+            return Err(rejected("authorization code not accepted"));
+        }
+        let tokens: TokenResponse = response.json().await.map_err(|_| rejected("authorization code not accepted"))?;
+
+        match &provider.kind {
+            ProviderKind::Oidc { issuer } => self.oidc_identity(provider, issuer, pending, &tokens).await,
+            ProviderKind::Github => self.github_identity(&tokens.access_token).await,
+        }
+    }
+
+    async fn oidc_identity(
+        &self,
+        provider: &Provider,
+        issuer: &str,
+        pending: &PendingLogin,
+        tokens: &TokenResponse,
+    ) -> Result<ExternalIdentity, (StatusCode, String)> {
+        let id_token = tokens.id_token.as_deref().ok_or_else(|| rejected("no ID token"))?;
+        let header = decode_header(id_token).map_err(|_| rejected("malformed ID token"))?;
+
+        // Providers rotate keys, so an unknown kid refetches the set once
+        let known = find_key(&*provider.keys.read().await, header.kid.as_deref()).is_some();
+        if !known {
+            self.refresh_keys(provider).await?;
+        }
+        let claims = validate_id_token(
+            id_token,
+            &*provider.keys.read().await,
+            issuer,
+            &provider.client_id,
+            &pending.nonce,
+        )?;
+
+        let mut identity = ExternalIdentity {
+            email_verified: claims.email_verified.as_ref().is_some_and(Verified::is_true),
+            username: claims.preferred_username.or(claims.name),
+            email: claims.email,
+            subject: claims.sub,
+        };
+
+        // Some providers leave the email out of the ID token and only serve it as user info
+        if identity.email.is_none() {
+            if let Some(endpoint) = self.metadata(provider).await?.userinfo_endpoint.clone() {
+                let info: UserInfo = self
+                    .client
+                    .get(endpoint)
+                    .bearer_auth(&tokens.access_token)
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status())
+                    .map_err(upstream_error)?
+                    .json()
+                    .await
+                    .map_err(upstream_error)?;
+                if info.sub == identity.subject {
+                    identity.email_verified = info.email_verified.as_ref().is_some_and(Verified::is_true);
+                    identity.email = info.email;
+                }
+            }
+        }
+        Ok(identity)
+    }
+
+    async fn refresh_keys(&self, provider: &Provider) -> Result<(), (StatusCode, String)> {
+        let jwks_uri = self
+            .metadata(provider)
+            .await?
+            .jwks_uri
+            .clone()
+            .ok_or_else(|| upstream_error("no jwks_uri in discovery document"))?;
+        let keys: JwkSet = self
+            .client
+            .get(jwks_uri)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(upstream_error)?
+            .json()
+            .await
+            .map_err(upstream_error)?;
+        *provider.keys.write().await = keys;
+        Ok(())
+    }
+
+    async fn github_identity(&self, access_token: &str) -> Result<ExternalIdentity, (StatusCode, String)> {
+        let user: GithubUser = self.github_get(access_token, "/user").await?;
+        // The profile email is whatever the user chose to show; only the emails API says
+        // which addresses GitHub verified
+        let emails: Vec<GithubEmail> = self.github_get(access_token, "/user/emails").await?;
+        let primary = emails.into_iter().find(|email| email.primary);
+
+        Ok(ExternalIdentity {
+            subject: user.id.to_string(),
+            email_verified: primary.as_ref().is_some_and(|email| email.verified),
+            email: primary.map(|email| email.email),
+            username: Some(user.login),
+        })
+    }
+
+    async fn github_get<T: serde::de::DeserializeOwned>(
+        &self,
+        access_token: &str,
+        path: &str,
+    ) -> Result<T, (StatusCode, String)> {
+        self.client
+            .get(format!("{}{}", GITHUB_API_URL, path))
+            .bearer_auth(access_token)
+            .header(reqwest::header::ACCEPT, "application/vnd.github+json")
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(upstream_error)?
+            .json()
+            .await
+            .map_err(upstream_error)
+    }
+}
@@ -0,0 +1,260 @@
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header, request::Parts, HeaderMap, StatusCode},
+};
+use common::auth::{session_state_key, ACCESS_TOKEN_TTL_SECONDS, SESSION_REVOKED};
+use mongodb::{
+    bson::{doc, oid::ObjectId, Bson, Document},
+    Collection, Database,
+};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::OnceLock,
+};
+
+use crate::{auth::verify_jwt, models::Session};
+
+// A request made with a valid access token whose session is still active
+pub struct AuthUser {
+    pub user_id: ObjectId,
+    pub session_id: ObjectId,
+}
+
+#[async_trait]
+impl FromRequestParts<(Database, redis::Client)> for AuthUser {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &(Database, redis::Client),
+    ) -> Result<Self, Self::Rejection> {
+        let unauthorized = || (StatusCode::UNAUTHORIZED, "Invalid token".to_string());
+
+        let token = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(unauthorized)?;
+        let claims = verify_jwt(token)?;
+        let user_id = claims.user_id()?;
+        let session_id = claims.session_id()?;
+
+        let sessions: Collection<Session> = state.0.collection("sessions");
+        sessions
+            .find_one(doc! { "_id": session_id, "user_id": user_id, "revoked_at": null }, None)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+            .ok_or_else(unauthorized)?;
+
+        Ok(AuthUser { user_id, session_id })
+    }
+}
+
+// Proxies allowed to tell us the client's address in X-Forwarded-For, as addresses and
+// CIDR ranges
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TrustedProxies(Vec<(IpAddr, u8)>);
+
+impl TrustedProxies {
+    // A comma separated list such as "10.0.0.0/8, 192.168.1.1"
+    pub fn parse(list: &str) -> Result<Self, String> {
+        list.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let invalid = || format!("Invalid trusted proxy: {}", entry);
+                let (address, prefix) = match entry.split_once('/') {
+                    Some((address, prefix)) => (address, Some(prefix)),
+                    None => (entry, None),
+                };
+                let address = address.parse::<IpAddr>().map_err(|_| invalid())?.to_canonical();
+                let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+                let prefix = match prefix {
+                    Some(prefix) => prefix.parse::<u8>().map_err(|_| invalid())?,
+                    None => max_prefix,
+                };
+                if prefix > max_prefix {
+                    return Err(invalid());
+                }
+                Ok((address, prefix))
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+
+    // TRUSTED_PROXIES; with none configured, X-Forwarded-For is never believed
+    pub fn from_env() -> Result<Self, String> {
+        Self::parse(&std::env::var("TRUSTED_PROXIES").unwrap_or_default())
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.0.iter().any(|&(range, prefix)| match (range, ip) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+                u32::from(range) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(range), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+                u128::from(range) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        })
+    }
+
+    // The client's address: the peer, unless it is a trusted proxy, in which case the right-most
+    // X-Forwarded-For hop that isn't one. Hops further left were written by the client and could
+    // say anything.
+    pub fn client_ip(&self, headers: &HeaderMap, peer: SocketAddr) -> IpAddr {
+        let mut ip = peer.ip().to_canonical();
+        let hops: Vec<&str> = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect();
+        for hop in hops.into_iter().rev() {
+            if !self.contains(ip) {
+                break;
+            }
+            match hop.trim().parse::<IpAddr>() {
+                Ok(hop) => ip = hop.to_canonical(),
+                Err(_) => break,
+            }
+        }
+        ip
+    }
+}
+
+pub fn trusted_proxies() -> &'static TrustedProxies {
+    static TRUSTED_PROXIES: OnceLock<TrustedProxies> = OnceLock::new();
+    TRUSTED_PROXIES.get_or_init(|| TrustedProxies::from_env().expect("Invalid TRUSTED_PROXIES"))
+}
+
+// User agent and IP of the client
+pub fn client_details(headers: &HeaderMap, peer: SocketAddr) -> (Option<String>, String) {
+    let user_agent = headers
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
+    let ip = trusted_proxies().client_ip(headers, peer).to_string();
+    (user_agent, ip)
+}
+
+// Records a new login and returns its session id
+pub async fn create_session(
+    db: &Database,
+    user_id: ObjectId,
+    name: Option<String>,
+    user_agent: Option<String>,
+    ip: String,
+) -> Result<ObjectId, (StatusCode, String)> {
+    let now = chrono::Utc::now();
+    let session = Session {
+        id: None,
+        user_id,
+        name,
+        user_agent,
+        ip: Some(ip),
+        created_at: now,
+        last_used_at: now,
+        revoked_at: None,
+    };
+
+    let sessions: Collection<Session> = db.collection("sessions");
+    let result = sessions
+        .insert_one(session, None)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(result.inserted_id.as_object_id().unwrap())
+}
+
+// Marks an active session as used now; false if it does not exist or was revoked
+pub async fn touch_session(
+    db: &Database,
+    session_id: ObjectId,
+) -> Result<bool, (StatusCode, String)> {
+    let now = mongodb::bson::to_bson(&chrono::Utc::now())
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    let sessions: Collection<Session> = db.collection("sessions");
+    let result = sessions
+        .update_one(
+            doc! { "_id": session_id, "revoked_at": null },
+            doc! { "$set": { "last_used_at": now } },
+            None,
+        )
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(result.matched_count > 0)
+}
+
+// Revokes the user's active sessions matching `filter` along with their refresh tokens,
+// and returns how many were revoked
+pub async fn revoke_sessions(
+    db: &Database,
+    redis: &redis::Client,
+    user_id: ObjectId,
+    filter: Document,
+) -> Result<usize, (StatusCode, String)> {
+    let sessions: Collection<Session> = db.collection("sessions");
+    let mut filter = filter;
+    filter.insert("user_id", user_id);
+    filter.insert("revoked_at", Bson::Null);
+
+    let session_ids: Vec<Bson> = sessions
+        .distinct("_id", filter, None)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if session_ids.is_empty() {
+        return Ok(0);
+    }
+
+    let now = mongodb::bson::to_bson(&chrono::Utc::now())
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    sessions
+        .update_many(
+            doc! { "_id": { "$in": &session_ids } },
+            doc! { "$set": { "revoked_at": now } },
+            None,
+        )
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    let refresh_tokens: Collection<Document> = db.collection("refresh_tokens");
+    refresh_tokens
+        .update_many(
+            doc! { "family_id": { "$in": &session_ids } },
+            doc! { "$set": { "revoked": true } },
+            None,
+        )
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    mark_revoked_in_cache(redis, &session_ids).await;
+
+    Ok(session_ids.len())
+}
+
+// Overwrites what core-service has cached about the sessions, so it stops accepting their
+// tokens immediately rather than when its cache entry expires
+async fn mark_revoked_in_cache(redis: &redis::Client, session_ids: &[Bson]) {
+    let result = async {
+        let mut conn = redis.get_multiplexed_async_connection().await?;
+        let mut pipe = redis::pipe();
+        for session_id in session_ids.iter().filter_map(Bson::as_object_id) {
+            pipe.set_ex(session_state_key(&session_id.to_hex()), SESSION_REVOKED, ACCESS_TOKEN_TTL_SECONDS)
+                .ignore();
+        }
+        pipe.query_async::<()>(&mut conn).await
+    }
+    .await;
+
+    if let Err(e) = result {
+        eprintln!("⚠️ Failed to mark revoked sessions in the cache: {}", e);
+    }
+}
@@ -1,6 +1,8 @@
 #[cfg(test)]
 mod auth_tests {
-    use crate::auth::{hash_password, verify_password, create_jwt};
+    use crate::auth::{create_jwt, generate_refresh_token, hash_password, verify_password};
+    use common::auth::hash_token;
+    use mongodb::bson::oid::ObjectId;
 
     #[test]
     fn test_password_hashing() {
@@ -23,21 +25,36 @@ mod auth_tests {
 
     #[test]
     fn test_jwt_creation() {
-        // Mock JWT_SECRET for tests if not set
-        if std::env::var("JWT_SECRET").is_err() {
-            std::env::set_var("JWT_SECRET", "test_secret_key");
-        }
-        
-        let user_id = "507f1f77bcf86cd799439011";
-        let token = create_jwt(user_id);
+        let token = create_jwt(&ObjectId::new(), &ObjectId::new()).unwrap();
         
         assert!(!token.is_empty());
         assert!(token.contains('.'));  // JWT format
     }
+
+    #[test]
+    fn test_refresh_tokens_are_random() {
+        let token1 = generate_refresh_token();
+        let token2 = generate_refresh_token();
+
+        assert_eq!(token1.len(), 64);
+        assert_ne!(token1, token2);
+    }
+
+    #[test]
+    fn test_refresh_token_hashing() {
+        let token = generate_refresh_token();
+        let hash = hash_token(&token);
+
+        // Stable, so tokens can be looked up by hash, and never the token itself
+        assert_eq!(hash, hash_token(&token));
+        assert_ne!(hash, token);
+        assert_ne!(hash, hash_token(&generate_refresh_token()));
+    }
 }
 
 #[cfg(test)]
 mod validation_tests {
+    use crate::handlers::normalize_email;
     use crate::models::RegisterRequest;
     use validator::Validate;
 
@@ -80,4 +97,874 @@ mod validation_tests {
         };
         assert!(req.validate().is_err());
     }
+
+    #[test]
+    fn test_emails_are_normalized() {
+        assert_eq!(normalize_email("Alice@Example.COM"), "alice@example.com");
+        assert_eq!(normalize_email(" bob@example.com "), "bob@example.com");
+        assert_eq!(normalize_email("alice@example.com"), normalize_email("ALICE@example.com"));
+    }
+}
+
+#[cfg(test)]
+mod session_tests {
+    use crate::auth::{create_jwt, verify_jwt};
+    use crate::sessions::{client_details, TrustedProxies};
+    use axum::http::{header, HeaderMap, HeaderValue};
+    use mongodb::bson::oid::ObjectId;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn test_jwt_carries_session_id() {
+        let user_id = ObjectId::parse_str("507f1f77bcf86cd799439011").unwrap();
+        let session_id = ObjectId::parse_str("507f1f77bcf86cd799439012").unwrap();
+        let token = create_jwt(&user_id, &session_id).unwrap();
+        let claims = verify_jwt(&token).unwrap();
+
+        assert_eq!(claims.sub, "507f1f77bcf86cd799439011");
+        assert_eq!(claims.sid, "507f1f77bcf86cd799439012");
+        assert_eq!(claims.user_id().unwrap(), user_id);
+        assert!(verify_jwt("not-a-token").is_err());
+    }
+
+    #[test]
+    fn test_client_details_use_the_peer_address() {
+        let peer: SocketAddr = "203.0.113.7:51234".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(header::USER_AGENT, HeaderValue::from_static("Firefox"));
+        assert_eq!(client_details(&headers, peer), (Some("Firefox".to_string()), "203.0.113.7".to_string()));
+
+        // No proxy is trusted by default, so the client can't pick its own address
+        headers.insert("x-forwarded-for", HeaderValue::from_static("198.51.100.1"));
+        assert_eq!(client_details(&headers, peer).1, "203.0.113.7");
+    }
+
+    #[test]
+    fn test_forwarded_ip_is_read_from_trusted_proxies_only() {
+        let proxies = TrustedProxies::parse("10.0.0.0/8, 192.168.1.1").unwrap();
+        let proxy: SocketAddr = "10.0.0.2:51234".parse().unwrap();
+        let stranger: SocketAddr = "198.51.100.9:51234".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        assert_eq!(proxies.client_ip(&headers, proxy).to_string(), "10.0.0.2");
+
+        // The client made up the left-most hop; the right-most untrusted one is its real address
+        headers.insert("x-forwarded-for", HeaderValue::from_static("1.2.3.4, 203.0.113.7, 192.168.1.1"));
+        assert_eq!(proxies.client_ip(&headers, proxy).to_string(), "203.0.113.7");
+        assert_eq!(proxies.client_ip(&headers, stranger).to_string(), "198.51.100.9");
+
+        headers.insert("x-forwarded-for", HeaderValue::from_static("garbage, 10.1.2.3"));
+        assert_eq!(proxies.client_ip(&headers, proxy).to_string(), "10.1.2.3");
+
+        let mapped: SocketAddr = "[::ffff:10.0.0.2]:51234".parse().unwrap();
+        headers.insert("x-forwarded-for", HeaderValue::from_static("203.0.113.7"));
+        assert_eq!(proxies.client_ip(&headers, mapped).to_string(), "203.0.113.7");
+    }
+
+    #[test]
+    fn test_trusted_proxies_parse() {
+        let proxies = TrustedProxies::parse("10.0.0.0/8, 2001:db8::/32 ,127.0.0.1").unwrap();
+        assert!(proxies.contains("10.255.0.1".parse().unwrap()));
+        assert!(!proxies.contains("11.0.0.1".parse().unwrap()));
+        assert!(proxies.contains("2001:db8::1".parse().unwrap()));
+        assert!(!proxies.contains("2001:db9::1".parse().unwrap()));
+        assert!(proxies.contains("127.0.0.1".parse().unwrap()));
+        assert!(!proxies.contains("127.0.0.2".parse().unwrap()));
+
+        assert_eq!(TrustedProxies::parse(""), Ok(TrustedProxies::default()));
+        assert!(TrustedProxies::parse("0.0.0.0/0").unwrap().contains("8.8.8.8".parse().unwrap()));
+        assert!(TrustedProxies::parse("10.0.0.0/33").is_err());
+        assert!(TrustedProxies::parse("proxy.internal").is_err());
+    }
+}
+
+#[cfg(test)]
+mod key_tests {
+    use crate::auth::{create_jwt, verify_jwt};
+    use crate::keys::{key_set, KeySet, SigningKey};
+    use jsonwebtoken::{decode_header, encode, jwk::AlgorithmParameters, Algorithm, EncodingKey, Header};
+    use mongodb::bson::oid::ObjectId;
+    use common::auth::Claims;
+    use ring::{rand::SystemRandom, signature::Ed25519KeyPair};
+
+    fn claims() -> Claims {
+        Claims {
+            sub: "507f1f77bcf86cd799439011".to_string(),
+            sid: "507f1f77bcf86cd799439012".to_string(),
+            exp: 4102444800,
+        }
+    }
+
+    fn ed25519_pem() -> Vec<u8> {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        pem::encode(&pem::Pem::new("PRIVATE KEY", pkcs8.as_ref().to_vec())).into_bytes()
+    }
+
+    #[test]
+    fn test_tokens_name_their_signing_key() {
+        let token = create_jwt(&ObjectId::new(), &ObjectId::new()).unwrap();
+        let header = decode_header(&token).unwrap();
+        let signing = key_set().signing_key();
+
+        assert_eq!(header.kid.as_deref(), Some(signing.kid.as_str()));
+        assert_eq!(header.alg, Algorithm::EdDSA);
+        assert!(key_set().jwks().find(&signing.kid).is_some());
+    }
+
+    #[test]
+    fn test_symmetric_tokens_are_rejected() {
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some(key_set().signing_key().kid.clone());
+        let token = encode(&header, &claims(), &EncodingKey::from_secret(b"shared")).unwrap();
+
+        assert!(verify_jwt(&token).is_err());
+    }
+
+    #[test]
+    fn test_retired_keys_still_verify() {
+        let old = SigningKey::from_pem("2024-01", &ed25519_pem()).unwrap();
+        let new = SigningKey::from_pem("2024-02", &ed25519_pem()).unwrap();
+
+        let mut header = Header::new(old.algorithm);
+        header.kid = Some(old.kid.clone());
+        let token = encode(&header, &claims(), old.encoding_key()).unwrap();
+
+        let keys = KeySet::new(vec![old, new], "2024-02").unwrap();
+        assert_eq!(keys.signing_key().kid, "2024-02");
+        assert_eq!(keys.jwks().keys.len(), 2);
+
+        let key = keys.find("2024-01").unwrap();
+        assert_eq!(key.verifying_key().verify(&token).unwrap().sid, "507f1f77bcf86cd799439012");
+
+        // A token signed by one key never verifies against another
+        let other = keys.find("2024-02").unwrap();
+        assert!(other.verifying_key().verify(&token).is_err());
+    }
+
+    #[test]
+    fn test_published_keys_are_public_only() {
+        let key = SigningKey::from_pem("k1", &ed25519_pem()).unwrap();
+        let keys = KeySet::new(vec![key], "k1").unwrap();
+        let jwk = &keys.jwks().keys[0];
+
+        assert_eq!(jwk.common.key_id.as_deref(), Some("k1"));
+        assert!(matches!(jwk.algorithm, AlgorithmParameters::OctetKeyPair(_)));
+        let json = serde_json::to_value(jwk).unwrap();
+        assert_eq!(json["kty"], "OKP");
+        assert_eq!(json["crv"], "Ed25519");
+        assert!(json.get("d").is_none());
+    }
+
+    #[test]
+    fn test_unknown_signing_kid_is_an_error() {
+        let key = SigningKey::from_pem("k1", &ed25519_pem()).unwrap();
+        assert!(KeySet::new(vec![key], "k2").is_err());
+        assert!(SigningKey::from_pem("k1", b"-----BEGIN PUBLIC KEY-----\nAAAA\n-----END PUBLIC KEY-----\n").is_err());
+    }
+}
+
+#[cfg(test)]
+mod mfa_tests {
+    use crate::mfa::*;
+    use crate::models::{LoginResponse, MfaChallenge};
+    use data_encoding::BASE32_NOPAD;
+
+    // RFC 6238 appendix B secret for SHA-1
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn test_totp_rfc_vectors() {
+        // The RFC lists 8 digits; authenticator apps use the last 6
+        assert_eq!(totp_at_step(RFC_SECRET, 59 / 30), "287082");
+        assert_eq!(totp_at_step(RFC_SECRET, 1111111109 / 30), "081804");
+        assert_eq!(totp_at_step(RFC_SECRET, 1234567890 / 30), "005924");
+        assert_eq!(totp_at_step(RFC_SECRET, 2000000000 / 30), "279037");
+    }
+
+    #[test]
+    fn test_verify_totp_allows_one_step_of_drift() {
+        let secret = BASE32_NOPAD.encode(RFC_SECRET);
+        let now = 1111111109;
+        let step = now / TOTP_STEP_SECONDS;
+
+        assert_eq!(verify_totp(&secret, "081804", now), Some(step));
+        assert_eq!(verify_totp(&secret, &totp_at_step(RFC_SECRET, step + 1), now), Some(step + 1));
+        assert_eq!(verify_totp(&secret, &totp_at_step(RFC_SECRET, step - 1), now), Some(step - 1));
+        assert_eq!(verify_totp(&secret, &totp_at_step(RFC_SECRET, step + 2), now), None);
+        assert_eq!(verify_totp(&secret, "08180", now), None);
+        assert_eq!(verify_totp(&secret, "08180a", now), None);
+    }
+
+    #[test]
+    fn test_otpauth_uri() {
+        let secret = generate_secret();
+        assert_eq!(secret.len(), 32);
+
+        let uri = otpauth_uri("WebChat", "alice@example.com", &secret);
+        assert!(uri.starts_with("otpauth://totp/WebChat:alice%40example%2Ecom?secret="));
+        assert!(uri.contains(&format!("secret={}&issuer=WebChat", secret)));
+        assert!(uri.ends_with("&digits=6&period=30"));
+    }
+
+    #[test]
+    fn test_recovery_codes() {
+        let codes = generate_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        assert!(codes.iter().all(|code| code.len() == 14 && code.matches('-').count() == 2));
+
+        let mut unique = codes.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), codes.len());
+
+        // Typed back in capitals or without dashes, a code still matches
+        let code = &codes[0];
+        assert_eq!(hash_recovery_code(code), hash_recovery_code(&code.to_uppercase().replace('-', "")));
+        assert_ne!(hash_recovery_code(code), hash_recovery_code(&codes[1]));
+    }
+
+    #[test]
+    fn test_mfa_challenge_carries_no_tokens() {
+        let response = LoginResponse::MfaRequired(MfaChallenge {
+            mfa_required: true,
+            ticket: "abc".to_string(),
+            methods: vec!["totp", "recovery_code"],
+            expires_in: MFA_TICKET_TTL_SECONDS,
+        });
+        let json = serde_json::to_value(&response).unwrap();
+
+        assert_eq!(json["mfa_required"], true);
+        assert_eq!(json["ticket"], "abc");
+        assert!(json.get("token").is_none());
+    }
+}
+
+#[cfg(test)]
+mod webauthn_tests {
+    use crate::models::WebauthnCredential;
+    use crate::webauthn::*;
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use ciborium::Value;
+    use mongodb::bson::oid::ObjectId;
+    use ring::{
+        rand::SystemRandom,
+        signature::{EcdsaKeyPair, Ed25519KeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING},
+    };
+    use sha2::{Digest, Sha256};
+
+    fn rp() -> RelyingParty {
+        RelyingParty {
+            id: "chat.example.com".to_string(),
+            name: "WebChat".to_string(),
+            origins: vec!["https://chat.example.com".to_string()],
+        }
+    }
+
+    fn cbor(value: &Value) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(value, &mut bytes).unwrap();
+        bytes
+    }
+
+    fn cose_ed25519(public_key: &[u8]) -> Vec<u8> {
+        cbor(&Value::Map(vec![
+            (Value::from(1), Value::from(1)),
+            (Value::from(3), Value::from(EDDSA)),
+            (Value::from(-1), Value::from(6)),
+            (Value::from(-2), Value::Bytes(public_key.to_vec())),
+        ]))
+    }
+
+    fn client_data(kind: &str, challenge: &str, origin: &str) -> String {
+        let json = serde_json::json!({ "type": kind, "challenge": challenge, "origin": origin });
+        URL_SAFE_NO_PAD.encode(json.to_string())
+    }
+
+    fn auth_data(rp_id: &str, flags: u8, sign_count: u32, attested: Option<(&[u8], &[u8])>) -> Vec<u8> {
+        let mut data = Sha256::digest(rp_id.as_bytes()).to_vec();
+        data.push(flags);
+        data.extend_from_slice(&sign_count.to_be_bytes());
+        if let Some((credential_id, cose_key)) = attested {
+            data.extend_from_slice(&[0u8; 16]);
+            data.extend_from_slice(&(credential_id.len() as u16).to_be_bytes());
+            data.extend_from_slice(credential_id);
+            data.extend_from_slice(cose_key);
+        }
+        data
+    }
+
+    struct Authenticator {
+        key: Ed25519KeyPair,
+        credential_id: Vec<u8>,
+    }
+
+    impl Authenticator {
+        fn new() -> Self {
+            let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+            let key = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+            Self { key, credential_id: vec![7u8; 16] }
+        }
+
+        fn register(&self, challenge: &str, origin: &str, extensions: &[u8]) -> RegistrationCredential {
+            let cose_key = cose_ed25519(self.key.public_key().as_ref());
+            let mut data = auth_data("chat.example.com", 0x45, 0, Some((&self.credential_id, &cose_key)));
+            data.extend_from_slice(extensions);
+            let attestation = cbor(&Value::Map(vec![
+                (Value::from("fmt"), Value::from("none")),
+                (Value::from("attStmt"), Value::Map(Vec::new())),
+                (Value::from("authData"), Value::Bytes(data)),
+            ]));
+            serde_json::from_value(serde_json::json!({
+                "id": URL_SAFE_NO_PAD.encode(&self.credential_id),
+                "rawId": URL_SAFE_NO_PAD.encode(&self.credential_id),
+                "type": "public-key",
+                "response": {
+                    "clientDataJSON": client_data("webauthn.create", challenge, origin),
+                    "attestationObject": URL_SAFE_NO_PAD.encode(attestation),
+                    "transports": ["internal"],
+                },
+            }))
+            .unwrap()
+        }
+
+        fn assert(&self, challenge: &str, flags: u8, sign_count: u32) -> AuthenticationCredential {
+            let client_data_json = client_data("webauthn.get", challenge, "https://chat.example.com");
+            let data = auth_data("chat.example.com", flags, sign_count, None);
+            let mut message = data.clone();
+            message.extend_from_slice(&Sha256::digest(URL_SAFE_NO_PAD.decode(&client_data_json).unwrap()));
+            let signature = self.key.sign(&message);
+            serde_json::from_value(serde_json::json!({
+                "id": URL_SAFE_NO_PAD.encode(&self.credential_id),
+                "rawId": URL_SAFE_NO_PAD.encode(&self.credential_id),
+                "type": "public-key",
+                "response": {
+                    "clientDataJSON": client_data_json,
+                    "authenticatorData": URL_SAFE_NO_PAD.encode(data),
+                    "signature": URL_SAFE_NO_PAD.encode(signature.as_ref()),
+                },
+            }))
+            .unwrap()
+        }
+
+        fn stored(&self, user_id: ObjectId, sign_count: u32) -> WebauthnCredential {
+            WebauthnCredential {
+                id: Some(ObjectId::new()),
+                user_id,
+                credential_id: URL_SAFE_NO_PAD.encode(&self.credential_id),
+                public_key: URL_SAFE_NO_PAD.encode(cose_ed25519(self.key.public_key().as_ref())),
+                sign_count,
+                name: "Laptop".to_string(),
+                aaguid: "00".repeat(16),
+                transports: Vec::new(),
+                created_at: chrono::Utc::now(),
+                last_used_at: None,
+            }
+        }
+    }
+
+    #[test]
+    fn test_registration_extracts_credential() {
+        let authenticator = Authenticator::new();
+        let challenge = generate_challenge();
+        let credential = authenticator.register(&challenge, "https://chat.example.com", &[]);
+
+        let verified = verify_registration(&rp(), &credential).unwrap();
+        assert_eq!(verified.challenge, challenge);
+        assert_eq!(verified.credential_id, URL_SAFE_NO_PAD.encode(&authenticator.credential_id));
+        assert_eq!(verified.transports, vec!["internal"]);
+        let public_key = URL_SAFE_NO_PAD.decode(&verified.public_key).unwrap();
+        assert_eq!(
+            PublicKey::from_cose(&public_key).unwrap(),
+            PublicKey::Ed25519(authenticator.key.public_key().as_ref().to_vec())
+        );
+    }
+
+    #[test]
+    fn test_registration_key_ends_before_extensions() {
+        let authenticator = Authenticator::new();
+        let extensions = cbor(&Value::Map(vec![(Value::from("credProtect"), Value::from(2))]));
+        let credential = authenticator.register("c", "https://chat.example.com", &extensions);
+
+        let verified = verify_registration(&rp(), &credential).unwrap();
+        let public_key = URL_SAFE_NO_PAD.decode(&verified.public_key).unwrap();
+        assert_eq!(public_key, cose_ed25519(authenticator.key.public_key().as_ref()));
+    }
+
+    #[test]
+    fn test_registration_rejects_other_origins_and_sites() {
+        let authenticator = Authenticator::new();
+        let credential = authenticator.register("c", "https://evil.example.net", &[]);
+        assert_eq!(verify_registration(&rp(), &credential).unwrap_err(), CeremonyError::OriginMismatch);
+
+        let mut other_site = rp();
+        other_site.id = "example.org".to_string();
+        other_site.origins = vec!["https://chat.example.com".to_string()];
+        let credential = authenticator.register("c", "https://chat.example.com", &[]);
+        assert_eq!(
+            verify_registration(&other_site, &credential).unwrap_err(),
+            CeremonyError::WrongRelyingParty
+        );
+    }
+
+    #[test]
+    fn test_assertion_verifies_signature() {
+        let authenticator = Authenticator::new();
+        let stored = authenticator.stored(ObjectId::new(), 4);
+
+        let assertion = verify_assertion(&rp(), &stored, &authenticator.assert("c", 0x05, 5)).unwrap();
+        assert_eq!(assertion.challenge, "c");
+        assert_eq!(assertion.sign_count, 5);
+
+        // Another key's signature doesn't verify against the stored key
+        let impostor = Authenticator::new();
+        assert_eq!(
+            verify_assertion(&rp(), &stored, &impostor.assert("c", 0x05, 5)).unwrap_err(),
+            CeremonyError::BadSignature
+        );
+    }
+
+    #[test]
+    fn test_assertion_requires_user_verification() {
+        let authenticator = Authenticator::new();
+        let stored = authenticator.stored(ObjectId::new(), 0);
+        // User present but not verified
+        assert_eq!(
+            verify_assertion(&rp(), &stored, &authenticator.assert("c", 0x01, 0)).unwrap_err(),
+            CeremonyError::UserNotVerified
+        );
+    }
+
+    #[test]
+    fn test_assertion_rejects_other_users_handle() {
+        let authenticator = Authenticator::new();
+        let stored = authenticator.stored(ObjectId::new(), 0);
+        let mut credential = authenticator.assert("c", 0x05, 0);
+        credential.response.user_handle = Some(user_handle(&ObjectId::new()));
+        assert!(verify_assertion(&rp(), &stored, &credential).is_err());
+
+        credential.response.user_handle = Some(user_handle(&stored.user_id));
+        assert!(verify_assertion(&rp(), &stored, &credential).is_ok());
+    }
+
+    #[test]
+    fn test_es256_signature_verifies() {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng).unwrap();
+        let point = key.public_key().as_ref();
+        let cose_key = cbor(&Value::Map(vec![
+            (Value::from(1), Value::from(2)),
+            (Value::from(3), Value::from(ES256)),
+            (Value::from(-1), Value::from(1)),
+            (Value::from(-2), Value::Bytes(point[1..33].to_vec())),
+            (Value::from(-3), Value::Bytes(point[33..].to_vec())),
+        ]));
+
+        let public_key = PublicKey::from_cose(&cose_key).unwrap();
+        let signature = key.sign(&rng, b"message").unwrap();
+        assert!(public_key.verify(b"message", signature.as_ref()).is_ok());
+        assert_eq!(public_key.verify(b"other", signature.as_ref()), Err(CeremonyError::BadSignature));
+    }
+
+    #[test]
+    fn test_unsupported_key_rejected() {
+        // An ES384 key
+        let cose_key = cbor(&Value::Map(vec![
+            (Value::from(1), Value::from(2)),
+            (Value::from(3), Value::from(-35)),
+            (Value::from(-1), Value::from(2)),
+        ]));
+        assert_eq!(PublicKey::from_cose(&cose_key), Err(CeremonyError::UnsupportedAlgorithm));
+    }
+
+    #[test]
+    fn test_key_type_must_match_algorithm() {
+        // An Ed25519 key claiming to be for ES256
+        let cose_key = cbor(&Value::Map(vec![
+            (Value::from(1), Value::from(1)),
+            (Value::from(3), Value::from(ES256)),
+            (Value::from(-1), Value::from(6)),
+            (Value::from(-2), Value::Bytes(vec![0; 32])),
+        ]));
+        assert_eq!(PublicKey::from_cose(&cose_key), Err(CeremonyError::UnsupportedAlgorithm));
+        assert_eq!(PublicKey::from_cose(b"\xa1\x01"), Err(CeremonyError::Malformed("COSE key")));
+    }
+
+    #[test]
+    fn test_sign_count_must_increase() {
+        // Authenticators without a counter always report zero
+        assert!(sign_count_is_valid(0, 0));
+        assert!(sign_count_is_valid(0, 1));
+        assert!(sign_count_is_valid(5, 6));
+        // A repeated or lower value means a cloned authenticator
+        assert!(!sign_count_is_valid(5, 5));
+        assert!(!sign_count_is_valid(5, 2));
+        assert!(!sign_count_is_valid(5, 0));
+    }
+
+    #[test]
+    fn test_pending_ceremony_round_trips() {
+        let pending = PendingCeremony::Registration { user_id: ObjectId::new() };
+        let json = serde_json::to_string(&pending).unwrap();
+        assert_eq!(serde_json::from_str::<PendingCeremony>(&json).unwrap(), pending);
+        assert_ne!(
+            serde_json::from_str::<PendingCeremony>(&json).unwrap(),
+            PendingCeremony::Authentication
+        );
+    }
+}
+
+#[cfg(test)]
+mod email_token_tests {
+    use crate::auth::{create_jwt, hash_password, verify_jwt};
+    use crate::email_tokens::*;
+    use crate::keys::key_set;
+    use crate::mailer::{self, Email, FileMailer, Mailer};
+    use crate::models::User;
+    use jsonwebtoken::{encode, Header};
+    use mongodb::bson::oid::ObjectId;
+
+    fn user() -> User {
+        User {
+            id: Some(ObjectId::new()),
+            username: "alice".to_string(),
+            email: "alice@example.com".to_string(),
+            password_hash: hash_password("password123").unwrap(),
+            created_at: chrono::Utc::now(),
+            last_seen: None,
+            email_verified_at: None,
+            bot: false,
+            owner_id: None,
+        }
+    }
+
+    #[test]
+    fn test_token_round_trip() {
+        let user = user();
+        let token = issue(&user, Purpose::ResetPassword).unwrap();
+        let claims = verify(&token, Purpose::ResetPassword).unwrap();
+
+        assert_eq!(claims.user_id().unwrap(), user.id.unwrap());
+        assert_eq!(claims.email, user.email);
+        assert_eq!(claims.pwd, Some(password_fingerprint(&user.password_hash)));
+
+        // Verification tokens aren't tied to the password
+        let token = issue(&user, Purpose::VerifyEmail).unwrap();
+        assert!(verify(&token, Purpose::VerifyEmail).unwrap().pwd.is_none());
+    }
+
+    #[test]
+    fn test_tokens_are_unique() {
+        let user = user();
+        let first = verify(&issue(&user, Purpose::VerifyEmail).unwrap(), Purpose::VerifyEmail).unwrap();
+        let second = verify(&issue(&user, Purpose::VerifyEmail).unwrap(), Purpose::VerifyEmail).unwrap();
+        assert_ne!(first.jti, second.jti);
+    }
+
+    #[test]
+    fn test_token_only_valid_for_its_purpose() {
+        let user = user();
+        let verification = issue(&user, Purpose::VerifyEmail).unwrap();
+        assert!(verify(&verification, Purpose::ResetPassword).is_err());
+        let unlock = issue(&user, Purpose::UnlockAccount).unwrap();
+        assert!(verify(&unlock, Purpose::ResetPassword).is_err());
+        assert!(verify(&unlock, Purpose::UnlockAccount).is_ok());
+
+        // Neither kind works as an access token, nor the other way around
+        assert!(verify_jwt(&verification).is_err());
+        let access = create_jwt(&user.id.unwrap(), &ObjectId::new()).unwrap();
+        assert!(verify(&access, Purpose::VerifyEmail).is_err());
+        assert!(verify(&access, Purpose::ResetPassword).is_err());
+    }
+
+    #[test]
+    fn test_expired_token_rejected() {
+        let user = user();
+        let claims = EmailTokenClaims {
+            sub: user.id.unwrap().to_hex(),
+            aud: "webchat:verify-email".to_string(),
+            jti: ObjectId::new().to_hex(),
+            exp: (chrono::Utc::now() - chrono::Duration::hours(1)).timestamp() as usize,
+            email: user.email.clone(),
+            pwd: None,
+        };
+        let key = key_set().signing_key();
+        let mut header = Header::new(key.algorithm);
+        header.kid = Some(key.kid.clone());
+        let token = encode(&header, &claims, key.encoding_key()).unwrap();
+
+        assert!(verify(&token, Purpose::VerifyEmail).is_err());
+    }
+
+    #[test]
+    fn test_password_change_changes_fingerprint() {
+        let old = hash_password("password123").unwrap();
+        let new = hash_password("password123").unwrap();
+        assert_eq!(password_fingerprint(&old), password_fingerprint(&old));
+        assert_ne!(password_fingerprint(&old), password_fingerprint(&new));
+    }
+
+    #[test]
+    fn test_emails_link_to_the_frontend() {
+        let email = mailer::password_reset_email("alice@example.com", "alice", "abc");
+        assert_eq!(email.to, "alice@example.com");
+        assert!(email.body.contains("/reset-password?token=abc"));
+
+        let email = mailer::verification_email("alice@example.com", "alice", "abc");
+        assert!(email.body.contains("/verify-email?token=abc"));
+    }
+
+    #[tokio::test]
+    async fn test_file_mailer_writes_eml() {
+        let dir = std::env::temp_dir().join(format!("webchat-mail-{}", ObjectId::new()));
+        let mailer = FileMailer::new(&dir, "WebChat <no-reply@localhost>");
+        let email = Email {
+            to: "alice@example.com".to_string(),
+            subject: "Hello".to_string(),
+            body: "Body".to_string(),
+        };
+        mailer.send(&email).await.unwrap();
+
+        let files: Vec<_> = std::fs::read_dir(&dir).unwrap().collect::<Result<_, _>>().unwrap();
+        assert_eq!(files.len(), 1);
+        let contents = std::fs::read_to_string(files[0].path()).unwrap();
+        assert!(contents.contains("To: alice@example.com\r\n"));
+        assert!(contents.contains("Subject: Hello\r\n"));
+        assert!(contents.ends_with("\r\n\r\nBody\r\n"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod throttle_tests {
+    use crate::auth::hash_password;
+    use crate::throttle::*;
+
+    #[test]
+    fn test_delays_grow_after_free_attempts() {
+        assert_eq!(delay_after(0), 0);
+        assert_eq!(delay_after(FREE_ATTEMPTS - 1), 0);
+        assert_eq!(delay_after(FREE_ATTEMPTS), 1);
+        assert_eq!(delay_after(FREE_ATTEMPTS + 1), 2);
+        assert_eq!(delay_after(FREE_ATTEMPTS + 3), 8);
+        assert_eq!(delay_after(FREE_ATTEMPTS + 10), MAX_DELAY_SECONDS);
+        assert_eq!(delay_after(u64::MAX), MAX_DELAY_SECONDS);
+    }
+
+    #[test]
+    fn test_retry_after_counts_from_last_failure() {
+        let last = 1_700_000_000;
+        assert_eq!(retry_after(1, last, last), None);
+        assert_eq!(retry_after(FREE_ATTEMPTS + 2, last, last + 1), Some(3));
+        assert_eq!(retry_after(FREE_ATTEMPTS + 2, last, last + 4), None);
+    }
+
+    #[test]
+    fn test_account_id_ignores_case_and_spaces() {
+        assert_eq!(account_id("Alice@Example.com "), account_id("alice@example.com"));
+        assert_ne!(account_id("alice@example.com"), account_id("bob@example.com"));
+        // Addresses themselves never end up in Redis keys
+        assert!(!account_id("alice@example.com").contains("alice"));
+    }
+
+    #[test]
+    fn test_unknown_account_never_matches() {
+        let hash = hash_password("password123").unwrap();
+        assert!(verify_password_or_dummy("password123", Some(&hash)).unwrap());
+        assert!(!verify_password_or_dummy("wrong", Some(&hash)).unwrap());
+        assert!(!verify_password_or_dummy("password123", None).unwrap());
+        assert!(!verify_password_or_dummy("not the password of any account", None).unwrap());
+    }
+}
+
+#[cfg(test)]
+mod oidc_tests {
+    use crate::keys::{KeySet, SigningKey};
+    use crate::oidc::*;
+    use axum::http::StatusCode;
+    use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+    use std::collections::HashMap;
+
+    const ISSUER: &str = "https://idp.example.com/realms/webchat";
+    const CLIENT_ID: &str = "webchat";
+
+    // Stands in for the identity provider's signing keys
+    fn idp_keys() -> KeySet {
+        KeySet::new(vec![SigningKey::generate("idp-1")], "idp-1").unwrap()
+    }
+
+    fn id_token(keys: &KeySet, claims: &serde_json::Value) -> String {
+        let key = keys.signing_key();
+        let mut header = Header::new(key.algorithm);
+        header.kid = Some(key.kid.clone());
+        encode(&header, claims, key.encoding_key()).unwrap()
+    }
+
+    fn claims() -> serde_json::Value {
+        serde_json::json!({
+            "iss": ISSUER,
+            "aud": CLIENT_ID,
+            "sub": "248289761001",
+            "exp": chrono::Utc::now().timestamp() + 300,
+            "nonce": "n-0S6_WzA2Mj",
+            "email": "alice@example.com",
+            "email_verified": true,
+            "preferred_username": "alice",
+        })
+    }
+
+    fn validate(keys: &KeySet, token: &str) -> Result<IdTokenClaims, (StatusCode, String)> {
+        validate_id_token(token, &keys.jwks(), ISSUER, CLIENT_ID, "n-0S6_WzA2Mj")
+    }
+
+    #[test]
+    fn test_pkce_challenge_matches_rfc_7636() {
+        assert_eq!(
+            code_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+
+    #[test]
+    fn test_valid_id_token() {
+        let keys = idp_keys();
+        let claims = validate(&keys, &id_token(&keys, &claims())).unwrap();
+        assert_eq!(claims.sub, "248289761001");
+        assert_eq!(claims.email.as_deref(), Some("alice@example.com"));
+        assert_eq!(claims.preferred_username.as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn test_id_token_claims_are_checked() {
+        let keys = idp_keys();
+        let cases = [
+            ("nonce", serde_json::json!("replayed")),
+            ("aud", serde_json::json!("another-client")),
+            ("iss", serde_json::json!("https://evil.example.com")),
+            ("exp", serde_json::json!(chrono::Utc::now().timestamp() - 3600)),
+        ];
+        for (claim, value) in cases {
+            let mut tampered = claims();
+            tampered[claim] = value;
+            let err = validate(&keys, &id_token(&keys, &tampered)).unwrap_err();
+            assert_eq!(err.0, StatusCode::UNAUTHORIZED, "{} was not checked", claim);
+        }
+    }
+
+    #[test]
+    fn test_id_token_must_be_signed_by_the_provider() {
+        let keys = idp_keys();
+        // Same kid, other key
+        let impostor = KeySet::new(vec![SigningKey::generate("idp-1")], "idp-1").unwrap();
+        assert!(validate(&keys, &id_token(&impostor, &claims())).is_err());
+
+        // Signed with the client secret
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some("idp-1".to_string());
+        let token = encode(&header, &claims(), &EncodingKey::from_secret(b"client-secret")).unwrap();
+        assert!(validate(&keys, &token).is_err());
+    }
+
+    #[test]
+    fn test_string_email_verified_is_understood() {
+        let claims: IdTokenClaims =
+            serde_json::from_str(r#"{"sub":"1","email_verified":"true"}"#).unwrap();
+        assert!(matches!(claims.email_verified, Some(Verified::Text(ref v)) if v == "true"));
+    }
+
+    #[test]
+    fn test_providers_from_config() {
+        let vars: HashMap<&str, &str> = HashMap::from([
+            ("OIDC_PROVIDERS", "keycloak, github"),
+            ("OIDC_KEYCLOAK_ISSUER", "https://sso.example.com/realms/acme/"),
+            ("OIDC_KEYCLOAK_CLIENT_ID", "webchat"),
+            ("OIDC_KEYCLOAK_CLIENT_SECRET", "secret"),
+            ("OIDC_KEYCLOAK_NAME", "Acme SSO"),
+            ("OIDC_GITHUB_TYPE", "github"),
+            ("OIDC_GITHUB_CLIENT_ID", "gh"),
+            ("OIDC_GITHUB_CLIENT_SECRET", "secret"),
+        ]);
+        let providers = Providers::from_vars(|name| vars.get(name).map(|v| v.to_string())).unwrap();
+
+        let keycloak = providers.get("keycloak").unwrap();
+        assert_eq!(keycloak.name, "Acme SSO");
+        assert_eq!(
+            keycloak.kind,
+            ProviderKind::Oidc { issuer: "https://sso.example.com/realms/acme".to_string() }
+        );
+        assert_eq!(providers.get("github").unwrap().kind, ProviderKind::Github);
+        assert_eq!(providers.list().len(), 2);
+        assert!(providers.get("google").is_none());
+    }
+
+    #[test]
+    fn test_incomplete_provider_config_is_an_error() {
+        let vars: HashMap<&str, &str> = HashMap::from([
+            ("OIDC_PROVIDERS", "google"),
+            ("OIDC_GOOGLE_ISSUER", "https://accounts.google.com"),
+            ("OIDC_GOOGLE_CLIENT_ID", "webchat"),
+        ]);
+        let err = Providers::from_vars(|name| vars.get(name).map(|v| v.to_string())).err().unwrap();
+        assert!(err.contains("OIDC_GOOGLE_CLIENT_SECRET"));
+
+        assert!(Providers::from_vars(|_| None).unwrap().list().is_empty());
+    }
+
+    #[test]
+    fn test_suggested_username() {
+        assert_eq!(suggested_username(&[Some("alice"), Some("a@example.com")]), "alice");
+        // Too short, or only symbols: fall back to the email's local part
+        assert_eq!(suggested_username(&[Some("al"), Some("alice.smith@example.com")]), "alice.smith");
+        assert_eq!(suggested_username(&[None, Some("bob@example.com")]), "bob");
+        let long = "x".repeat(50);
+        assert_eq!(suggested_username(&[Some(&long)]).len(), 30);
+        assert!(suggested_username(&[Some("!!"), None]).starts_with("user"));
+    }
+}
+
+#[cfg(test)]
+mod api_token_tests {
+    use crate::api_tokens::*;
+    use crate::models::{ApiTokenKind, CreatePersonalTokenRequest};
+    use common::auth::{hash_token, Credentials, Scope};
+    use mongodb::bson::oid::ObjectId;
+    use validator::Validate;
+
+    #[test]
+    fn test_tokens_are_prefixed_by_kind() {
+        let bot = generate(ApiTokenKind::Bot);
+        let personal = generate(ApiTokenKind::Personal);
+        assert!(bot.starts_with("wcb_"));
+        assert!(personal.starts_with("wcp_"));
+        assert_ne!(generate(ApiTokenKind::Bot), bot);
+        assert_ne!(hash_token(&bot), bot);
+
+        // Each kind is only accepted under its own scheme
+        let header = format!("Bot {}", bot);
+        assert_eq!(Credentials::from_authorization(&header), Some(Credentials::BotToken(&bot)));
+        let header = format!("Bearer {}", personal);
+        assert_eq!(Credentials::from_authorization(&header), Some(Credentials::PersonalToken(&personal)));
+    }
+
+    #[test]
+    fn test_bot_emails_are_unroutable() {
+        let bot_id = ObjectId::new();
+        assert_eq!(bot_email(&bot_id), format!("{}@bots.invalid", bot_id.to_hex()));
+    }
+
+    #[test]
+    fn test_personal_token_request_validation() {
+        let request: CreatePersonalTokenRequest =
+            serde_json::from_str(r#"{"name":"ci","scopes":["messages:read","messages:write"]}"#).unwrap();
+        assert!(request.validate().is_ok());
+        assert_eq!(request.scopes, vec![Scope::MessagesRead, Scope::MessagesWrite]);
+        assert!(request.expires_in_days.is_none());
+
+        let request: CreatePersonalTokenRequest = serde_json::from_str(r#"{"name":"ci","scopes":[]}"#).unwrap();
+        assert!(request.validate().is_err());
+        let request: CreatePersonalTokenRequest =
+            serde_json::from_str(r#"{"name":"ci","scopes":["messages:read"],"expires_in_days":400}"#).unwrap();
+        assert!(request.validate().is_err());
+        assert!(serde_json::from_str::<CreatePersonalTokenRequest>(r#"{"name":"ci","scopes":["admin"]}"#).is_err());
+    }
 }
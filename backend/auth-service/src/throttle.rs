@@ -0,0 +1,182 @@
+use axum::http::StatusCode;
+use redis::AsyncCommands;
+use sha2::{Digest, Sha256};
+use std::sync::OnceLock;
+
+use crate::auth::{hash_password, verify_password};
+
+// Failed logins are counted over a sliding window that restarts with each failure
+pub const FAILURE_WINDOW_SECONDS: i64 = 15 * 60;
+// Failures on one account before each further attempt has to wait
+pub const FREE_ATTEMPTS: u64 = 3;
+// Longest wait between attempts on one account
+pub const MAX_DELAY_SECONDS: i64 = 60;
+// Failures on one account before it is locked and an unlock link emailed
+pub const LOCKOUT_THRESHOLD: u64 = 10;
+pub const LOCKOUT_SECONDS: u64 = 15 * 60;
+// Failures from one IP, across all accounts, before it is turned away for the window
+pub const IP_MAX_FAILURES: u64 = 100;
+
+// What a login attempt is counted against: the client's IP and the account it targets
+#[derive(Debug, Clone)]
+pub struct LoginAttempt {
+    ip_key: String,
+    account_key: String,
+}
+
+impl LoginAttempt {
+    pub fn new(ip: &str, email: &str) -> Self {
+        Self {
+            ip_key: format!("login:failures:ip:{}", ip),
+            account_key: format!("login:failures:account:{}", account_id(email)),
+        }
+    }
+
+    // The hashed account the attempt targets, for logs
+    pub fn account(&self) -> &str {
+        self.account_key.trim_start_matches("login:failures:account:")
+    }
+
+    fn lock_key(&self) -> String {
+        self.account_key.replacen("failures", "lock", 1)
+    }
+}
+
+// Counters are kept per address whether or not it has an account, so locking and delays
+// don't reveal which addresses are registered. Hashed to keep addresses out of Redis.
+pub fn account_id(email: &str) -> String {
+    hex::encode(Sha256::digest(email.trim().to_lowercase().as_bytes()))
+}
+
+// Seconds to wait after `failures` failed attempts before the next one: doubling from
+// one second once the free attempts are used, up to MAX_DELAY_SECONDS
+pub fn delay_after(failures: u64) -> i64 {
+    if failures < FREE_ATTEMPTS {
+        return 0;
+    }
+    let exponent = (failures - FREE_ATTEMPTS).min(16) as u32;
+    2i64.pow(exponent).min(MAX_DELAY_SECONDS)
+}
+
+// Seconds until the next attempt is allowed, if it has to wait
+pub fn retry_after(failures: u64, last_failure: i64, now: i64) -> Option<i64> {
+    let wait = last_failure + delay_after(failures) - now;
+    (wait > 0).then_some(wait)
+}
+
+fn internal_error(e: impl ToString) -> (StatusCode, String) {
+    (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+}
+
+fn too_many_attempts(wait: i64) -> (StatusCode, String) {
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        format!("Too many failed attempts, try again in {} seconds", wait),
+    )
+}
+
+// Rejects the attempt before the password is checked if the IP or the account has
+// failed too often
+pub async fn check(redis: &redis::Client, attempt: &LoginAttempt) -> Result<(), (StatusCode, String)> {
+    let mut conn = redis.get_multiplexed_async_connection().await.map_err(internal_error)?;
+    let (ip_failures, account_failures, last_failure, lock_ttl): (Option<u64>, Option<u64>, Option<i64>, i64) =
+        redis::pipe()
+            .hget(&attempt.ip_key, "count")
+            .hget(&attempt.account_key, "count")
+            .hget(&attempt.account_key, "last")
+            .ttl(attempt.lock_key())
+            .query_async(&mut conn)
+            .await
+            .map_err(internal_error)?;
+
+    if lock_ttl > 0 {
+        return Err((
+            StatusCode::LOCKED,
+            "Account temporarily locked after too many failed attempts; check your email to unlock it"
+                .to_string(),
+        ));
+    }
+    if ip_failures.unwrap_or(0) >= IP_MAX_FAILURES {
+        let ttl: i64 = conn.ttl(&attempt.ip_key).await.map_err(internal_error)?;
+        return Err(too_many_attempts(ttl.max(1)));
+    }
+    if let (Some(failures), Some(last)) = (account_failures, last_failure) {
+        if let Some(wait) = retry_after(failures, last, chrono::Utc::now().timestamp()) {
+            return Err(too_many_attempts(wait));
+        }
+    }
+    Ok(())
+}
+
+// Counts a failed attempt. True if it just locked the account, in which case the caller
+// emails the owner, if there is one, an unlock link.
+pub async fn record_failure(
+    redis: &redis::Client,
+    attempt: &LoginAttempt,
+) -> Result<bool, (StatusCode, String)> {
+    let now = chrono::Utc::now().timestamp();
+    let mut conn = redis.get_multiplexed_async_connection().await.map_err(internal_error)?;
+    let (account_failures,): (u64,) = redis::pipe()
+        .hincr(&attempt.ip_key, "count", 1)
+        .ignore()
+        .expire(&attempt.ip_key, FAILURE_WINDOW_SECONDS)
+        .ignore()
+        .hincr(&attempt.account_key, "count", 1)
+        .hset(&attempt.account_key, "last", now)
+        .ignore()
+        .expire(&attempt.account_key, FAILURE_WINDOW_SECONDS)
+        .ignore()
+        .query_async(&mut conn)
+        .await
+        .map_err(internal_error)?;
+
+    if account_failures < LOCKOUT_THRESHOLD {
+        return Ok(false);
+    }
+    // The counter restarts so the account gets its free attempts back once unlocked
+    let (locked,): (Option<String>,) = redis::pipe()
+        .set_options(
+            attempt.lock_key(),
+            1,
+            redis::SetOptions::default()
+                .conditional_set(redis::ExistenceCheck::NX)
+                .with_expiration(redis::SetExpiry::EX(LOCKOUT_SECONDS)),
+        )
+        .del(&attempt.account_key)
+        .ignore()
+        .query_async(&mut conn)
+        .await
+        .map_err(internal_error)?;
+    Ok(locked.is_some())
+}
+
+// A successful login forgets the account's failures. The IP's stay, so one valid
+// account can't be used to reset the counter while guessing others.
+pub async fn clear(redis: &redis::Client, attempt: &LoginAttempt) -> Result<(), (StatusCode, String)> {
+    let mut conn = redis.get_multiplexed_async_connection().await.map_err(internal_error)?;
+    conn.del::<_, ()>(&attempt.account_key).await.map_err(internal_error)
+}
+
+// Lifts a lockout early, from the link in the unlock email
+pub async fn unlock(redis: &redis::Client, email: &str) -> Result<(), (StatusCode, String)> {
+    let attempt = LoginAttempt::new("", email);
+    let mut conn = redis.get_multiplexed_async_connection().await.map_err(internal_error)?;
+    conn.del::<_, ()>(&[attempt.lock_key(), attempt.account_key])
+        .await
+        .map_err(internal_error)
+}
+
+// Stand-in hash checked when the account doesn't exist
+pub fn dummy_hash() -> &'static str {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    DUMMY_HASH.get_or_init(|| {
+        hash_password("not the password of any account").expect("Failed to hash dummy password")
+    })
+}
+
+// Verifies the password against the user's hash, or against a throwaway hash when there
+// is no such user, so both cases take the same argon2 work
+pub fn verify_password_or_dummy(password: &str, password_hash: Option<&str>) -> Result<bool, (StatusCode, String)> {
+    let matches = verify_password(password, password_hash.unwrap_or(dummy_hash())).map_err(internal_error)?;
+    Ok(matches && password_hash.is_some())
+}
@@ -0,0 +1,508 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use axum::http::StatusCode;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ciborium::Value;
+use coset::{
+    iana::{self, EnumI64},
+    CborSerializable, CoseKey, Label,
+};
+use mongodb::{
+    bson::{doc, oid::ObjectId},
+    Collection, Database,
+};
+use redis::AsyncCommands;
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::sync::OnceLock;
+
+use crate::models::{User, WebauthnCredential};
+
+// How long the browser has to complete a ceremony
+pub const CEREMONY_TIMEOUT_SECONDS: u64 = 300;
+
+// COSE algorithm ids we can verify, in order of preference
+pub const ES256: i64 = iana::Algorithm::ES256 as i64;
+pub const EDDSA: i64 = iana::Algorithm::EdDSA as i64;
+pub const RS256: i64 = iana::Algorithm::RS256 as i64;
+
+// authenticatorData flags
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL: u8 = 0x40;
+
+// Relying party settings: the domain passkeys are bound to and the origins allowed to use them
+#[derive(Debug, Clone)]
+pub struct RelyingParty {
+    pub id: String,
+    pub name: String,
+    pub origins: Vec<String>,
+}
+
+impl RelyingParty {
+    // WEBAUTHN_RP_ID is the site's domain, WEBAUTHN_ORIGINS a comma separated list of
+    // origins the frontend is served from
+    pub fn from_env() -> Self {
+        let id = std::env::var("WEBAUTHN_RP_ID").unwrap_or_else(|_| "localhost".to_string());
+        let name = std::env::var("WEBAUTHN_RP_NAME").unwrap_or_else(|_| "WebChat".to_string());
+        let origins = std::env::var("WEBAUTHN_ORIGINS")
+            .unwrap_or_else(|_| "http://localhost:3000".to_string())
+            .split(',')
+            .map(|origin| origin.trim().trim_end_matches('/').to_string())
+            .filter(|origin| !origin.is_empty())
+            .collect();
+        Self { id, name, origins }
+    }
+}
+
+pub fn relying_party() -> &'static RelyingParty {
+    static RELYING_PARTY: OnceLock<RelyingParty> = OnceLock::new();
+    RELYING_PARTY.get_or_init(RelyingParty::from_env)
+}
+
+// Why a ceremony response was rejected
+#[derive(Debug, PartialEq)]
+pub enum CeremonyError {
+    Malformed(&'static str),
+    ChallengeMismatch,
+    OriginMismatch,
+    WrongRelyingParty,
+    UserNotVerified,
+    UnsupportedAlgorithm,
+    BadSignature,
+}
+
+impl CeremonyError {
+    pub fn rejection(&self) -> (StatusCode, String) {
+        let message = match self {
+            CeremonyError::Malformed(what) => format!("Malformed credential: {}", what),
+            CeremonyError::ChallengeMismatch => "Unknown or expired challenge".to_string(),
+            CeremonyError::OriginMismatch => "Origin not allowed".to_string(),
+            CeremonyError::WrongRelyingParty => "Credential is for another site".to_string(),
+            CeremonyError::UserNotVerified => "User verification is required".to_string(),
+            CeremonyError::UnsupportedAlgorithm => "Unsupported key algorithm".to_string(),
+            CeremonyError::BadSignature => "Invalid signature".to_string(),
+        };
+        (StatusCode::UNAUTHORIZED, message)
+    }
+}
+
+fn decode_b64(value: &str, what: &'static str) -> Result<Vec<u8>, CeremonyError> {
+    URL_SAFE_NO_PAD
+        .decode(value.trim_end_matches('='))
+        .map_err(|_| CeremonyError::Malformed(what))
+}
+
+// Random challenge the authenticator signs, base64url encoded
+pub fn generate_challenge() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+// WebAuthn user handle: the user id, which must not carry personal information
+pub fn user_handle(user_id: &ObjectId) -> String {
+    URL_SAFE_NO_PAD.encode(user_id.bytes())
+}
+
+// PublicKeyCredentialCreationOptions for navigator.credentials.create()
+pub fn creation_options(
+    rp: &RelyingParty,
+    user: &User,
+    challenge: &str,
+    existing: &[WebauthnCredential],
+) -> serde_json::Value {
+    let exclude: Vec<_> = existing
+        .iter()
+        .map(|credential| json!({ "type": "public-key", "id": credential.credential_id }))
+        .collect();
+    let params: Vec<_> = [ES256, EDDSA, RS256]
+        .iter()
+        .map(|alg| json!({ "type": "public-key", "alg": alg }))
+        .collect();
+    json!({
+        "rp": { "id": rp.id, "name": rp.name },
+        "user": {
+            "id": user_handle(&user.id.unwrap()),
+            "name": user.email,
+            "displayName": user.username,
+        },
+        "challenge": challenge,
+        "pubKeyCredParams": params,
+        "timeout": CEREMONY_TIMEOUT_SECONDS * 1000,
+        "excludeCredentials": exclude,
+        "authenticatorSelection": {
+            "residentKey": "required",
+            "userVerification": "required",
+        },
+        "attestation": "none",
+    })
+}
+
+// PublicKeyCredentialRequestOptions for navigator.credentials.get(). No allowCredentials:
+// the authenticator offers whichever passkeys it holds for the site.
+pub fn request_options(rp: &RelyingParty, challenge: &str) -> serde_json::Value {
+    json!({
+        "challenge": challenge,
+        "rpId": rp.id,
+        "timeout": CEREMONY_TIMEOUT_SECONDS * 1000,
+        "userVerification": "required",
+        "allowCredentials": [],
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+}
+
+// Checks clientDataJSON and returns the challenge it answers
+fn verify_client_data(
+    rp: &RelyingParty,
+    client_data_json: &[u8],
+    expected_type: &str,
+) -> Result<String, CeremonyError> {
+    let client_data: ClientData = serde_json::from_slice(client_data_json)
+        .map_err(|_| CeremonyError::Malformed("clientDataJSON"))?;
+    if client_data.kind != expected_type {
+        return Err(CeremonyError::Malformed("clientDataJSON type"));
+    }
+    if !rp.origins.contains(&client_data.origin) {
+        return Err(CeremonyError::OriginMismatch);
+    }
+    Ok(client_data.challenge)
+}
+
+// The parts of authenticatorData we check
+#[derive(Debug)]
+pub struct AuthenticatorData {
+    pub sign_count: u32,
+    // Present in registration responses
+    pub attested: Option<AttestedCredential>,
+}
+
+#[derive(Debug)]
+pub struct AttestedCredential {
+    pub aaguid: [u8; 16],
+    pub credential_id: Vec<u8>,
+    // COSE_Key bytes
+    pub public_key: Vec<u8>,
+}
+
+pub fn parse_authenticator_data(rp: &RelyingParty, data: &[u8]) -> Result<AuthenticatorData, CeremonyError> {
+    if data.len() < 37 {
+        return Err(CeremonyError::Malformed("authenticatorData"));
+    }
+    if data[..32] != Sha256::digest(rp.id.as_bytes())[..] {
+        return Err(CeremonyError::WrongRelyingParty);
+    }
+    let flags = data[32];
+    if flags & FLAG_USER_PRESENT == 0 || flags & FLAG_USER_VERIFIED == 0 {
+        return Err(CeremonyError::UserNotVerified);
+    }
+    let sign_count = u32::from_be_bytes([data[33], data[34], data[35], data[36]]);
+
+    let attested = if flags & FLAG_ATTESTED_CREDENTIAL != 0 {
+        let rest = &data[37..];
+        if rest.len() < 18 {
+            return Err(CeremonyError::Malformed("attested credential data"));
+        }
+        let mut aaguid = [0u8; 16];
+        aaguid.copy_from_slice(&rest[..16]);
+        let id_len = u16::from_be_bytes([rest[16], rest[17]]) as usize;
+        let rest = &rest[18..];
+        if rest.len() < id_len {
+            return Err(CeremonyError::Malformed("credential id"));
+        }
+        let (credential_id, rest) = rest.split_at(id_len);
+
+        // The COSE key is followed by extensions, if any; decoding it tells where it ends
+        let mut reader = rest;
+        let _: Value = ciborium::from_reader(&mut reader)
+            .map_err(|_| CeremonyError::Malformed("credential public key"))?;
+        let key_len = rest.len() - reader.len();
+        Some(AttestedCredential {
+            aaguid,
+            credential_id: credential_id.to_vec(),
+            public_key: rest[..key_len].to_vec(),
+        })
+    } else {
+        None
+    };
+
+    Ok(AuthenticatorData { sign_count, attested })
+}
+
+fn map_get_text<'a>(map: &'a [(Value, Value)], key: &str) -> Option<&'a Value> {
+    map.iter().find(|(k, _)| k.as_text() == Some(key)).map(|(_, v)| v)
+}
+
+// A credential public key we know how to verify with
+#[derive(Debug, PartialEq)]
+pub enum PublicKey {
+    // Uncompressed P-256 point
+    Es256(Vec<u8>),
+    Ed25519(Vec<u8>),
+    Rs256 { n: Vec<u8>, e: Vec<u8> },
+}
+
+impl PublicKey {
+    // COSE_Key decoding is left to coset; only the algorithms we offer are accepted
+    pub fn from_cose(bytes: &[u8]) -> Result<Self, CeremonyError> {
+        let key = CoseKey::from_slice(bytes).map_err(|_| CeremonyError::Malformed("COSE key"))?;
+        let param = |label: i64| {
+            key.params
+                .iter()
+                .find(|(l, _)| *l == Label::Int(label))
+                .map(|(_, value)| value)
+        };
+        let bytes = |label: i64| {
+            param(label)
+                .and_then(Value::as_bytes)
+                .cloned()
+                .ok_or(CeremonyError::Malformed("COSE key parameter"))
+        };
+        // EC2 and OKP keys name their curve under the same label
+        let curve = param(iana::Ec2KeyParameter::Crv.to_i64())
+            .and_then(Value::as_integer)
+            .and_then(|crv| i64::try_from(crv).ok())
+            .and_then(iana::EllipticCurve::from_i64);
+        let (coset::KeyType::Assigned(kty), Some(coset::Algorithm::Assigned(alg))) =
+            (&key.kty, &key.alg)
+        else {
+            return Err(CeremonyError::UnsupportedAlgorithm);
+        };
+
+        match (kty, alg, curve) {
+            (iana::KeyType::EC2, iana::Algorithm::ES256, Some(iana::EllipticCurve::P_256)) => {
+                let x = bytes(iana::Ec2KeyParameter::X.to_i64())?;
+                let y = bytes(iana::Ec2KeyParameter::Y.to_i64())?;
+                if x.len() != 32 || y.len() != 32 {
+                    return Err(CeremonyError::Malformed("P-256 key"));
+                }
+                Ok(PublicKey::Es256([&[0x04][..], &x, &y].concat()))
+            }
+            (iana::KeyType::OKP, iana::Algorithm::EdDSA, Some(iana::EllipticCurve::Ed25519)) => {
+                Ok(PublicKey::Ed25519(bytes(iana::OkpKeyParameter::X.to_i64())?))
+            }
+            (iana::KeyType::RSA, iana::Algorithm::RS256, _) => Ok(PublicKey::Rs256 {
+                n: bytes(iana::RsaKeyParameter::N.to_i64())?,
+                e: bytes(iana::RsaKeyParameter::E.to_i64())?,
+            }),
+            _ => Err(CeremonyError::UnsupportedAlgorithm),
+        }
+    }
+
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> Result<(), CeremonyError> {
+        let result = match self {
+            PublicKey::Es256(point) => {
+                UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_ASN1, point).verify(message, signature)
+            }
+            PublicKey::Ed25519(key) => {
+                UnparsedPublicKey::new(&signature::ED25519, key).verify(message, signature)
+            }
+            PublicKey::Rs256 { n, e } => RsaPublicKeyComponents { n, e }
+                .verify(&signature::RSA_PKCS1_2048_8192_SHA256, message, signature),
+        };
+        result.map_err(|_| CeremonyError::BadSignature)
+    }
+}
+
+// navigator.credentials.create() result, as serialized by PublicKeyCredential.toJSON()
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RegistrationCredential {
+    pub raw_id: String,
+    pub response: AttestationResponse,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub attestation_object: String,
+    #[serde(default)]
+    pub transports: Vec<String>,
+}
+
+// navigator.credentials.get() result, as serialized by PublicKeyCredential.toJSON()
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticationCredential {
+    pub raw_id: String,
+    pub response: AssertionResponse,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub signature: String,
+    #[serde(default)]
+    pub user_handle: Option<String>,
+}
+
+// A verified registration, ready to store
+#[derive(Debug)]
+pub struct NewCredential {
+    pub challenge: String,
+    pub credential_id: String,
+    pub public_key: String,
+    pub sign_count: u32,
+    pub aaguid: String,
+    pub transports: Vec<String>,
+}
+
+// Verifies a registration response. Attestation statements are not checked: we ask for
+// "none", and which authenticator model holds the passkey doesn't matter to us.
+pub fn verify_registration(
+    rp: &RelyingParty,
+    credential: &RegistrationCredential,
+) -> Result<NewCredential, CeremonyError> {
+    let client_data_json = decode_b64(&credential.response.client_data_json, "clientDataJSON")?;
+    let challenge = verify_client_data(rp, &client_data_json, "webauthn.create")?;
+
+    let attestation = decode_b64(&credential.response.attestation_object, "attestationObject")?;
+    let attestation: Value = ciborium::from_reader(attestation.as_slice())
+        .map_err(|_| CeremonyError::Malformed("attestationObject"))?;
+    let auth_data = attestation
+        .as_map()
+        .and_then(|map| map_get_text(map, "authData"))
+        .and_then(Value::as_bytes)
+        .ok_or(CeremonyError::Malformed("attestationObject"))?;
+
+    let data = parse_authenticator_data(rp, auth_data)?;
+    let attested = data.attested.ok_or(CeremonyError::Malformed("missing attested credential"))?;
+    if attested.credential_id != decode_b64(&credential.raw_id, "rawId")? {
+        return Err(CeremonyError::Malformed("credential id mismatch"));
+    }
+    PublicKey::from_cose(&attested.public_key)?;
+
+    Ok(NewCredential {
+        challenge,
+        credential_id: URL_SAFE_NO_PAD.encode(&attested.credential_id),
+        public_key: URL_SAFE_NO_PAD.encode(&attested.public_key),
+        sign_count: data.sign_count,
+        aaguid: hex::encode(attested.aaguid),
+        transports: credential.response.transports.clone(),
+    })
+}
+
+// A verified assertion
+#[derive(Debug)]
+pub struct Assertion {
+    pub challenge: String,
+    pub sign_count: u32,
+}
+
+// Verifies an authentication response against the stored credential it names
+pub fn verify_assertion(
+    rp: &RelyingParty,
+    stored: &WebauthnCredential,
+    credential: &AuthenticationCredential,
+) -> Result<Assertion, CeremonyError> {
+    let client_data_json = decode_b64(&credential.response.client_data_json, "clientDataJSON")?;
+    let challenge = verify_client_data(rp, &client_data_json, "webauthn.get")?;
+
+    let auth_data = decode_b64(&credential.response.authenticator_data, "authenticatorData")?;
+    let data = parse_authenticator_data(rp, &auth_data)?;
+
+    if let Some(handle) = &credential.response.user_handle {
+        if *handle != user_handle(&stored.user_id) {
+            return Err(CeremonyError::Malformed("user handle mismatch"));
+        }
+    }
+
+    // The signature covers authenticatorData followed by the hash of clientDataJSON
+    let mut message = auth_data.clone();
+    message.extend_from_slice(&Sha256::digest(&client_data_json));
+    let signature = decode_b64(&credential.response.signature, "signature")?;
+    let public_key = PublicKey::from_cose(&decode_b64(&stored.public_key, "public key")?)?;
+    public_key.verify(&message, &signature)?;
+
+    Ok(Assertion { challenge, sign_count: data.sign_count })
+}
+
+// Whether a new signature counter is consistent with the stored one. Authenticators that
+// don't count always report 0; otherwise the counter must grow, or the key was cloned.
+pub fn sign_count_is_valid(stored: u32, received: u32) -> bool {
+    (stored == 0 && received == 0) || received > stored
+}
+
+// A ceremony waiting for the browser's response, keyed by its challenge
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "ceremony", rename_all = "snake_case")]
+pub enum PendingCeremony {
+    Registration { user_id: ObjectId },
+    Authentication,
+}
+
+fn challenge_key(challenge: &str) -> String {
+    format!("webauthn:challenge:{}", challenge)
+}
+
+fn internal_error(e: impl ToString) -> (StatusCode, String) {
+    (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+}
+
+pub async fn store_challenge(
+    redis: &redis::Client,
+    challenge: &str,
+    ceremony: &PendingCeremony,
+) -> Result<(), (StatusCode, String)> {
+    let value = serde_json::to_string(ceremony).map_err(internal_error)?;
+    let mut conn = redis.get_multiplexed_async_connection().await.map_err(internal_error)?;
+    conn.set_ex::<_, _, ()>(challenge_key(challenge), value, CEREMONY_TIMEOUT_SECONDS)
+        .await
+        .map_err(internal_error)
+}
+
+// Takes the ceremony a challenge was issued for; each challenge can be answered once
+pub async fn take_challenge(
+    redis: &redis::Client,
+    challenge: &str,
+) -> Result<Option<PendingCeremony>, (StatusCode, String)> {
+    let mut conn = redis.get_multiplexed_async_connection().await.map_err(internal_error)?;
+    let value: Option<String> = redis::cmd("GETDEL")
+        .arg(challenge_key(challenge))
+        .query_async(&mut conn)
+        .await
+        .map_err(internal_error)?;
+    Ok(value.and_then(|v| serde_json::from_str(&v).ok()))
+}
+
+// Stores the counter from a verified assertion. False if it did not move forward, which
+// means the credential was cloned or the assertion raced another login with it.
+pub async fn accept_sign_count(
+    db: &Database,
+    credential: &WebauthnCredential,
+    sign_count: u32,
+) -> Result<bool, (StatusCode, String)> {
+    if !sign_count_is_valid(credential.sign_count, sign_count) {
+        return Ok(false);
+    }
+    let now = mongodb::bson::to_bson(&chrono::Utc::now()).map_err(internal_error)?;
+    let credentials: Collection<WebauthnCredential> = db.collection("webauthn_credentials");
+    // Conditional on the stored counter, so one assertion can't be accepted twice
+    let filter = if sign_count == 0 {
+        doc! { "_id": credential.id, "sign_count": 0 }
+    } else {
+        doc! { "_id": credential.id, "sign_count": { "$lt": sign_count } }
+    };
+    let result = credentials
+        .update_one(
+            filter,
+            doc! { "$set": { "sign_count": sign_count, "last_used_at": now } },
+            None,
+        )
+        .await
+        .map_err(internal_error)?;
+    Ok(result.matched_count > 0)
+}
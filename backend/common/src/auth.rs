@@ -0,0 +1,156 @@
+use std::{
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bson::oid::ObjectId;
+use jsonwebtoken::{decode, decode_header, jwk::Jwk, Algorithm, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::Error;
+
+// How long an access token is valid; refresh tokens outlive it
+pub const ACCESS_TOKEN_TTL_SECONDS: u64 = 3600;
+
+// Redis key where core-service caches whether an auth session is revoked. auth-service writes
+// SESSION_REVOKED there when it revokes the session, so the revocation applies immediately.
+pub fn session_state_key(session_id: &str) -> String {
+    format!("session:{}", session_id)
+}
+
+pub const SESSION_ACTIVE: &str = "active";
+pub const SESSION_REVOKED: &str = "revoked";
+
+// Only asymmetric algorithms: a service that verifies tokens must never be able to mint them
+pub const ACCEPTED_ALGORITHMS: [Algorithm; 2] = [Algorithm::EdDSA, Algorithm::RS256];
+
+// Claims of an access token issued by auth-service
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Claims {
+    pub sub: String, // user id
+    // Session the token was issued for; revoking it invalidates the token
+    pub sid: String,
+    pub exp: usize,
+}
+
+impl Claims {
+    pub fn new(user_id: &ObjectId, session_id: &ObjectId) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("clock is after the epoch")
+            .as_secs();
+        Self {
+            sub: user_id.to_hex(),
+            sid: session_id.to_hex(),
+            exp: (now + ACCESS_TOKEN_TTL_SECONDS) as usize,
+        }
+    }
+
+    pub fn user_id(&self) -> Result<ObjectId, Error> {
+        ObjectId::parse_str(&self.sub).map_err(|_| Error::InvalidToken)
+    }
+
+    pub fn session_id(&self) -> Result<ObjectId, Error> {
+        ObjectId::parse_str(&self.sid).map_err(|_| Error::InvalidToken)
+    }
+}
+
+// The `kid` of the key a token claims to be signed with
+pub fn token_kid(token: &str) -> Result<String, Error> {
+    decode_header(token)
+        .ok()
+        .and_then(|header| header.kid)
+        .ok_or(Error::InvalidToken)
+}
+
+// Public key that verifies access tokens for one `kid`
+#[derive(Clone)]
+pub struct VerifyingKey {
+    pub algorithm: Algorithm,
+    pub key: DecodingKey,
+}
+
+impl VerifyingKey {
+    // None for keys without an accepted `alg`, which are never trusted
+    pub fn from_jwk(jwk: &Jwk) -> Option<Self> {
+        let algorithm = Algorithm::from_str(&jwk.common.key_algorithm?.to_string()).ok()?;
+        if !ACCEPTED_ALGORITHMS.contains(&algorithm) {
+            return None;
+        }
+        let key = DecodingKey::from_jwk(jwk).ok()?;
+        Some(Self { algorithm, key })
+    }
+
+    // Checks the signature and expiry. The algorithm comes from the key, never from the token header.
+    pub fn verify(&self, token: &str) -> Result<Claims, Error> {
+        decode::<Claims>(token, &self.key, &Validation::new(self.algorithm))
+            .map(|data| data.claims)
+            .map_err(|_| Error::InvalidToken)
+    }
+}
+
+// Bot tokens and personal access tokens are opaque and don't expire with a login. Their
+// prefix tells them apart from access tokens and makes leaked ones easy to search for.
+pub const BOT_TOKEN_PREFIX: &str = "wcb_";
+pub const PERSONAL_TOKEN_PREFIX: &str = "wcp_";
+
+// What a personal access token may do. Access tokens from a login and bot tokens may do
+// everything their user can.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Scope {
+    #[serde(rename = "servers:read")]
+    ServersRead,
+    #[serde(rename = "servers:write")]
+    ServersWrite,
+    #[serde(rename = "channels:read")]
+    ChannelsRead,
+    #[serde(rename = "channels:write")]
+    ChannelsWrite,
+    #[serde(rename = "messages:read")]
+    MessagesRead,
+    #[serde(rename = "messages:write")]
+    MessagesWrite,
+}
+
+// Credentials a request presents in its Authorization header
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Credentials<'a> {
+    // `Bearer <access token>`
+    AccessToken(&'a str),
+    // `Bearer wcp_...`
+    PersonalToken(&'a str),
+    // `Bot wcb_...`
+    BotToken(&'a str),
+}
+
+impl<'a> Credentials<'a> {
+    // None for unknown schemes, and for bot tokens sent as bearer tokens: bots have to
+    // say they are bots
+    pub fn from_authorization(value: &'a str) -> Option<Self> {
+        let (scheme, token) = value.split_once(' ')?;
+        let token = token.trim();
+        if token.is_empty() {
+            return None;
+        }
+        if scheme.eq_ignore_ascii_case("Bot") {
+            return token
+                .starts_with(BOT_TOKEN_PREFIX)
+                .then_some(Credentials::BotToken(token));
+        }
+        if !scheme.eq_ignore_ascii_case("Bearer") || token.starts_with(BOT_TOKEN_PREFIX) {
+            return None;
+        }
+        if token.starts_with(PERSONAL_TOKEN_PREFIX) {
+            Some(Credentials::PersonalToken(token))
+        } else {
+            Some(Credentials::AccessToken(token))
+        }
+    }
+}
+
+// Hash under which random secrets are stored: API and refresh tokens, recovery codes, MFA
+// tickets. They are random, so a fast hash is enough to keep them unusable if the DB leaks.
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
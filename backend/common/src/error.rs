@@ -0,0 +1,65 @@
+use std::fmt;
+
+use http::StatusCode;
+
+// Errors the services share; each maps to one HTTP status
+#[derive(Debug)]
+pub enum Error {
+    // Missing, malformed, expired or wrongly signed access token
+    InvalidToken,
+    // The token's session was revoked
+    SessionRevoked,
+    BadRequest(String),
+    Forbidden,
+    NotFound,
+    Conflict(String),
+    Database(mongodb::error::Error),
+}
+
+impl Error {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::InvalidToken | Error::SessionRevoked => StatusCode::UNAUTHORIZED,
+            Error::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Error::Forbidden => StatusCode::FORBIDDEN,
+            Error::NotFound => StatusCode::NOT_FOUND,
+            Error::Conflict(_) => StatusCode::CONFLICT,
+            Error::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidToken => f.write_str("Invalid token"),
+            Error::SessionRevoked => f.write_str("Session revoked"),
+            Error::BadRequest(message) | Error::Conflict(message) => f.write_str(message),
+            Error::Forbidden => f.write_str("Forbidden"),
+            Error::NotFound => f.write_str("Not found"),
+            Error::Database(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<mongodb::error::Error> for Error {
+    fn from(e: mongodb::error::Error) -> Self {
+        Error::Database(e)
+    }
+}
+
+// core-service handlers reject with a bare status
+impl From<Error> for StatusCode {
+    fn from(e: Error) -> Self {
+        e.status()
+    }
+}
+
+// auth-service handlers reject with a status and a message
+impl From<Error> for (StatusCode, String) {
+    fn from(e: Error) -> Self {
+        (e.status(), e.to_string())
+    }
+}
@@ -1 +1,8 @@
+// Domain types and auth semantics shared by auth-service and core-service
+pub mod auth;
+pub mod error;
 pub mod models;
+pub mod permissions;
+mod tests;
+
+pub use error::Error;
@@ -2,6 +2,8 @@ use serde::{Deserialize, Serialize};
 use bson::oid::ObjectId;
 use chrono::{DateTime, Utc};
 
+use crate::{auth::Scope, Error};
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct User {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
@@ -9,14 +11,24 @@ pub struct User {
     pub username: String,
     pub email: String,
     pub password_hash: String,
-    pub servers: Vec<ObjectId>,
     pub created_at: DateTime<Utc>,
+    // Set when the user's last gateway session disconnects
+    #[serde(default)]
     pub last_seen: Option<DateTime<Utc>>,
+    // Set once the user follows the link in their verification email
+    #[serde(default)]
+    pub email_verified_at: Option<DateTime<Utc>>,
+    // Bots are users that authenticate with a bot token instead of logging in
+    #[serde(default)]
+    pub bot: bool,
+    // The user who created and manages a bot
+    #[serde(default)]
+    pub owner_id: Option<ObjectId>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AuthResponse {
-    pub access_token: String,
+    pub token: String,
     pub refresh_token: String,
     pub user: UserResponse,
 }
@@ -26,6 +38,10 @@ pub struct UserResponse {
     pub id: String,
     pub username: String,
     pub email: String,
+    #[serde(default)]
+    pub email_verified: bool,
+    #[serde(default)]
+    pub bot: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -33,11 +49,13 @@ pub struct Server {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
     pub id: Option<ObjectId>,
     pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
     pub icon_url: Option<String>,
     pub owner_id: ObjectId,
+    #[serde(default)]
     pub roles: Vec<Role>,
-    pub channels: Vec<ObjectId>,
-    pub members: Vec<Member>,
     pub created_at: DateTime<Utc>,
 }
 
@@ -45,16 +63,31 @@ pub struct Server {
 pub struct Role {
     pub role_id: ObjectId,
     pub name: String,
-    pub permissions: u64, // Bitfield
+    pub permissions: u64, // Bitfield of crate::permissions
     pub color: String,
     pub position: i32,
+    // Whether members with the role are listed apart from the others
+    #[serde(default)]
+    pub hoist: bool,
+    // Whether anyone may mention the role
+    #[serde(default)]
+    pub mentionable: bool,
 }
 
+// A user's membership of a server; one per user and server, the owner's included
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Member {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub server_id: ObjectId,
     pub user_id: ObjectId,
+    #[serde(default)]
     pub roles: Vec<ObjectId>,
     pub joined_at: DateTime<Utc>,
+    // Joined through a temporary invite; removed when their last gateway session closes,
+    // unless they have been given a role since
+    #[serde(default)]
+    pub temporary: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -65,18 +98,22 @@ pub struct Channel {
     pub name: String,
     pub channel_type: ChannelType,
     pub topic: Option<String>,
+    #[serde(default)]
     pub position: i32,
+    #[serde(default)]
     pub permissions: Vec<PermissionOverride>,
     pub created_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
 pub enum ChannelType {
     Text,
     Voice,
     Announcement,
 }
 
+// Permissions a channel grants or takes away from one role, on top of its server-wide ones
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct PermissionOverride {
     pub role_id: ObjectId,
@@ -84,4 +121,56 @@ pub struct PermissionOverride {
     pub deny: u64,
 }
 
-pub const PERMISSIONS: u64 = 0xFF; // Simple placeholder for all permissions
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Message {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub channel_id: ObjectId,
+    pub user_id: ObjectId,
+    pub content: String,
+    pub attachments: Vec<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ApiTokenKind {
+    Bot,
+    Personal,
+}
+
+// A long-lived token for API clients, stored by hash: a bot's token, or a personal
+// access token a user created for their own scripts
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ApiToken {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    // The bot, or the user the personal access token acts as
+    pub user_id: ObjectId,
+    pub kind: ApiTokenKind,
+    pub name: String,
+    pub token_hash: String,
+    // What a personal access token may do; bot tokens may do everything
+    #[serde(default)]
+    pub scopes: Vec<Scope>,
+    pub created_at: DateTime<Utc>,
+    #[serde(default)]
+    pub last_used_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl ApiToken {
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.kind == ApiTokenKind::Bot || self.scopes.contains(&scope)
+    }
+
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+// Parses an id from a path or payload
+pub fn parse_id(id: &str) -> Result<ObjectId, Error> {
+    ObjectId::parse_str(id).map_err(|_| Error::BadRequest(format!("Invalid id: {}", id)))
+}
@@ -0,0 +1,166 @@
+use bson::oid::ObjectId;
+
+use crate::models::{Channel, Member, Role, Server};
+
+// Permission bits of `Role::permissions` and of `PermissionOverride::allow` / `deny`
+pub const VIEW_CHANNEL: u64 = 1 << 0;
+pub const SEND_MESSAGES: u64 = 1 << 1;
+pub const READ_MESSAGE_HISTORY: u64 = 1 << 2;
+pub const ATTACH_FILES: u64 = 1 << 3;
+pub const MENTION_EVERYONE: u64 = 1 << 4;
+pub const MANAGE_MESSAGES: u64 = 1 << 5;
+pub const CREATE_INVITE: u64 = 1 << 6;
+pub const MANAGE_CHANNELS: u64 = 1 << 7;
+pub const MANAGE_ROLES: u64 = 1 << 8;
+pub const KICK_MEMBERS: u64 = 1 << 9;
+pub const BAN_MEMBERS: u64 = 1 << 10;
+pub const MANAGE_SERVER: u64 = 1 << 11;
+// Every permission in every channel, whatever the overrides say
+pub const ADMINISTRATOR: u64 = 1 << 12;
+
+pub const ALL: u64 = (1 << 13) - 1;
+
+// What @everyone may do in a new server, and in servers created before roles existed
+pub const DEFAULT_EVERYONE: u64 =
+    VIEW_CHANNEL | SEND_MESSAGES | READ_MESSAGE_HISTORY | ATTACH_FILES | CREATE_INVITE;
+
+pub fn has(permissions: u64, required: u64) -> bool {
+    permissions & required == required
+}
+
+// Every server has an @everyone role that applies to all its members. Its id is the server's.
+pub fn everyone_role(server: &Server) -> Option<&Role> {
+    server.roles.iter().find(|role| Some(role.role_id) == server.id)
+}
+
+pub fn new_everyone_role(server_id: ObjectId) -> Role {
+    Role {
+        role_id: server_id,
+        name: "@everyone".to_string(),
+        permissions: DEFAULT_EVERYONE,
+        color: String::new(),
+        position: 0,
+        hoist: false,
+        mentionable: false,
+    }
+}
+
+// Position of the member's highest role, 0 with none but @everyone. The owner outranks every
+// role.
+pub fn highest_position(server: &Server, member: &Member) -> i32 {
+    if member.user_id == server.owner_id {
+        return i32::MAX;
+    }
+    server
+        .roles
+        .iter()
+        .filter(|role| member.roles.contains(&role.role_id))
+        .map(|role| role.position)
+        .max()
+        .unwrap_or(0)
+        .max(0)
+}
+
+// Members may only edit, assign or delete roles below their highest one, administrators too
+pub fn can_manage_role(server: &Server, member: &Member, role: &Role) -> bool {
+    role.position < highest_position(server, member)
+}
+
+// The roles with each `(role_id, position)` move applied and the others shifted to make room,
+// numbered from 1 upwards in order; @everyone stays at 0. None if a move names @everyone or a
+// role that isn't in `roles`.
+pub fn reorder_roles(roles: &[Role], everyone_id: ObjectId, moves: &[(ObjectId, i32)]) -> Option<Vec<Role>> {
+    if moves
+        .iter()
+        .any(|(id, _)| *id == everyone_id || !roles.iter().any(|role| role.role_id == *id))
+    {
+        return None;
+    }
+
+    let mut ranked: Vec<(i32, i8, Role)> = roles
+        .iter()
+        .filter(|role| role.role_id != everyone_id)
+        .map(|role| match moves.iter().rev().find(|(id, _)| *id == role.role_id) {
+            // A role moved up goes above those already at its new position, one moved down
+            // below them
+            Some(&(_, position)) if position > role.position => (position, 1, role.clone()),
+            Some(&(_, position)) if position < role.position => (position, -1, role.clone()),
+            _ => (role.position, 0, role.clone()),
+        })
+        .collect();
+    ranked.sort_by_key(|(position, tiebreak, _)| (*position, *tiebreak));
+
+    let mut reordered: Vec<Role> = roles.iter().filter(|role| role.role_id == everyone_id).cloned().collect();
+    reordered.extend(ranked.into_iter().zip(1..).map(|((_, _, mut role), position)| {
+        role.position = position;
+        role
+    }));
+    Some(reordered)
+}
+
+// Whether the roles at or above `highest` are still the top ones, in the same order, after
+// `before` became `after`. Moving or creating roles below them shifts their positions, which
+// is fine; moving a role in among them isn't.
+pub fn keeps_outranking_roles(before: &[Role], after: &[Role], highest: i32) -> bool {
+    let top = |roles: &[Role]| -> Vec<ObjectId> {
+        let mut roles: Vec<&Role> = roles.iter().collect();
+        roles.sort_by_key(|role| std::cmp::Reverse(role.position));
+        roles.into_iter().map(|role| role.role_id).collect()
+    };
+    let outranking = before.iter().filter(|role| role.position >= highest).count();
+    let before = top(before);
+    let after = top(after);
+    after.len() >= outranking && before[..outranking] == after[..outranking]
+}
+
+// What a member may do server-wide: @everyone's permissions plus those of each of their roles.
+// The owner and administrators may do everything.
+pub fn server_permissions(server: &Server, member: &Member) -> u64 {
+    if member.user_id == server.owner_id {
+        return ALL;
+    }
+
+    let everyone = everyone_role(server).map_or(DEFAULT_EVERYONE, |role| role.permissions);
+    let permissions = server
+        .roles
+        .iter()
+        .filter(|role| member.roles.contains(&role.role_id))
+        .fold(everyone, |permissions, role| permissions | role.permissions);
+
+    if has(permissions, ADMINISTRATOR) {
+        ALL
+    } else {
+        permissions
+    }
+}
+
+// What a member may do in a channel: their server-wide permissions with the channel's
+// @everyone override applied, then their roles' overrides, where an allow wins over a deny.
+// Without VIEW_CHANNEL they may do nothing in it.
+pub fn channel_permissions(server: &Server, member: &Member, channel: &Channel) -> u64 {
+    let mut permissions = server_permissions(server, member);
+    if has(permissions, ADMINISTRATOR) {
+        return ALL;
+    }
+
+    if let Some(everyone) = channel
+        .permissions
+        .iter()
+        .find(|o| Some(o.role_id) == server.id)
+    {
+        permissions = (permissions & !everyone.deny) | everyone.allow;
+    }
+
+    let (allow, deny) = channel
+        .permissions
+        .iter()
+        .filter(|o| member.roles.contains(&o.role_id))
+        .fold((0, 0), |(allow, deny), o| (allow | o.allow, deny | o.deny));
+    permissions = (permissions & !deny) | allow;
+
+    if has(permissions, VIEW_CHANNEL) {
+        permissions
+    } else {
+        0
+    }
+}
@@ -0,0 +1,288 @@
+#[cfg(test)]
+mod model_tests {
+    use crate::models::*;
+
+    #[test]
+    fn test_channel_type_is_lowercase() {
+        assert_eq!(serde_json::to_string(&ChannelType::Voice).unwrap(), r#""voice""#);
+        let channel_type: ChannelType = serde_json::from_str(r#""text""#).unwrap();
+        assert_eq!(channel_type, ChannelType::Text);
+        assert!(serde_json::from_str::<ChannelType>(r#""video""#).is_err());
+    }
+
+    #[test]
+    fn test_user_defaults_missing_fields() {
+        // Users who never connected have no `last_seen`, and users registered before
+        // email verification existed are unverified
+        let user: User = serde_json::from_str(
+            r#"{"username":"alice","email":"alice@example.com","password_hash":"x","created_at":"2024-01-01T00:00:00Z"}"#,
+        )
+        .unwrap();
+        assert!(user.last_seen.is_none());
+        assert!(user.email_verified_at.is_none());
+        assert!(!user.bot);
+        assert!(user.owner_id.is_none());
+    }
+
+    #[test]
+    fn test_api_token_scopes() {
+        let token: ApiToken = serde_json::from_str(
+            r#"{"user_id":{"$oid":"507f1f77bcf86cd799439011"},"kind":"personal","name":"ci","token_hash":"x","scopes":["messages:read"],"created_at":"2024-01-01T00:00:00Z"}"#,
+        )
+        .unwrap();
+        assert!(token.allows(crate::auth::Scope::MessagesRead));
+        assert!(!token.allows(crate::auth::Scope::MessagesWrite));
+        assert!(!token.is_expired(chrono::Utc::now()));
+
+        // Bot tokens carry every scope
+        let bot = ApiToken { kind: ApiTokenKind::Bot, scopes: Vec::new(), ..token };
+        assert!(bot.allows(crate::auth::Scope::ServersWrite));
+    }
+
+    #[test]
+    fn test_parse_id() {
+        assert!(parse_id("507f1f77bcf86cd799439011").is_ok());
+        assert_eq!(parse_id("nope").unwrap_err().status(), http::StatusCode::BAD_REQUEST);
+    }
+}
+
+#[cfg(test)]
+mod auth_tests {
+    use crate::auth::*;
+    use crate::Error;
+    use bson::oid::ObjectId;
+    use http::StatusCode;
+    use jsonwebtoken::jwk::Jwk;
+
+    #[test]
+    fn test_claims_round_trip_ids() {
+        let user_id = ObjectId::new();
+        let session_id = ObjectId::new();
+        let claims = Claims::new(&user_id, &session_id);
+
+        assert_eq!(claims.user_id().unwrap(), user_id);
+        assert_eq!(claims.session_id().unwrap(), session_id);
+    }
+
+    #[test]
+    fn test_symmetric_keys_are_never_trusted() {
+        let jwk: Jwk = serde_json::from_str(r#"{"alg":"HS256","kid":"k","kty":"oct","k":"c2VjcmV0"}"#).unwrap();
+        assert!(VerifyingKey::from_jwk(&jwk).is_none());
+
+        // Nor keys that don't say which algorithm they are for
+        let jwk: Jwk = serde_json::from_str(
+            r#"{"kid":"k","kty":"OKP","crv":"Ed25519","x":"_wt10oWdbPkWKzfqMdMt7fPSa9UUN5YfTDkEEvLPZvs"}"#,
+        )
+        .unwrap();
+        assert!(VerifyingKey::from_jwk(&jwk).is_none());
+    }
+
+    #[test]
+    fn test_token_without_kid_is_invalid() {
+        assert!(matches!(token_kid("not-a-token"), Err(Error::InvalidToken)));
+    }
+
+    #[test]
+    fn test_authorization_schemes() {
+        assert_eq!(Credentials::from_authorization("Bearer eyJ.a.b"), Some(Credentials::AccessToken("eyJ.a.b")));
+        assert_eq!(Credentials::from_authorization("Bearer wcp_abc"), Some(Credentials::PersonalToken("wcp_abc")));
+        assert_eq!(Credentials::from_authorization("Bot wcb_abc"), Some(Credentials::BotToken("wcb_abc")));
+        // Bot tokens only under their own scheme, and only bot tokens under it
+        assert_eq!(Credentials::from_authorization("Bearer wcb_abc"), None);
+        assert_eq!(Credentials::from_authorization("Bot wcp_abc"), None);
+        assert_eq!(Credentials::from_authorization("Basic dXNlcjpwdw=="), None);
+        assert_eq!(Credentials::from_authorization("Bearer "), None);
+    }
+
+    #[test]
+    fn test_scopes_use_resource_action_names() {
+        assert_eq!(serde_json::to_string(&Scope::MessagesWrite).unwrap(), r#""messages:write""#);
+        assert!(serde_json::from_str::<Scope>(r#""messages:delete""#).is_err());
+        assert_eq!(hash_token("wcp_abc").len(), 64);
+    }
+
+    #[test]
+    fn test_error_statuses() {
+        assert_eq!(StatusCode::from(Error::InvalidToken), StatusCode::UNAUTHORIZED);
+        assert_eq!(StatusCode::from(Error::SessionRevoked), StatusCode::UNAUTHORIZED);
+        let (status, message) = <(StatusCode, String)>::from(Error::Conflict("Email already exists".to_string()));
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(message, "Email already exists");
+    }
+}
+
+#[cfg(test)]
+mod permission_tests {
+    use crate::models::*;
+    use crate::permissions::*;
+    use bson::oid::ObjectId;
+
+    fn role(name: &str, permissions: u64) -> Role {
+        Role {
+            role_id: ObjectId::new(),
+            name: name.to_string(),
+            permissions,
+            color: String::new(),
+            position: 1,
+            hoist: false,
+            mentionable: false,
+        }
+    }
+
+    fn server(roles: Vec<Role>) -> Server {
+        let id = ObjectId::new();
+        let mut all_roles = vec![new_everyone_role(id)];
+        all_roles.extend(roles);
+        Server {
+            id: Some(id),
+            name: "Test".to_string(),
+            description: None,
+            icon_url: None,
+            owner_id: ObjectId::new(),
+            roles: all_roles,
+            created_at: chrono::Utc::now(),
+        }
+    }
+
+    fn member(server: &Server, roles: Vec<ObjectId>) -> Member {
+        Member { id: None, server_id: server.id.unwrap(), user_id: ObjectId::new(), roles, joined_at: chrono::Utc::now(), temporary: false }
+    }
+
+    fn channel(server: &Server, permissions: Vec<PermissionOverride>) -> Channel {
+        Channel {
+            id: Some(ObjectId::new()),
+            server_id: server.id.unwrap(),
+            name: "general".to_string(),
+            channel_type: ChannelType::Text,
+            topic: None,
+            position: 0,
+            permissions,
+            created_at: chrono::Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_roles_add_to_everyone() {
+        let moderators = role("Moderators", MANAGE_CHANNELS | KICK_MEMBERS);
+        let server = server(vec![moderators.clone()]);
+
+        let plain = member(&server, Vec::new());
+        assert_eq!(server_permissions(&server, &plain), DEFAULT_EVERYONE);
+
+        let moderator = member(&server, vec![moderators.role_id]);
+        let permissions = server_permissions(&server, &moderator);
+        assert!(has(permissions, MANAGE_CHANNELS | KICK_MEMBERS | SEND_MESSAGES));
+        assert!(!has(permissions, MANAGE_ROLES));
+    }
+
+    #[test]
+    fn test_owner_and_administrators_have_everything() {
+        let admins = role("Admins", ADMINISTRATOR);
+        let server = server(vec![admins.clone()]);
+        let mut owner = member(&server, Vec::new());
+        owner.user_id = server.owner_id;
+        let admin = member(&server, vec![admins.role_id]);
+
+        // Even in a channel hidden from everyone
+        let everyone_id = server.id.unwrap();
+        let hidden = channel(&server, vec![PermissionOverride { role_id: everyone_id, allow: 0, deny: VIEW_CHANNEL }]);
+        assert_eq!(channel_permissions(&server, &owner, &hidden), ALL);
+        assert_eq!(channel_permissions(&server, &admin, &hidden), ALL);
+    }
+
+    #[test]
+    fn test_channel_overrides() {
+        let muted = role("Muted", 0);
+        let staff = role("Staff", 0);
+        let server = server(vec![muted.clone(), staff.clone()]);
+        let everyone_id = server.id.unwrap();
+
+        // Read-only for everyone, except staff
+        let announcements = channel(
+            &server,
+            vec![
+                PermissionOverride { role_id: everyone_id, allow: 0, deny: SEND_MESSAGES },
+                PermissionOverride { role_id: staff.role_id, allow: SEND_MESSAGES, deny: 0 },
+                PermissionOverride { role_id: muted.role_id, allow: 0, deny: SEND_MESSAGES },
+            ],
+        );
+        let plain = member(&server, Vec::new());
+        assert!(!has(channel_permissions(&server, &plain, &announcements), SEND_MESSAGES));
+        assert!(has(channel_permissions(&server, &plain, &announcements), VIEW_CHANNEL));
+        let staffer = member(&server, vec![staff.role_id]);
+        assert!(has(channel_permissions(&server, &staffer, &announcements), SEND_MESSAGES));
+        // Between roles, the allow wins
+        let muted_staffer = member(&server, vec![staff.role_id, muted.role_id]);
+        assert!(has(channel_permissions(&server, &muted_staffer, &announcements), SEND_MESSAGES));
+    }
+
+    #[test]
+    fn test_hidden_channels_allow_nothing() {
+        let server = server(Vec::new());
+        let everyone_id = server.id.unwrap();
+        let hidden = channel(&server, vec![PermissionOverride { role_id: everyone_id, allow: 0, deny: VIEW_CHANNEL }]);
+        assert_eq!(channel_permissions(&server, &member(&server, Vec::new()), &hidden), 0);
+    }
+
+    #[test]
+    fn test_servers_without_an_everyone_role_use_defaults() {
+        let mut server = server(Vec::new());
+        server.roles.clear();
+        assert_eq!(server_permissions(&server, &member(&server, Vec::new())), DEFAULT_EVERYONE);
+    }
+
+    #[test]
+    fn test_members_manage_roles_below_their_highest() {
+        let mut helpers = role("Helpers", 0);
+        let mut moderators = role("Moderators", MANAGE_ROLES);
+        let mut admins = role("Admins", ADMINISTRATOR);
+        (helpers.position, moderators.position, admins.position) = (1, 2, 3);
+        let server = server(vec![helpers.clone(), moderators.clone(), admins.clone()]);
+
+        let moderator = member(&server, vec![helpers.role_id, moderators.role_id]);
+        assert_eq!(highest_position(&server, &moderator), 2);
+        assert!(can_manage_role(&server, &moderator, &helpers));
+        assert!(!can_manage_role(&server, &moderator, &moderators));
+        assert!(!can_manage_role(&server, &moderator, &admins));
+        assert!(can_manage_role(&server, &moderator, everyone_role(&server).unwrap()));
+
+        // Administrators are still bound by their position, the owner isn't
+        let admin = member(&server, vec![admins.role_id]);
+        assert!(!can_manage_role(&server, &admin, &admins));
+        let mut owner = member(&server, Vec::new());
+        owner.user_id = server.owner_id;
+        assert!(can_manage_role(&server, &owner, &admins));
+
+        let plain = member(&server, Vec::new());
+        assert!(!can_manage_role(&server, &plain, everyone_role(&server).unwrap()));
+    }
+
+    #[test]
+    fn test_reorder_roles() {
+        let (mut a, mut b, mut c) = (role("A", 0), role("B", 0), role("C", 0));
+        (a.position, b.position, c.position) = (1, 2, 3);
+        let server = server(vec![a.clone(), b.clone(), c.clone()]);
+        let everyone_id = server.id.unwrap();
+        let names = |roles: Vec<Role>| -> Vec<(String, i32)> {
+            roles.into_iter().map(|role| (role.name, role.position)).collect()
+        };
+        let expected = |order: &[&str]| -> Vec<(String, i32)> {
+            order.iter().zip(0..).map(|(name, position)| (name.to_string(), position)).collect()
+        };
+
+        let up = reorder_roles(&server.roles, everyone_id, &[(a.role_id, 3)]).unwrap();
+        assert_eq!(names(up), expected(&["@everyone", "B", "C", "A"]));
+        let down = reorder_roles(&server.roles, everyone_id, &[(c.role_id, 1)]).unwrap();
+        assert_eq!(names(down), expected(&["@everyone", "C", "A", "B"]));
+        let swapped = reorder_roles(&server.roles, everyone_id, &[(a.role_id, 2), (b.role_id, 1)]).unwrap();
+        assert_eq!(names(swapped), expected(&["@everyone", "B", "A", "C"]));
+
+        // A member whose highest role is B may move A below B, but not above it
+        assert!(keeps_outranking_roles(&server.roles, &reorder_roles(&server.roles, everyone_id, &[(a.role_id, 1)]).unwrap(), 2));
+        assert!(!keeps_outranking_roles(&server.roles, &reorder_roles(&server.roles, everyone_id, &[(a.role_id, 3)]).unwrap(), 2));
+        assert!(keeps_outranking_roles(&server.roles, &reorder_roles(&server.roles, everyone_id, &[(a.role_id, 3)]).unwrap(), i32::MAX));
+
+        assert!(reorder_roles(&server.roles, everyone_id, &[(everyone_id, 2)]).is_none());
+        assert!(reorder_roles(&server.roles, everyone_id, &[(ObjectId::new(), 2)]).is_none());
+    }
+}
@@ -0,0 +1,100 @@
+use axum::http::StatusCode;
+use common::permissions::{self, has};
+use mongodb::{
+    bson::{doc, oid::ObjectId},
+    Collection, Database,
+};
+
+use crate::models::{Channel, Member, Server};
+
+// A channel and what the user may do in it; None if the user isn't a member of its server
+pub type ChannelAccess = (Channel, Option<u64>);
+
+pub async fn member(
+    db: &Database,
+    server_id: ObjectId,
+    user_id: ObjectId,
+) -> mongodb::error::Result<Option<Member>> {
+    let members: Collection<Member> = db.collection("members");
+    members
+        .find_one(doc! { "server_id": server_id, "user_id": user_id }, None)
+        .await
+}
+
+// None if the channel doesn't exist
+pub async fn in_channel(
+    db: &Database,
+    channel_id: ObjectId,
+    user_id: ObjectId,
+) -> mongodb::error::Result<Option<ChannelAccess>> {
+    let channels: Collection<Channel> = db.collection("channels");
+    let Some(channel) = channels.find_one(doc! { "_id": channel_id }, None).await? else {
+        return Ok(None);
+    };
+    let servers: Collection<Server> = db.collection("servers");
+    let Some(server) = servers.find_one(doc! { "_id": channel.server_id }, None).await? else {
+        return Ok(None);
+    };
+    let permissions = member(db, channel.server_id, user_id)
+        .await?
+        .map(|member| permissions::channel_permissions(&server, &member, &channel));
+    Ok(Some((channel, permissions)))
+}
+
+// Whether a member with these permissions may do what `required` covers
+pub fn allows(permissions: Option<u64>, required: u64) -> bool {
+    permissions.is_some_and(|permissions| has(permissions, required))
+}
+
+// The server and the user's membership of it, if they have every `required` permission
+// there; 404 if it doesn't exist, 403 otherwise
+pub async fn require_member(
+    db: &Database,
+    server_id: ObjectId,
+    user_id: ObjectId,
+    required: u64,
+) -> Result<(Server, Member), StatusCode> {
+    let servers: Collection<Server> = db.collection("servers");
+    let server = servers
+        .find_one(doc! { "_id": server_id }, None)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let member = member(db, server_id, user_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::FORBIDDEN)?;
+    if !has(permissions::server_permissions(&server, &member), required) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok((server, member))
+}
+
+pub async fn require_server(
+    db: &Database,
+    server_id: ObjectId,
+    user_id: ObjectId,
+    required: u64,
+) -> Result<Server, StatusCode> {
+    require_member(db, server_id, user_id, required)
+        .await
+        .map(|(server, _)| server)
+}
+
+// The channel, if the user has every `required` permission in it; 404 if it doesn't
+// exist, 403 otherwise
+pub async fn require_channel(
+    db: &Database,
+    channel_id: ObjectId,
+    user_id: ObjectId,
+    required: u64,
+) -> Result<Channel, StatusCode> {
+    let (channel, permissions) = in_channel(db, channel_id, user_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if !allows(permissions, required) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(channel)
+}
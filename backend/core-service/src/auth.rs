@@ -1,43 +1,129 @@
 use axum::{
     async_trait,
     extract::FromRequestParts,
-    http::{request::Parts, StatusCode},
+    http::{header, request::Parts, StatusCode},
     response::{IntoResponse, Response},
 };
-use axum_extra::TypedHeader;
-use headers::{authorization::Bearer, Authorization};
-use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
-use serde::{Deserialize, Serialize};
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Claims {
-    pub sub: String,
-    pub exp: usize,
+use common::{
+    auth::{token_kid, Claims, Credentials, Scope},
+    models::ApiTokenKind,
+    Error,
+};
+use mongodb::{bson::oid::ObjectId, Database};
+
+use crate::{cache::CacheManager, jwks::JwksCache, sessions, tokens};
+
+// A request made with an access token whose session is still active, a bot token, or a
+// personal access token
+pub struct AuthUser {
+    pub user_id: String,
+    // What the personal access token the request was made with may do; None when the
+    // request may do everything the user can
+    pub scopes: Option<Vec<Scope>>,
 }
 
-pub struct AuthUser(pub String); // user id
+impl AuthUser {
+    // Rejects requests made with a personal access token that lacks `scope`
+    pub fn require(&self, scope: Scope) -> Result<(), StatusCode> {
+        match &self.scopes {
+            Some(scopes) if !scopes.contains(&scope) => Err(StatusCode::FORBIDDEN),
+            _ => Ok(()),
+        }
+    }
+}
 
 #[async_trait]
-impl<S> FromRequestParts<S> for AuthUser
-where
-    S: Send + Sync,
-{
+impl FromRequestParts<(Database, redis::Client)> for AuthUser {
     type Rejection = Response;
 
-    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
-        let TypedHeader(Authorization(bearer)) =
-            TypedHeader::<Authorization<Bearer>>::from_request_parts(parts, _state)
-                .await
-                .map_err(|_| StatusCode::UNAUTHORIZED.into_response())?;
-
-        let secret = std::env::var("JWT_SECRET").expect("JWT_SECRET must be set");
-        let token_data = decode::<Claims>(
-            bearer.token(),
-            &DecodingKey::from_secret(secret.as_ref()),
-            &Validation::new(Algorithm::HS256),
-        )
-        .map_err(|_| StatusCode::UNAUTHORIZED.into_response())?;
-
-        Ok(AuthUser(token_data.claims.sub))
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &(Database, redis::Client),
+    ) -> Result<Self, Self::Rejection> {
+        let credentials = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(Credentials::from_authorization)
+            .ok_or_else(|| StatusCode::UNAUTHORIZED.into_response())?;
+
+        let (token, kind) = match credentials {
+            Credentials::AccessToken(token) => return session_user(parts, state, token).await,
+            Credentials::BotToken(token) => (token, ApiTokenKind::Bot),
+            Credentials::PersonalToken(token) => (token, ApiTokenKind::Personal),
+        };
+        let token = tokens::find_active(&state.0, token, kind)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?
+            .ok_or_else(|| Error::InvalidToken.status().into_response())?;
+
+        Ok(AuthUser {
+            user_id: token.user_id.to_hex(),
+            scopes: (kind == ApiTokenKind::Personal).then_some(token.scopes),
+        })
+    }
+}
+
+async fn session_user(
+    parts: &Parts,
+    state: &(Database, redis::Client),
+    token: &str,
+) -> Result<AuthUser, Response> {
+    let keys = parts
+        .extensions
+        .get::<JwksCache>()
+        .ok_or_else(|| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
+    let claims = verify_token(keys, token)
+        .await
+        .map_err(|e| e.status().into_response())?;
+
+    let mut cache = parts
+        .extensions
+        .get::<CacheManager>()
+        .cloned()
+        .ok_or_else(|| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
+    let revoked = sessions::is_revoked(&state.0, &mut cache, &claims.sub, &claims.sid)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
+    if revoked {
+        return Err(Error::SessionRevoked.status().into_response());
+    }
+
+    Ok(AuthUser {
+        user_id: claims.sub,
+        scopes: None,
+    })
+}
+
+// Validates a JWT against auth-service's published key for its `kid` and returns its claims
+pub async fn verify_token(keys: &JwksCache, token: &str) -> Result<Claims, Error> {
+    let kid = token_kid(token)?;
+    let key = keys.get(&kid).await.ok_or(Error::InvalidToken)?;
+    key.verify(token)
+}
+
+// What authenticated a gateway connection
+#[derive(Debug, Clone)]
+pub enum Credential {
+    // An access token, issued for this auth session
+    Session(String),
+    // A bot token
+    ApiToken(ObjectId),
+}
+
+impl Credential {
+    // Whether the session was logged out, or the token deleted or expired, since
+    pub async fn revoked(
+        &self,
+        db: &Database,
+        cache: &mut CacheManager,
+        user_id: &ObjectId,
+    ) -> mongodb::error::Result<bool> {
+        match self {
+            Credential::Session(session_id) => {
+                sessions::is_revoked(db, cache, &user_id.to_hex(), session_id).await
+            }
+            Credential::ApiToken(token_id) => tokens::is_revoked(db, *token_id).await,
+        }
     }
 }
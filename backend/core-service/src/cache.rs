@@ -1,7 +1,9 @@
+use common::auth::session_state_key;
+use mongodb::bson::oid::ObjectId;
 use redis::{aio::ConnectionManager, AsyncCommands, Client};
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
 
+#[derive(Clone)]
 pub struct CacheManager {
     conn: ConnectionManager,
 }
@@ -13,27 +15,6 @@ impl CacheManager {
         Ok(Self { conn })
     }
 
-    // Cache server data
-    pub async fn cache_server<T: Serialize>(
-        &mut self,
-        server_id: &str,
-        data: &T,
-        ttl_seconds: u64,
-    ) -> redis::RedisResult<()> {
-        let key = format!("server:{}", server_id);
-        let value = serde_json::to_string(data).unwrap();
-        self.conn.set_ex(&key, value, ttl_seconds).await
-    }
-
-    pub async fn get_server<T: for<'de> Deserialize<'de>>(
-        &mut self,
-        server_id: &str,
-    ) -> redis::RedisResult<Option<T>> {
-        let key = format!("server:{}", server_id);
-        let value: Option<String> = self.conn.get(&key).await?;
-        Ok(value.and_then(|v| serde_json::from_str(&v).ok()))
-    }
-
     // Cache channel data
     pub async fn cache_channels<T: Serialize>(
         &mut self,
@@ -55,24 +36,34 @@ impl CacheManager {
         Ok(value.and_then(|v| serde_json::from_str(&v).ok()))
     }
 
-    // Invalidate cache
-    pub async fn invalidate(&mut self, pattern: &str) -> redis::RedisResult<()> {
-        self.conn.del(pattern).await
+    // Drops a server's cached channels after they changed. Entries expire on their own, so a
+    // failure is only logged.
+    pub async fn invalidate_channels(&mut self, server_id: &ObjectId) {
+        let key = format!("channels:{}", server_id.to_hex());
+        if let Err(err) = self.conn.del::<_, ()>(&key).await {
+            eprintln!("⚠️ Failed to invalidate the cached channels of server {}: {}", server_id, err);
+        }
     }
 
-    // Cache user session
-    pub async fn cache_session(
+    // Caches whether an auth session is revoked. Only written if nothing is cached yet, so a
+    // lookup that raced a revocation never overwrites what auth-service recorded.
+    pub async fn cache_session_state(
         &mut self,
-        user_id: &str,
-        session_data: &str,
+        session_id: &str,
+        state: &str,
         ttl_seconds: u64,
     ) -> redis::RedisResult<()> {
-        let key = format!("session:{}", user_id);
-        self.conn.set_ex(&key, session_data, ttl_seconds).await
+        redis::cmd("SET")
+            .arg(session_state_key(session_id))
+            .arg(state)
+            .arg("NX")
+            .arg("EX")
+            .arg(ttl_seconds)
+            .query_async(&mut self.conn)
+            .await
     }
 
-    pub async fn get_session(&mut self, user_id: &str) -> redis::RedisResult<Option<String>> {
-        let key = format!("session:{}", user_id);
-        self.conn.get(&key).await
+    pub async fn get_session_state(&mut self, session_id: &str) -> redis::RedisResult<Option<String>> {
+        self.conn.get(session_state_key(session_id)).await
     }
 }
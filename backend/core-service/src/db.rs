@@ -1,4 +1,9 @@
-use mongodb::{bson::doc, options::IndexOptions, Client, Database, IndexModel};
+use futures_util::TryStreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, Bson, Document},
+    options::{FindOptions, IndexOptions, UpdateOptions},
+    Client, Collection, Database, IndexModel,
+};
 
 pub async fn get_database() -> mongodb::error::Result<Database> {
     let mongo_uri = std::env::var("MONGO_URI").expect("MONGO_URI must be set");
@@ -7,6 +12,8 @@ pub async fn get_database() -> mongodb::error::Result<Database> {
     
     // Create indexes for performance
     create_indexes(&db).await?;
+    migrate_members(&db).await?;
+    migrate_invites(&db).await?;
     
     Ok(db)
 }
@@ -30,13 +37,38 @@ async fn create_indexes(db: &Database) -> mongodb::error::Result<()> {
             .build(),
         None
     ).await?;
-    servers.create_index(
+    
+    // Members collection indexes: one membership per user and server, listed both ways
+    let members = db.collection::<mongodb::bson::Document>("members");
+    members.create_index(
+        IndexModel::builder()
+            .keys(doc! { "server_id": 1, "user_id": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build(),
+        None
+    ).await?;
+    members.create_index(
+        IndexModel::builder()
+            .keys(doc! { "user_id": 1 })
+            .build(),
+        None
+    ).await?;
+    
+    // Invites collection indexes
+    let invites = db.collection::<mongodb::bson::Document>("invites");
+    invites.create_index(
         IndexModel::builder()
-            .keys(doc! { "invite_code": 1 })
+            .keys(doc! { "code": 1 })
             .options(IndexOptions::builder().unique(true).build())
             .build(),
         None
     ).await?;
+    invites.create_index(
+        IndexModel::builder()
+            .keys(doc! { "server_id": 1 })
+            .build(),
+        None
+    ).await?;
     
     // Channels collection indexes
     let channels = db.collection::<mongodb::bson::Document>("channels");
@@ -62,7 +94,136 @@ async fn create_indexes(db: &Database) -> mongodb::error::Result<()> {
         None
     ).await?;
     
+    // Read states collection indexes
+    let read_states = db.collection::<mongodb::bson::Document>("read_states");
+    read_states.create_index(
+        IndexModel::builder()
+            .keys(doc! { "user_id": 1, "channel_id": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build(),
+        None
+    ).await?;
+    
     println!("✅ Database indexes created successfully");
     
     Ok(())
 }
+
+// Before the members collection, owners were members implicitly and other users listed the
+// servers they joined on their user document. Copies both into it, once.
+async fn migrate_members(db: &Database) -> mongodb::error::Result<()> {
+    let migrations: Collection<Document> = db.collection("migrations");
+    if migrations.find_one(doc! { "_id": "members" }, None).await?.is_some() {
+        return Ok(());
+    }
+
+    let mut memberships: Vec<(ObjectId, ObjectId)> = Vec::new();
+    let servers: Collection<Document> = db.collection("servers");
+    let options = FindOptions::builder().projection(doc! { "owner_id": 1 }).build();
+    let mut cursor = servers.find(doc! {}, options).await?;
+    while let Some(server) = cursor.try_next().await? {
+        if let (Ok(server_id), Ok(owner_id)) = (server.get_object_id("_id"), server.get_object_id("owner_id")) {
+            memberships.push((server_id, owner_id));
+        }
+    }
+
+    let users: Collection<Document> = db.collection("users");
+    let options = FindOptions::builder().projection(doc! { "servers": 1 }).build();
+    let mut cursor = users.find(doc! { "servers": { "$exists": true } }, options).await?;
+    while let Some(user) = cursor.try_next().await? {
+        let (Ok(user_id), Ok(joined)) = (user.get_object_id("_id"), user.get_array("servers")) else {
+            continue;
+        };
+        memberships.extend(joined.iter().filter_map(Bson::as_object_id).map(|server_id| (server_id, user_id)));
+    }
+
+    let members: Collection<Document> = db.collection("members");
+    let now = mongodb::bson::to_bson(&chrono::Utc::now())?;
+    for (server_id, user_id) in &memberships {
+        members
+            .update_one(
+                doc! { "server_id": server_id, "user_id": user_id },
+                doc! { "$setOnInsert": { "roles": [], "joined_at": &now } },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await?;
+    }
+    users
+        .update_many(doc! { "servers": { "$exists": true } }, doc! { "$unset": { "servers": "" } }, None)
+        .await?;
+
+    // Upserted, as another instance may be finishing the same migration
+    migrations
+        .update_one(
+            doc! { "_id": "members" },
+            doc! { "$set": { "completed_at": now } },
+            UpdateOptions::builder().upsert(true).build(),
+        )
+        .await?;
+    println!("✅ Migrated {} server memberships", memberships.len());
+
+    Ok(())
+}
+
+// Before the invites collection, each server had a single permanent `invite_code`. Turns every
+// one into an invite from the server's owner that never expires, once.
+async fn migrate_invites(db: &Database) -> mongodb::error::Result<()> {
+    let migrations: Collection<Document> = db.collection("migrations");
+    if migrations.find_one(doc! { "_id": "invites" }, None).await?.is_some() {
+        return Ok(());
+    }
+
+    // Servers no longer have a code, so the unique index on it would only hold nulls. It may
+    // already be gone.
+    let servers: Collection<Document> = db.collection("servers");
+    let _ = servers.drop_index("invite_code_1", None).await;
+
+    let invites: Collection<Document> = db.collection("invites");
+    let now = mongodb::bson::to_bson(&chrono::Utc::now())?;
+    let options = FindOptions::builder()
+        .projection(doc! { "owner_id": 1, "invite_code": 1, "created_at": 1 })
+        .build();
+    let mut cursor = servers.find(doc! { "invite_code": { "$exists": true } }, options).await?;
+    let mut migrated = 0;
+    while let Some(server) = cursor.try_next().await? {
+        let (Ok(server_id), Ok(owner_id), Ok(code)) = (
+            server.get_object_id("_id"),
+            server.get_object_id("owner_id"),
+            server.get_str("invite_code"),
+        ) else {
+            continue;
+        };
+        invites
+            .update_one(
+                doc! { "code": code },
+                doc! { "$setOnInsert": {
+                    "server_id": server_id,
+                    "channel_id": Bson::Null,
+                    "creator_id": owner_id,
+                    "max_age": 0,
+                    "max_uses": 0,
+                    "uses": 0,
+                    "temporary": false,
+                    "created_at": server.get("created_at").cloned().unwrap_or_else(|| now.clone()),
+                    "expires_at": Bson::Null,
+                } },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await?;
+        migrated += 1;
+    }
+    servers
+        .update_many(doc! { "invite_code": { "$exists": true } }, doc! { "$unset": { "invite_code": "" } }, None)
+        .await?;
+
+    migrations
+        .update_one(
+            doc! { "_id": "invites" },
+            doc! { "$set": { "completed_at": now } },
+            UpdateOptions::builder().upsert(true).build(),
+        )
+        .await?;
+    println!("✅ Migrated {} server invite codes", migrated);
+
+    Ok(())
+}
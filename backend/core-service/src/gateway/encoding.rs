@@ -0,0 +1,73 @@
+use axum::extract::ws::Message;
+use flate2::{Compress, Compression as Level, FlushCompress};
+use serde::{Deserialize, Serialize};
+
+// Payload format negotiated with `?encoding=` at connect time
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Json,
+    Msgpack,
+}
+
+// Transport compression negotiated with `?compress=` at connect time
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    // One zlib context for the whole connection; every frame ends with a sync flush
+    #[serde(rename = "zlib-stream")]
+    ZlibStream,
+}
+
+// Encodes outgoing gateway frames for one connection
+pub struct FrameEncoder {
+    encoding: Encoding,
+    zlib: Option<Compress>,
+}
+
+impl FrameEncoder {
+    pub fn new(encoding: Encoding, compression: Option<Compression>) -> Self {
+        let zlib = compression.map(|Compression::ZlibStream| Compress::new(Level::default(), true));
+        Self { encoding, zlib }
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    // Serializes a payload into a websocket message: JSON is sent as text unless
+    // compressed, MessagePack and compressed payloads are sent as binary
+    pub fn encode<T: Serialize>(&mut self, payload: &T) -> Message {
+        let bytes = match self.encoding {
+            Encoding::Json => serde_json::to_vec(payload).expect("gateway payloads are serializable"),
+            Encoding::Msgpack => {
+                rmp_serde::to_vec_named(payload).expect("gateway payloads are serializable")
+            }
+        };
+
+        match &mut self.zlib {
+            Some(zlib) => Message::Binary(deflate_sync(zlib, &bytes)),
+            None if self.encoding == Encoding::Json => {
+                Message::Text(String::from_utf8(bytes).expect("JSON is valid UTF-8"))
+            }
+            None => Message::Binary(bytes),
+        }
+    }
+}
+
+// Compresses `input` with the connection's zlib context and sync-flushes it, so
+// the client can inflate the frame without waiting for the next one
+fn deflate_sync(zlib: &mut Compress, input: &[u8]) -> Vec<u8> {
+    let start = zlib.total_in();
+    let mut out = Vec::with_capacity(input.len() / 2 + 64);
+    loop {
+        let consumed = (zlib.total_in() - start) as usize;
+        zlib.compress_vec(&input[consumed..], &mut out, FlushCompress::Sync)
+            .expect("zlib stream is never finished");
+        // The flush is complete once all input is consumed without filling the buffer
+        if (zlib.total_in() - start) as usize == input.len() && out.len() < out.capacity() {
+            return out;
+        }
+        out.reserve(out.capacity().max(64));
+    }
+}
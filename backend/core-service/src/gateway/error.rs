@@ -0,0 +1,102 @@
+use std::fmt;
+
+// Application close codes the gateway ends connections with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseCode {
+    InternalError = 4000,
+    InvalidPayload = 4002,
+    NotAuthenticated = 4003,
+    AuthenticationFailed = 4004,
+    AlreadyAuthenticated = 4005,
+    RateLimited = 4008,
+    SessionTimedOut = 4009,
+    ServerRestarting = 4010,
+    SessionInvalidated = 4011,
+}
+
+impl CloseCode {
+    pub fn code(self) -> u16 {
+        self as u16
+    }
+
+    pub fn reason(self) -> &'static str {
+        match self {
+            CloseCode::InternalError => "internal error",
+            CloseCode::InvalidPayload => "invalid payload",
+            CloseCode::NotAuthenticated => "not authenticated",
+            CloseCode::AuthenticationFailed => "authentication failed",
+            CloseCode::AlreadyAuthenticated => "already authenticated",
+            CloseCode::RateLimited => "rate limited",
+            CloseCode::SessionTimedOut => "session timed out",
+            CloseCode::ServerRestarting => "server restarting",
+            CloseCode::SessionInvalidated => "session invalidated",
+        }
+    }
+
+    // Whether the client may reconnect and RESUME its session after this close
+    pub fn can_resume(self) -> bool {
+        matches!(
+            self,
+            CloseCode::InternalError
+                | CloseCode::RateLimited
+                | CloseCode::SessionTimedOut
+                | CloseCode::ServerRestarting
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum GatewayError {
+    // The socket is gone; nothing more can be sent to the client
+    Socket(axum::Error),
+    Redis(redis::RedisError),
+    Mongo(mongodb::error::Error),
+    // The gateway decided to end the connection
+    Close(CloseCode),
+}
+
+impl GatewayError {
+    // Close code to send to the client, if the socket is still writable
+    pub fn close_code(&self) -> Option<CloseCode> {
+        match self {
+            GatewayError::Socket(_) => None,
+            GatewayError::Redis(_) | GatewayError::Mongo(_) => Some(CloseCode::InternalError),
+            GatewayError::Close(code) => Some(*code),
+        }
+    }
+}
+
+impl fmt::Display for GatewayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GatewayError::Socket(e) => write!(f, "socket error: {}", e),
+            GatewayError::Redis(e) => write!(f, "redis error: {}", e),
+            GatewayError::Mongo(e) => write!(f, "mongo error: {}", e),
+            GatewayError::Close(code) => write!(f, "closed: {}", code.reason()),
+        }
+    }
+}
+
+impl From<axum::Error> for GatewayError {
+    fn from(e: axum::Error) -> Self {
+        GatewayError::Socket(e)
+    }
+}
+
+impl From<redis::RedisError> for GatewayError {
+    fn from(e: redis::RedisError) -> Self {
+        GatewayError::Redis(e)
+    }
+}
+
+impl From<mongodb::error::Error> for GatewayError {
+    fn from(e: mongodb::error::Error) -> Self {
+        GatewayError::Mongo(e)
+    }
+}
+
+impl From<CloseCode> for GatewayError {
+    fn from(code: CloseCode) -> Self {
+        GatewayError::Close(code)
+    }
+}
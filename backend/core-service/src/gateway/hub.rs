@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use redis::{aio::ConnectionManager, ErrorKind, RedisError, RedisResult};
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+use super::protocol::{ServerEvent, TopicMessage};
+
+// Events buffered per topic before slow sessions start lagging
+const TOPIC_CAPACITY: usize = 256;
+// Delay between attempts to reopen the pub/sub connection
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+// What a session's inbox receives from its subscriptions
+#[derive(Debug)]
+pub enum Delivery {
+    Event {
+        topic: String,
+        message: Arc<TopicMessage>,
+    },
+    // The session fell behind and missed events; it must catch up from the topic log
+    Lagged { topic: String },
+    // The hub lost its pub/sub connection and dropped every subscription
+    Closed,
+}
+
+enum Command {
+    Subscribe {
+        topic: String,
+        reply: oneshot::Sender<RedisResult<(u64, broadcast::Receiver<Arc<TopicMessage>>)>>,
+    },
+    Release {
+        topic: String,
+        generation: u64,
+    },
+}
+
+// A topic the hub is subscribed to in Redis, shared by every local session that wants it
+struct Topic {
+    sender: broadcast::Sender<Arc<TopicMessage>>,
+    // Bumped each time the topic is subscribed, so releases from a dropped
+    // pub/sub connection don't count against the current one
+    generation: u64,
+    refs: usize,
+}
+
+// Process-wide gateway hub: one pub/sub connection per instance fanning topic
+// messages out to local sessions, and one shared connection for everything else
+#[derive(Clone)]
+pub struct Hub {
+    conn: ConnectionManager,
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+impl Hub {
+    pub async fn connect(client: redis::Client) -> RedisResult<Self> {
+        let conn = ConnectionManager::new(client.clone()).await?;
+        let (commands, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(run(client, receiver));
+        Ok(Self { conn, commands })
+    }
+
+    // Shared connection for publishing and for session and replay state
+    pub fn connection(&self) -> ConnectionManager {
+        self.conn.clone()
+    }
+
+    pub async fn publish(&self, topic: &str, event: ServerEvent) -> RedisResult<()> {
+        super::publish(&mut self.connection(), topic, event).await
+    }
+
+    // Forwards messages published on `topic` to `inbox` until the subscription is dropped.
+    // Returns once the hub is subscribed in Redis, so nothing published afterwards is missed.
+    pub async fn subscribe(
+        &self,
+        topic: String,
+        inbox: mpsc::Sender<Delivery>,
+    ) -> RedisResult<Subscription> {
+        let (reply, response) = oneshot::channel();
+        self.commands
+            .send(Command::Subscribe { topic: topic.clone(), reply })
+            .map_err(|_| hub_stopped())?;
+        let (generation, mut receiver) = response.await.map_err(|_| hub_stopped())??;
+
+        let forwarded = topic.clone();
+        let forwarder = tokio::spawn(async move {
+            loop {
+                let delivery = match receiver.recv().await {
+                    Ok(message) => Delivery::Event { topic: forwarded.clone(), message },
+                    Err(broadcast::error::RecvError::Lagged(_)) => {
+                        Delivery::Lagged { topic: forwarded.clone() }
+                    }
+                    Err(broadcast::error::RecvError::Closed) => {
+                        let _ = inbox.send(Delivery::Closed).await;
+                        return;
+                    }
+                };
+                if inbox.send(delivery).await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        Ok(Subscription {
+            topic,
+            generation,
+            forwarder,
+            commands: self.commands.clone(),
+        })
+    }
+}
+
+fn hub_stopped() -> RedisError {
+    RedisError::from((ErrorKind::IoError, "gateway hub stopped"))
+}
+
+// A session's interest in a topic; dropping it releases the topic
+pub struct Subscription {
+    topic: String,
+    generation: u64,
+    forwarder: JoinHandle<()>,
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.forwarder.abort();
+        let _ = self.commands.send(Command::Release {
+            topic: std::mem::take(&mut self.topic),
+            generation: self.generation,
+        });
+    }
+}
+
+// Owns the pub/sub connection: applies subscribe/release commands and fans
+// incoming messages out to each topic's broadcast channel
+async fn run(client: redis::Client, mut commands: mpsc::UnboundedReceiver<Command>) {
+    let mut generation = 0;
+    loop {
+        let pubsub = match client.get_async_pubsub().await {
+            Ok(pubsub) => pubsub,
+            Err(err) => {
+                eprintln!("⚠️ Gateway hub failed to open pub/sub connection: {}", err);
+                tokio::time::sleep(RECONNECT_DELAY).await;
+                continue;
+            }
+        };
+        let (mut sink, mut stream) = pubsub.split();
+        let mut topics: HashMap<String, Topic> = HashMap::new();
+
+        loop {
+            tokio::select! {
+                command = commands.recv() => match command {
+                    Some(Command::Subscribe { topic, reply }) => {
+                        if let Some(entry) = topics.get_mut(&topic) {
+                            entry.refs += 1;
+                            let _ = reply.send(Ok((entry.generation, entry.sender.subscribe())));
+                            continue;
+                        }
+                        if let Err(err) = sink.subscribe(&topic).await {
+                            let _ = reply.send(Err(err));
+                            continue;
+                        }
+                        generation += 1;
+                        let (sender, receiver) = broadcast::channel(TOPIC_CAPACITY);
+                        topics.insert(topic, Topic { sender, generation, refs: 1 });
+                        let _ = reply.send(Ok((generation, receiver)));
+                    }
+                    Some(Command::Release { topic, generation }) => {
+                        let Some(entry) = topics.get_mut(&topic) else { continue };
+                        if entry.generation != generation {
+                            continue;
+                        }
+                        entry.refs -= 1;
+                        if entry.refs == 0 {
+                            topics.remove(&topic);
+                            if let Err(err) = sink.unsubscribe(&topic).await {
+                                eprintln!("⚠️ Gateway hub failed to unsubscribe from {}: {}", topic, err);
+                            }
+                        }
+                    }
+                    // Every Hub handle is gone
+                    None => return,
+                },
+
+                msg = stream.next() => {
+                    let Some(msg) = msg else { break };
+                    let topic = msg.get_channel_name();
+                    let Some(entry) = topics.get(topic) else { continue };
+                    let Ok(payload) = msg.get_payload::<String>() else { continue };
+                    let Ok(message) = serde_json::from_str::<TopicMessage>(&payload) else { continue };
+                    // No receivers left is fine; the release is already queued
+                    let _ = entry.sender.send(Arc::new(message));
+                }
+            }
+        }
+
+        // Dropping the topics closes every broadcast channel, which tells the
+        // sessions to reconnect and resume against a fresh subscription
+        eprintln!("⚠️ Gateway hub lost its pub/sub connection, reconnecting");
+    }
+}
@@ -0,0 +1,55 @@
+pub mod encoding;
+pub mod error;
+pub mod hub;
+pub mod presence;
+pub mod protocol;
+pub mod replay;
+pub mod topics;
+pub mod typing;
+
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use redis::{aio::ConnectionLike, AsyncCommands, RedisResult};
+use tokio::sync::watch;
+
+use protocol::{ServerEvent, TopicMessage};
+
+// How often clients must send a heartbeat, announced in HELLO
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+// Sockets that go this long without a heartbeat are treated as dead and torn down
+pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(45);
+// How long an unauthenticated socket has to send IDENTIFY or RESUME
+pub const IDENTIFY_TIMEOUT: Duration = Duration::from_secs(10);
+// Client ops (other than heartbeats) a socket may send per window before it is closed
+pub const RATE_LIMIT_OPS: u32 = 120;
+pub const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+fn shutdown_sender() -> &'static watch::Sender<bool> {
+    static SHUTDOWN: OnceLock<watch::Sender<bool>> = OnceLock::new();
+    SHUTDOWN.get_or_init(|| watch::channel(false).0)
+}
+
+// Resolves once the process starts shutting down
+pub async fn shutting_down() {
+    let mut rx = shutdown_sender().subscribe();
+    let _ = rx.wait_for(|shutting_down| *shutting_down).await;
+}
+
+// Tells every open socket to close with a reconnect hint
+pub fn begin_shutdown() {
+    shutdown_sender().send_replace(true);
+}
+
+// Records an event in its topic's log and publishes it to the topic's subscribers
+pub async fn publish<C: ConnectionLike + Send>(
+    conn: &mut C,
+    topic: &str,
+    event: ServerEvent,
+) -> RedisResult<()> {
+    let payload = event.to_json().expect("gateway events are serializable");
+    let id = replay::append_topic_log(conn, topic, &payload).await?;
+    let message = TopicMessage { id, event };
+    let message_json = serde_json::to_string(&message).expect("gateway events are serializable");
+    conn.publish(topic, message_json).await
+}
@@ -0,0 +1,137 @@
+use std::time::Duration;
+
+use mongodb::bson::oid::ObjectId;
+use redis::{aio::ConnectionLike, AsyncCommands, RedisResult};
+use serde::{Deserialize, Serialize};
+
+use super::protocol::{PresenceStatus, PresenceUpdate};
+
+// How long a session counts as connected without a heartbeat; longer than the
+// heartbeat timeout so only sessions on a crashed instance ever expire this way
+pub const PRESENCE_TTL: Duration = Duration::from_secs(60);
+
+fn presence_key(user_id: &ObjectId) -> String {
+    format!("presence:{}", user_id.to_hex())
+}
+
+fn sessions_key(user_id: &ObjectId) -> String {
+    format!("presence:{}:sessions", user_id.to_hex())
+}
+
+fn now_millis() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+// Status a user has chosen, shared by all of their sessions
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct Presence {
+    pub status: PresenceStatus,
+    pub custom_status: Option<String>,
+}
+
+impl Presence {
+    // The update other users see: invisible users appear offline, without custom status
+    pub fn visible_to_others(&self, user_id: &ObjectId) -> PresenceUpdate {
+        let (status, custom_status) = match self.status {
+            PresenceStatus::Invisible | PresenceStatus::Offline => (PresenceStatus::Offline, None),
+            status => (status, self.custom_status.clone()),
+        };
+        PresenceUpdate {
+            user_id: user_id.to_hex(),
+            status,
+            custom_status,
+        }
+    }
+
+    pub fn offline(user_id: &ObjectId) -> PresenceUpdate {
+        PresenceUpdate {
+            user_id: user_id.to_hex(),
+            status: PresenceStatus::Offline,
+            custom_status: None,
+        }
+    }
+
+    pub async fn load<C: ConnectionLike + Send>(
+        conn: &mut C,
+        user_id: &ObjectId,
+    ) -> RedisResult<Option<Self>> {
+        let value: Option<String> = conn.get(presence_key(user_id)).await?;
+        Ok(value.and_then(|v| serde_json::from_str(&v).ok()))
+    }
+
+    pub async fn save<C: ConnectionLike + Send>(
+        &self,
+        conn: &mut C,
+        user_id: &ObjectId,
+    ) -> RedisResult<()> {
+        let value = serde_json::to_string(self).expect("presence is serializable");
+        conn.pset_ex(presence_key(user_id), value, PRESENCE_TTL.as_millis() as u64)
+            .await
+    }
+}
+
+// Number of the user's sessions that are still connected, dropping expired ones
+pub async fn live_sessions<C: ConnectionLike + Send>(
+    conn: &mut C,
+    user_id: &ObjectId,
+) -> RedisResult<u64> {
+    let (count,): (u64,) = redis::pipe()
+        .zrembyscore(sessions_key(user_id), "-inf", now_millis())
+        .ignore()
+        .zcard(sessions_key(user_id))
+        .query_async(conn)
+        .await?;
+    Ok(count)
+}
+
+// Marks a session as connected for another PRESENCE_TTL, keeping the user's presence alive
+pub async fn refresh<C: ConnectionLike + Send>(
+    conn: &mut C,
+    user_id: &ObjectId,
+    session_id: &str,
+) -> RedisResult<()> {
+    let ttl = PRESENCE_TTL.as_millis() as i64;
+    redis::pipe()
+        .zadd(sessions_key(user_id), session_id, now_millis() + ttl)
+        .ignore()
+        .pexpire(sessions_key(user_id), ttl)
+        .ignore()
+        .pexpire(presence_key(user_id), ttl)
+        .ignore()
+        .query_async(conn)
+        .await
+}
+
+// Removes a session and returns true if it was the user's last one, clearing their presence
+pub async fn disconnect<C: ConnectionLike + Send>(
+    conn: &mut C,
+    user_id: &ObjectId,
+    session_id: &str,
+) -> RedisResult<bool> {
+    let _: () = conn.zrem(sessions_key(user_id), session_id).await?;
+    if live_sessions(conn, user_id).await? > 0 {
+        return Ok(false);
+    }
+    let _: () = conn.del(presence_key(user_id)).await?;
+    Ok(true)
+}
+
+// Presences of the given users who are currently connected
+pub async fn load_many<C: ConnectionLike + Send>(
+    conn: &mut C,
+    user_ids: &[ObjectId],
+) -> RedisResult<Vec<(ObjectId, Presence)>> {
+    if user_ids.is_empty() {
+        return Ok(Vec::new());
+    }
+    let keys: Vec<String> = user_ids.iter().map(presence_key).collect();
+    let values: Vec<Option<String>> = redis::cmd("MGET").arg(keys).query_async(conn).await?;
+    Ok(user_ids
+        .iter()
+        .zip(values)
+        .filter_map(|(user_id, value)| {
+            let presence = serde_json::from_str(&value?).ok()?;
+            Some((*user_id, presence))
+        })
+        .collect())
+}
@@ -0,0 +1,300 @@
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+
+use crate::models::{Channel, Member, Message, ReadState, Role, Server};
+
+pub const MAX_MESSAGE_LENGTH: usize = 2000;
+pub const MAX_CUSTOM_STATUS_LENGTH: usize = 128;
+
+// Ops sent by the client over the gateway
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientOp {
+    JoinChannel { channel_id: String },
+    LeaveChannel { channel_id: String },
+    SendMessage { channel_id: String, content: String },
+    Typing { channel_id: String },
+    Identify {
+        token: String,
+        #[serde(default)]
+        properties: ClientProperties,
+        #[serde(default)]
+        capabilities: Vec<String>,
+    },
+    Resume {
+        // Required when the socket was opened without a token
+        #[serde(default)]
+        token: Option<String>,
+        session_id: String,
+        seq: u64,
+    },
+    // Marks messages in a channel as read up to and including `message_id`
+    Ack { channel_id: String, message_id: String },
+    Heartbeat {
+        // Last sequence number the client received
+        #[serde(default)]
+        seq: Option<u64>,
+    },
+    // Sets the user's status across all of their sessions
+    PresenceUpdate {
+        status: PresenceStatus,
+        #[serde(default)]
+        custom_status: Option<String>,
+    },
+}
+
+impl ClientOp {
+    pub fn parse(text: &str) -> Result<Self, ErrorPayload> {
+        serde_json::from_str(text)
+            .map_err(|e| ErrorPayload::new(ErrorCode::InvalidPayload, e.to_string()))
+    }
+
+    // Parses a binary frame from a client that negotiated MessagePack
+    pub fn parse_msgpack(bytes: &[u8]) -> Result<Self, ErrorPayload> {
+        rmp_serde::from_slice(bytes)
+            .map_err(|e| ErrorPayload::new(ErrorCode::InvalidPayload, e.to_string()))
+    }
+
+    pub fn channel_id(&self) -> Option<&str> {
+        match self {
+            ClientOp::JoinChannel { channel_id }
+            | ClientOp::LeaveChannel { channel_id }
+            | ClientOp::SendMessage { channel_id, .. }
+            | ClientOp::Typing { channel_id }
+            | ClientOp::Ack { channel_id, .. } => Some(channel_id),
+            ClientOp::Identify { .. }
+            | ClientOp::Resume { .. }
+            | ClientOp::Heartbeat { .. }
+            | ClientOp::PresenceUpdate { .. } => None,
+        }
+    }
+
+    // Checks the op's fields and returns the channel it targets, if any
+    pub fn validate(&self) -> Result<Option<ObjectId>, ErrorPayload> {
+        if let ClientOp::PresenceUpdate { status, custom_status } = self {
+            if *status == PresenceStatus::Offline {
+                return Err(ErrorPayload::new(
+                    ErrorCode::InvalidPayload,
+                    "status must be online, idle, dnd or invisible",
+                ));
+            }
+            if custom_status
+                .as_ref()
+                .is_some_and(|text| text.chars().count() > MAX_CUSTOM_STATUS_LENGTH)
+            {
+                return Err(ErrorPayload::new(
+                    ErrorCode::InvalidPayload,
+                    format!("custom_status must be at most {} characters", MAX_CUSTOM_STATUS_LENGTH),
+                ));
+            }
+        }
+
+        let Some(channel_id) = self.channel_id() else {
+            return Ok(None);
+        };
+        let channel_oid = ObjectId::parse_str(channel_id).map_err(|_| {
+            ErrorPayload::new(ErrorCode::InvalidChannelId, "channel_id is not a valid id")
+        })?;
+
+        if let ClientOp::Ack { message_id, .. } = self {
+            ObjectId::parse_str(message_id).map_err(|_| {
+                ErrorPayload::new(ErrorCode::InvalidPayload, "message_id is not a valid id")
+            })?;
+        }
+
+        if let ClientOp::SendMessage { content, .. } = self {
+            validate_content(content)?;
+        }
+
+        Ok(Some(channel_oid))
+    }
+}
+
+// Checks a message's content, whether it was sent over the gateway or the REST API
+pub fn validate_content(content: &str) -> Result<(), ErrorPayload> {
+    if content.trim().is_empty() {
+        return Err(ErrorPayload::new(
+            ErrorCode::InvalidContent,
+            "content must not be empty",
+        ));
+    }
+    if content.chars().count() > MAX_MESSAGE_LENGTH {
+        return Err(ErrorPayload::new(
+            ErrorCode::InvalidContent,
+            format!("content must be at most {} characters", MAX_MESSAGE_LENGTH),
+        ));
+    }
+    Ok(())
+}
+
+// Client details sent with IDENTIFY
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct ClientProperties {
+    pub os: Option<String>,
+    pub browser: Option<String>,
+    pub device: Option<String>,
+}
+
+// Events dispatched by the server over the gateway
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", content = "data", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ServerEvent {
+    MessageCreate(Message),
+    TypingStart(TypingStart),
+    PresenceUpdate(PresenceUpdate),
+    Hello(Hello),
+    HeartbeatAck,
+    Ready(Ready),
+    Resumed,
+    InvalidSession,
+    Error(ErrorPayload),
+    RoleCreate(RoleEvent),
+    RoleUpdate(RoleEvent),
+    RoleDelete(RoleDelete),
+    // A member's roles changed
+    MemberAdd(Member),
+    MemberUpdate(Member),
+    MemberRemove(MemberRemove),
+    ServerUpdate(Server),
+    ServerDelete(ServerDelete),
+    // The user joined a server or left it, in this session or another one
+    ServerJoin(Server),
+    ServerLeave(ServerLeave),
+    // Sent as a delete to members who can't view the updated channel
+    ChannelUpdate(Channel),
+    ChannelDelete(ChannelDelete),
+}
+
+impl ServerEvent {
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(self)
+    }
+}
+
+// A sequenced event as sent to the client: `{"seq": n, "type": ..., "data": ...}`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Dispatch {
+    pub seq: u64,
+    #[serde(flatten)]
+    pub event: ServerEvent,
+}
+
+// Payload published on a Redis topic, carrying the event's entry id in the topic log
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TopicMessage {
+    pub id: String,
+    pub event: ServerEvent,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Hello {
+    // Milliseconds between the heartbeats the client must send
+    pub heartbeat_interval: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Ready {
+    pub session_id: String,
+    pub user_id: String,
+    pub servers: Vec<Server>,
+    pub channels: Vec<Channel>,
+    pub read_states: Vec<ReadState>,
+    // Users sharing a server with the user who are currently visible online
+    pub presences: Vec<PresenceUpdate>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TypingStart {
+    pub user_id: String,
+    pub channel_id: String,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    // Clients drop the indicator at this time unless it is refreshed
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PresenceUpdate {
+    pub user_id: String,
+    pub status: PresenceStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub custom_status: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RoleEvent {
+    pub server_id: String,
+    pub role: Role,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ServerDelete {
+    pub server_id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ServerLeave {
+    pub server_id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ChannelDelete {
+    pub id: String,
+    pub server_id: String,
+}
+
+impl ChannelDelete {
+    pub fn new(channel_id: ObjectId, server_id: ObjectId) -> Self {
+        Self { id: channel_id.to_hex(), server_id: server_id.to_hex() }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct MemberRemove {
+    pub server_id: String,
+    pub user_id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct RoleDelete {
+    pub server_id: String,
+    pub role_id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceStatus {
+    #[default]
+    Online,
+    Idle,
+    Dnd,
+    // Connected, but shown to everyone else as offline
+    Invisible,
+    Offline,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    InvalidPayload,
+    InvalidChannelId,
+    InvalidContent,
+    UnknownChannel,
+    Forbidden,
+    ResumeFailed,
+    Internal,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ErrorPayload {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ErrorPayload {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
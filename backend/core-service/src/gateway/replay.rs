@@ -0,0 +1,196 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use mongodb::bson::oid::ObjectId;
+use redis::{aio::ConnectionLike, streams::StreamRangeReply, AsyncCommands, RedisResult};
+use serde::{Deserialize, Serialize};
+
+use super::protocol::ClientProperties;
+
+// How long a dropped session can be resumed, and how long topic logs are kept
+pub const REPLAY_TTL_SECONDS: i64 = 300;
+// Approximate number of entries kept per session buffer and per topic log
+pub const REPLAY_BUFFER_LEN: usize = 1000;
+
+fn session_key(session_id: &str) -> String {
+    format!("gateway:session:{}", session_id)
+}
+
+fn session_claim_key(session_id: &str) -> String {
+    format!("gateway:session:{}:claim", session_id)
+}
+
+fn session_buffer_key(session_id: &str) -> String {
+    format!("gateway:session:{}:buffer", session_id)
+}
+
+pub fn topic_log_key(topic: &str) -> String {
+    format!("{}:log", topic)
+}
+
+// Compares two Redis stream entry ids ("<ms>-<seq>") numerically
+pub fn compare_stream_ids(a: &str, b: &str) -> Ordering {
+    fn parts(id: &str) -> (u64, u64) {
+        let (ms, seq) = id.split_once('-').unwrap_or((id, "0"));
+        (ms.parse().unwrap_or(0), seq.parse().unwrap_or(0))
+    }
+    parts(a).cmp(&parts(b))
+}
+
+// What is needed to rebuild a session after its socket dropped
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SessionState {
+    pub user_id: ObjectId,
+    pub seq: u64,
+    pub joined: Vec<ObjectId>,
+    // Last topic log entry delivered to the session, per topic
+    pub cursors: HashMap<String, String>,
+    #[serde(default)]
+    pub properties: ClientProperties,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+}
+
+impl SessionState {
+    // Claims the session for one socket, so two sockets resuming it at once can't both take it
+    // over. False if another socket holds it; the claim lasts until that socket suspends it.
+    pub async fn claim<C: ConnectionLike + Send>(
+        conn: &mut C,
+        session_id: &str,
+        socket_id: &str,
+    ) -> RedisResult<bool> {
+        let claimed: Option<String> = redis::cmd("SET")
+            .arg(session_claim_key(session_id))
+            .arg(socket_id)
+            .arg("NX")
+            .arg("EX")
+            .arg(REPLAY_TTL_SECONDS)
+            .query_async(conn)
+            .await?;
+        Ok(claimed.is_some())
+    }
+
+    pub async fn release<C: ConnectionLike + Send>(conn: &mut C, session_id: &str) -> RedisResult<()> {
+        conn.del(session_claim_key(session_id)).await
+    }
+
+    // Loads and removes the saved state, so it is resumed at most once
+    pub async fn take<C: ConnectionLike + Send>(
+        conn: &mut C,
+        session_id: &str,
+    ) -> RedisResult<Option<Self>> {
+        let value: Option<String> = conn.get_del(session_key(session_id)).await?;
+        Ok(value.and_then(|v| serde_json::from_str(&v).ok()))
+    }
+
+    pub async fn save<C: ConnectionLike + Send>(
+        &self,
+        conn: &mut C,
+        session_id: &str,
+    ) -> RedisResult<()> {
+        let value = serde_json::to_string(self).expect("session state is serializable");
+        conn.set_ex(session_key(session_id), value, REPLAY_TTL_SECONDS as u64)
+            .await
+    }
+
+    pub async fn delete<C: ConnectionLike + Send>(conn: &mut C, session_id: &str) -> RedisResult<()> {
+        conn.del(&[session_key(session_id), session_buffer_key(session_id)])
+            .await
+    }
+}
+
+// Appends a dispatched frame to the session's replay buffer, keyed by its sequence number
+pub async fn buffer_dispatch<C: ConnectionLike + Send>(
+    conn: &mut C,
+    session_id: &str,
+    seq: u64,
+    frame: &str,
+) -> RedisResult<()> {
+    let key = session_buffer_key(session_id);
+    redis::pipe()
+        .cmd("XADD")
+        .arg(&key)
+        .arg("MAXLEN")
+        .arg("~")
+        .arg(REPLAY_BUFFER_LEN)
+        .arg(format!("{}-0", seq))
+        .arg("frame")
+        .arg(frame)
+        .ignore()
+        .expire(&key, REPLAY_TTL_SECONDS)
+        .ignore()
+        .query_async(conn)
+        .await
+}
+
+// Buffered frames with a sequence number greater than `seq`, oldest first
+pub async fn buffered_since<C: ConnectionLike + Send>(
+    conn: &mut C,
+    session_id: &str,
+    seq: u64,
+) -> RedisResult<Vec<(u64, String)>> {
+    let reply: StreamRangeReply = conn
+        .xrange(session_buffer_key(session_id), format!("{}-0", seq + 1), "+")
+        .await?;
+    Ok(reply
+        .ids
+        .iter()
+        .filter_map(|entry| {
+            let seq = entry.id.split_once('-')?.0.parse().ok()?;
+            Some((seq, entry.get("frame")?))
+        })
+        .collect())
+}
+
+// Appends an event payload to a topic's log and returns its entry id
+pub async fn append_topic_log<C: ConnectionLike + Send>(
+    conn: &mut C,
+    topic: &str,
+    payload: &str,
+) -> RedisResult<String> {
+    let key = topic_log_key(topic);
+    let (id,): (String,) = redis::pipe()
+        .cmd("XADD")
+        .arg(&key)
+        .arg("MAXLEN")
+        .arg("~")
+        .arg(REPLAY_BUFFER_LEN)
+        .arg("*")
+        .arg("event")
+        .arg(payload)
+        .expire(&key, REPLAY_TTL_SECONDS)
+        .ignore()
+        .query_async(conn)
+        .await?;
+    Ok(id)
+}
+
+// Topic log entries after `cursor`, oldest first
+pub async fn topic_log_since<C: ConnectionLike + Send>(
+    conn: &mut C,
+    topic: &str,
+    cursor: &str,
+) -> RedisResult<Vec<(String, String)>> {
+    let reply: StreamRangeReply = conn
+        .xrange(topic_log_key(topic), format!("({}", cursor), "+")
+        .await?;
+    Ok(reply
+        .ids
+        .into_iter()
+        .filter_map(|entry| {
+            let event = entry.get("event")?;
+            Some((entry.id, event))
+        })
+        .collect())
+}
+
+// Id of the newest entry in a topic's log, if any
+pub async fn latest_topic_id<C: ConnectionLike + Send>(
+    conn: &mut C,
+    topic: &str,
+) -> RedisResult<Option<String>> {
+    let reply: StreamRangeReply = conn
+        .xrevrange_count(topic_log_key(topic), "+", "-", 1)
+        .await?;
+    Ok(reply.ids.into_iter().next().map(|entry| entry.id))
+}
@@ -0,0 +1,17 @@
+use mongodb::bson::oid::ObjectId;
+
+// Redis pub/sub topics the gateway fans events out on. Sockets only
+// subscribe to the topics of channels they joined, servers they belong to and their user.
+
+pub fn channel_topic(channel_id: &ObjectId) -> String {
+    format!("gateway:channel:{}", channel_id.to_hex())
+}
+
+pub fn server_topic(server_id: &ObjectId) -> String {
+    format!("gateway:server:{}", server_id.to_hex())
+}
+
+// Events for every session of one user, such as joining or leaving a server
+pub fn user_topic(user_id: &ObjectId) -> String {
+    format!("gateway:user:{}", user_id.to_hex())
+}
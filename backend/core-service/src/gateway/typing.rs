@@ -0,0 +1,43 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use mongodb::bson::oid::ObjectId;
+use redis::{aio::ConnectionLike, RedisResult};
+
+use super::protocol::TypingStart;
+
+// A user emits at most one TYPING_START per channel in this window
+pub const TYPING_THROTTLE: Duration = Duration::from_secs(5);
+// How long clients show the indicator unless another TYPING_START or a message arrives
+pub const TYPING_DURATION: Duration = Duration::from_secs(10);
+
+fn typing_key(channel_id: &ObjectId, user_id: &ObjectId) -> String {
+    format!("gateway:typing:{}:{}", channel_id.to_hex(), user_id.to_hex())
+}
+
+// Claims the user's typing slot in a channel; false while a previous event is still throttled.
+// Shared through Redis, so the throttle holds across sessions and instances.
+pub async fn try_start<C: ConnectionLike + Send>(
+    conn: &mut C,
+    channel_id: &ObjectId,
+    user_id: &ObjectId,
+) -> RedisResult<bool> {
+    let claimed: Option<String> = redis::cmd("SET")
+        .arg(typing_key(channel_id, user_id))
+        .arg(1)
+        .arg("NX")
+        .arg("PX")
+        .arg(TYPING_THROTTLE.as_millis() as u64)
+        .query_async(conn)
+        .await?;
+    Ok(claimed.is_some())
+}
+
+pub fn typing_start(user_id: &ObjectId, channel_id: &ObjectId, now: DateTime<Utc>) -> TypingStart {
+    TypingStart {
+        user_id: user_id.to_hex(),
+        channel_id: channel_id.to_hex(),
+        timestamp: now,
+        expires_at: now + TYPING_DURATION,
+    }
+}
@@ -0,0 +1,103 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use common::auth::VerifyingKey;
+use jsonwebtoken::jwk::JwkSet;
+use tokio::sync::{Mutex, RwLock};
+
+// How often the key set is re-fetched in the background
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+
+// Minimum time between fetches triggered by tokens with an unknown kid,
+// so garbage tokens cannot turn into a flood of requests to auth-service
+pub const MIN_FETCH_INTERVAL: Duration = Duration::from_secs(30);
+
+// auth-service's public keys, cached and refreshed so rotated keys are picked up
+#[derive(Clone)]
+pub struct JwksCache {
+    url: String,
+    client: reqwest::Client,
+    keys: Arc<RwLock<JwkSet>>,
+    last_fetch: Arc<Mutex<Option<Instant>>>,
+}
+
+impl JwksCache {
+    pub fn new(url: String) -> Self {
+        Self::with_keys(url, JwkSet { keys: Vec::new() })
+    }
+
+    pub fn with_keys(url: String, keys: JwkSet) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(5))
+            .build()
+            .expect("HTTP client configuration is valid");
+        Self {
+            url,
+            client,
+            keys: Arc::new(RwLock::new(keys)),
+            last_fetch: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    // Fetches the key set now and keeps it fresh in the background. A failed first
+    // fetch is not fatal: auth-service may still be starting, and the next token retries.
+    pub async fn connect(url: String) -> Self {
+        let cache = Self::new(url);
+        if let Err(e) = cache.refresh().await {
+            eprintln!("⚠️ Failed to fetch JWKS from {}: {}", cache.url, e);
+        }
+
+        let background = cache.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if let Err(e) = background.refresh().await {
+                    eprintln!("⚠️ Failed to refresh JWKS from {}: {}", background.url, e);
+                }
+            }
+        });
+        cache
+    }
+
+    async fn refresh(&self) -> reqwest::Result<()> {
+        *self.last_fetch.lock().await = Some(Instant::now());
+        let keys: JwkSet = self
+            .client
+            .get(&self.url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        *self.keys.write().await = keys;
+        Ok(())
+    }
+
+    async fn cached(&self, kid: &str) -> Option<VerifyingKey> {
+        self.keys.read().await.find(kid).and_then(VerifyingKey::from_jwk)
+    }
+
+    // Key for `kid`, re-fetching the set once if the kid is new (a freshly rotated key)
+    pub async fn get(&self, kid: &str) -> Option<VerifyingKey> {
+        if let Some(key) = self.cached(kid).await {
+            return Some(key);
+        }
+
+        {
+            let mut last_fetch = self.last_fetch.lock().await;
+            if last_fetch.is_some_and(|at| at.elapsed() < MIN_FETCH_INTERVAL) {
+                return None;
+            }
+            *last_fetch = Some(Instant::now());
+        }
+        if let Err(e) = self.refresh().await {
+            eprintln!("⚠️ Failed to fetch JWKS from {}: {}", self.url, e);
+            return None;
+        }
+        self.cached(kid).await
+    }
+}
@@ -1,11 +1,21 @@
+mod access;
 mod auth;
+mod cache;
 mod db;
+mod gateway;
+mod jwks;
+mod membership;
 mod models;
 mod routes;
+mod sessions;
+mod tokens;
 mod websocket;
 mod tests;
 
-use axum::{routing::{get, post}, Router};
+use axum::{
+    routing::{delete, get, patch, post, put},
+    Extension, Router,
+};
 use dotenv::dotenv;
 use std::net::SocketAddr;
 use tower_http::{cors::CorsLayer, trace::TraceLayer};
@@ -17,15 +27,35 @@ async fn main() {
     
     let db = db::get_database().await.expect("DB connection failed");
     let redis_url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://redis:6379".to_string());
-    let redis_client = redis::Client::open(redis_url).expect("Redis connection failed");
+    let redis_client = redis::Client::open(redis_url.as_str()).expect("Redis connection failed");
+    let cache = cache::CacheManager::new(&redis_url).await.expect("Redis connection failed");
+    let hub = gateway::hub::Hub::connect(redis_client.clone())
+        .await
+        .expect("Redis connection failed");
+    let jwks_url = std::env::var("AUTH_JWKS_URL")
+        .unwrap_or_else(|_| "http://auth:8081/.well-known/jwks.json".to_string());
+    let keys = jwks::JwksCache::connect(jwks_url).await;
 
     let cors = CorsLayer::permissive();
 
     let app = Router::new()
         .route("/servers", get(routes::servers::list_servers).post(routes::servers::create_server))
+        .route("/servers/:server_id", patch(routes::servers::update_server).delete(routes::servers::delete_server))
         .route("/servers/:server_id/channels", get(routes::channels::list_channels).post(routes::channels::create_channel))
+        .route("/servers/:server_id/members", get(routes::members::list_members))
+        .route("/servers/:server_id/members/@me", delete(routes::members::leave_server))
+        .route("/servers/:server_id/members/:user_id/roles/:role_id", put(routes::roles::add_member_role).delete(routes::roles::remove_member_role))
+        .route("/servers/:server_id/roles", get(routes::roles::list_roles).post(routes::roles::create_role).patch(routes::roles::reorder_roles))
+        .route("/servers/:server_id/roles/:role_id", patch(routes::roles::update_role).delete(routes::roles::delete_role))
+        .route("/servers/:server_id/invites", get(routes::invites::list_invites))
+        .route("/invites/:invite_code", get(routes::invites::preview_invite).post(routes::invites::join_server).delete(routes::invites::revoke_invite))
+        .route("/channels/:channel_id", patch(routes::channels::update_channel).delete(routes::channels::delete_channel))
+        .route("/channels/:channel_id/invites", post(routes::invites::create_invite))
         .route("/channels/:channel_id/messages", get(routes::messages::get_messages).post(routes::messages::send_message))
         .route("/ws", get(websocket::ws_handler))
+        .layer(Extension(hub))
+        .layer(Extension(cache))
+        .layer(Extension(keys))
         .layer(cors)
         .layer(TraceLayer::new_for_http())
         .with_state((db, redis_client));
@@ -34,5 +64,31 @@ async fn main() {
     println!("🚀 Core service running on {}", addr);
     
     let listener = TcpListener::bind(&addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal())
+        .await
+        .unwrap();
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.expect("failed to listen for ctrl-c");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    println!("🛑 Shutting down, closing gateway connections");
+    gateway::begin_shutdown();
 }
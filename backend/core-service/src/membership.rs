@@ -0,0 +1,139 @@
+use std::collections::HashSet;
+
+use mongodb::{
+    bson::{doc, oid::ObjectId, Bson},
+    options::UpdateOptions,
+    Collection, Database,
+};
+
+use crate::{
+    gateway::{
+        hub::Hub,
+        protocol::{MemberRemove, ServerEvent, ServerLeave},
+        topics::{server_topic, user_topic},
+    },
+    models::{Member, Server},
+};
+
+// Servers the user is a member of
+pub async fn server_ids_for_user(
+    db: &Database,
+    user_id: ObjectId,
+) -> mongodb::error::Result<HashSet<ObjectId>> {
+    let members: Collection<Member> = db.collection("members");
+    Ok(members
+        .distinct("server_id", doc! { "user_id": user_id }, None)
+        .await?
+        .iter()
+        .filter_map(Bson::as_object_id)
+        .collect())
+}
+
+// Makes the user a member of the server and returns the new member; None if they already
+// were one. A temporary member who joins again permanently stays on, while joining temporarily
+// changes nothing for members.
+pub async fn add_member(
+    db: &Database,
+    server_id: ObjectId,
+    user_id: ObjectId,
+    temporary: bool,
+) -> mongodb::error::Result<Option<Member>> {
+    let joined_at = chrono::Utc::now();
+    let now = mongodb::bson::to_bson(&joined_at)?;
+    let update = if temporary {
+        doc! { "$setOnInsert": { "roles": [], "joined_at": now, "temporary": true } }
+    } else {
+        doc! { "$setOnInsert": { "roles": [], "joined_at": now }, "$set": { "temporary": false } }
+    };
+    let members: Collection<Member> = db.collection("members");
+    let result = members
+        .update_one(
+            doc! { "server_id": server_id, "user_id": user_id },
+            update,
+            UpdateOptions::builder().upsert(true).build(),
+        )
+        .await?;
+    Ok(result.upserted_id.map(|id| Member {
+        id: id.as_object_id(),
+        server_id,
+        user_id,
+        roles: Vec::new(),
+        joined_at,
+        temporary,
+    }))
+}
+
+// Ends the user's membership of the server; false if they weren't a member
+pub async fn remove_member(
+    db: &Database,
+    server_id: ObjectId,
+    user_id: ObjectId,
+) -> mongodb::error::Result<bool> {
+    let members: Collection<Member> = db.collection("members");
+    let result = members
+        .delete_one(doc! { "server_id": server_id, "user_id": user_id }, None)
+        .await?;
+    Ok(result.deleted_count > 0)
+}
+
+// Ends the user's temporary memberships, except those where they were given a role, and
+// returns the servers they were removed from
+pub async fn remove_temporary(db: &Database, user_id: ObjectId) -> mongodb::error::Result<Vec<ObjectId>> {
+    let members: Collection<Member> = db.collection("members");
+    let server_ids: Vec<ObjectId> = members
+        .distinct("server_id", doc! { "user_id": user_id, "temporary": true }, None)
+        .await?
+        .iter()
+        .filter_map(Bson::as_object_id)
+        .collect();
+
+    let mut removed = Vec::new();
+    for server_id in server_ids {
+        // Checked again on delete, as they may have been given a role meanwhile
+        let result = members
+            .delete_one(
+                doc! { "server_id": server_id, "user_id": user_id, "temporary": true, "roles": { "$size": 0 } },
+                None,
+            )
+            .await?;
+        if result.deleted_count > 0 {
+            removed.push(server_id);
+        }
+    }
+    Ok(removed)
+}
+
+// Tells the server's members about the new member, and the member's gateway sessions that they
+// joined, so they subscribe to the server
+pub async fn announce_join(hub: &Hub, server: &Server, member: &Member) -> redis::RedisResult<()> {
+    hub.publish(&server_topic(&member.server_id), ServerEvent::MemberAdd(member.clone()))
+        .await?;
+    hub.publish(&user_topic(&member.user_id), ServerEvent::ServerJoin(server.clone()))
+        .await
+}
+
+// Tells the server's members the user left, and the user's gateway sessions that they are no
+// longer in the server, so they stop receiving its events and those of its channels
+pub async fn announce_leave(hub: &Hub, user_id: ObjectId, server_id: ObjectId) -> redis::RedisResult<()> {
+    let event = ServerEvent::MemberRemove(MemberRemove {
+        server_id: server_id.to_hex(),
+        user_id: user_id.to_hex(),
+    });
+    hub.publish(&server_topic(&server_id), event).await?;
+    let event = ServerEvent::ServerLeave(ServerLeave { server_id: server_id.to_hex() });
+    hub.publish(&user_topic(&user_id), event).await
+}
+
+// Users belonging to any of the given servers
+pub async fn member_ids(
+    db: &Database,
+    server_ids: &[ObjectId],
+) -> mongodb::error::Result<HashSet<ObjectId>> {
+    let members: Collection<Member> = db.collection("members");
+    Ok(members
+        .distinct("user_id", doc! { "server_id": { "$in": server_ids } }, None)
+        .await?
+        .iter()
+        .filter_map(Bson::as_object_id)
+        .collect())
+}
@@ -1,36 +1,17 @@
 use mongodb::bson::oid::ObjectId;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct Server {
-    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
-    pub id: Option<ObjectId>,
-    pub name: String,
-    pub owner_id: ObjectId,
-    pub invite_code: String,
-    pub created_at: chrono::DateTime<chrono::Utc>,
-}
-
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct Channel {
-    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
-    pub id: Option<ObjectId>,
-    pub server_id: ObjectId,
-    pub name: String,
-    pub channel_type: String, // "text" or "voice"
-    pub topic: Option<String>,
-    pub created_at: chrono::DateTime<chrono::Utc>,
-}
+pub use common::models::{parse_id, Channel, ChannelType, Member, Message, Role, Server};
 
+// Last message a user has read in a channel
 #[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct Message {
+pub struct ReadState {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
     pub id: Option<ObjectId>,
-    pub channel_id: ObjectId,
     pub user_id: ObjectId,
-    pub content: String,
-    pub attachments: Vec<String>,
-    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub channel_id: ObjectId,
+    pub last_message_id: ObjectId,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -42,8 +23,26 @@ pub struct CreateServerRequest {
 #[derive(Debug, Deserialize)]
 pub struct CreateChannelRequest {
     pub name: String,
-    pub channel_type: String,
+    pub channel_type: ChannelType,
+    pub topic: Option<String>,
+}
+
+// Fields left out are kept as they are; an empty description or icon_url clears it
+#[derive(Debug, Deserialize)]
+pub struct UpdateServerRequest {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub icon_url: Option<String>,
+    // Hands the server to another member; only the owner may
+    pub owner_id: Option<String>,
+}
+
+// Fields left out are kept as they are; an empty topic clears it
+#[derive(Debug, Deserialize)]
+pub struct UpdateChannelRequest {
+    pub name: Option<String>,
     pub topic: Option<String>,
+    pub position: Option<i32>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -51,3 +50,101 @@ pub struct SendMessageRequest {
     pub content: String,
     pub attachments: Option<Vec<String>>,
 }
+
+#[derive(Debug, Deserialize)]
+pub struct MemberQuery {
+    pub limit: Option<i64>,
+    // User id of the last member of the previous page
+    pub after: Option<String>,
+}
+
+// A member as listed to the server's other members
+#[derive(Debug, Serialize)]
+pub struct MemberResponse {
+    #[serde(flatten)]
+    pub member: Member,
+    pub username: String,
+    pub bot: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateRoleRequest {
+    pub name: String,
+    #[serde(default)]
+    pub color: String,
+    #[serde(default)]
+    pub permissions: u64,
+    #[serde(default)]
+    pub hoist: bool,
+    #[serde(default)]
+    pub mentionable: bool,
+}
+
+// Fields left out are kept as they are
+#[derive(Debug, Deserialize)]
+pub struct UpdateRoleRequest {
+    pub name: Option<String>,
+    pub color: Option<String>,
+    pub permissions: Option<u64>,
+    pub position: Option<i32>,
+    pub hoist: Option<bool>,
+    pub mentionable: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RolePosition {
+    pub id: String,
+    pub position: i32,
+}
+
+// A code that lets users join a server. Revoking an invite deletes it.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Invite {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub code: String,
+    pub server_id: ObjectId,
+    // Channel the invite leads to; none for the codes servers had before invites
+    pub channel_id: Option<ObjectId>,
+    pub creator_id: ObjectId,
+    // Seconds the invite is valid for after it was created, 0 for ever
+    pub max_age: u32,
+    // Joins the invite allows, 0 for any number
+    pub max_uses: u32,
+    pub uses: u32,
+    // Whether users joining with it become temporary members
+    pub temporary: bool,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl Invite {
+    // Whether the invite can still be used to join
+    pub fn is_usable(&self, now: chrono::DateTime<chrono::Utc>) -> bool {
+        self.expires_at.is_none_or(|expires_at| expires_at > now)
+            && (self.max_uses == 0 || self.uses < self.max_uses)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateInviteRequest {
+    pub max_age: Option<u32>,
+    #[serde(default)]
+    pub max_uses: u32,
+    #[serde(default)]
+    pub temporary: bool,
+}
+
+// What anyone holding an invite code may see of it before joining
+#[derive(Debug, Serialize)]
+pub struct InvitePreview {
+    pub code: String,
+    pub server_id: ObjectId,
+    pub server_name: String,
+    pub server_icon_url: Option<String>,
+    pub channel_id: Option<ObjectId>,
+    pub channel_name: Option<String>,
+    pub member_count: u64,
+    pub temporary: bool,
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+}
@@ -1,50 +1,96 @@
 use axum::{
     extract::{Path, State},
     http::StatusCode,
-    Json,
+    Extension, Json,
 };
 use futures_util::TryStreamExt;
-use mongodb::{bson::doc, Collection};
+use mongodb::{
+    bson::{doc, oid::ObjectId, Document},
+    options::{FindOneAndUpdateOptions, ReturnDocument},
+    Collection, Database,
+};
+
+use common::{
+    auth::Scope,
+    permissions::{self, MANAGE_CHANNELS, VIEW_CHANNEL},
+};
 
-use crate::{auth::AuthUser, models::*};
+use crate::{
+    access,
+    auth::AuthUser,
+    cache::CacheManager,
+    gateway::{hub::Hub, protocol::{ChannelDelete, ServerEvent}, topics::server_topic},
+    models::*,
+};
+
+// How long a server's channel list is cached; every change to its channels drops the cache
+// entry, so this only bounds staleness if that fails
+const CHANNELS_TTL_SECONDS: u64 = 300;
+const MAX_CHANNEL_NAME_LENGTH: usize = 100;
+const MAX_TOPIC_LENGTH: usize = 1024;
+
+// Names are stored trimmed, and can't be empty
+fn channel_name(name: &str) -> Result<String, StatusCode> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_CHANNEL_NAME_LENGTH {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    Ok(name.to_string())
+}
+
+// Empty topics are stored as none
+fn topic(topic: &str) -> Result<Option<String>, StatusCode> {
+    let topic = topic.trim();
+    if topic.chars().count() > MAX_TOPIC_LENGTH {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    Ok((!topic.is_empty()).then(|| topic.to_string()))
+}
 
 pub async fn list_channels(
     State((db, _redis)): State<(mongodb::Database, redis::Client)>,
+    Extension(mut cache): Extension<CacheManager>,
     Path(server_id): Path<String>,
-    _user: AuthUser,
+    user: AuthUser,
 ) -> Result<Json<Vec<Channel>>, StatusCode> {
-    let server_oid = mongodb::bson::oid::ObjectId::parse_str(&server_id)
-        .map_err(|_| StatusCode::BAD_REQUEST)?;
-    
-    let channels: Collection<Channel> = db.collection("channels");
-    let cursor = channels
-        .find(doc! { "server_id": server_oid }, None)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    user.require(Scope::ChannelsRead)?;
+    let server_oid = parse_id(&server_id)?;
+    let user_oid = parse_id(&user.user_id)?;
+    let (server, member) = access::require_member(&db, server_oid, user_oid, VIEW_CHANNEL).await?;
     
-    let results: Vec<Channel> = cursor
-        .try_collect()
+    let results = server_channels(&db, &mut cache, server_oid)
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    // Channels hidden from the user are left out
+    let results = results
+        .into_iter()
+        .filter(|channel| permissions::channel_permissions(&server, &member, channel) != 0)
+        .collect();
     
     Ok(Json(results))
 }
 
 pub async fn create_channel(
     State((db, _redis)): State<(mongodb::Database, redis::Client)>,
+    Extension(mut cache): Extension<CacheManager>,
     Path(server_id): Path<String>,
-    _user: AuthUser,
+    user: AuthUser,
     Json(payload): Json<CreateChannelRequest>,
 ) -> Result<Json<Channel>, StatusCode> {
-    let server_oid = mongodb::bson::oid::ObjectId::parse_str(&server_id)
-        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    user.require(Scope::ChannelsWrite)?;
+    let server_oid = parse_id(&server_id)?;
+    let user_oid = parse_id(&user.user_id)?;
+    access::require_server(&db, server_oid, user_oid, MANAGE_CHANNELS).await?;
     
     let channel = Channel {
         id: None,
         server_id: server_oid,
-        name: payload.name,
+        name: channel_name(&payload.name)?,
         channel_type: payload.channel_type,
-        topic: payload.topic,
+        topic: payload.topic.as_deref().map(topic).transpose()?.flatten(),
+        position: 0,
+        permissions: Vec::new(),
         created_at: chrono::Utc::now(),
     };
     
@@ -55,7 +101,127 @@ pub async fn create_channel(
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     
     let mut channel = channel;
-    channel.id = Some(result.inserted_id.as_object_id().unwrap());
+    channel.id = Some(result.inserted_id.as_object_id().ok_or(StatusCode::INTERNAL_SERVER_ERROR)?);
+    cache.invalidate_channels(&server_oid).await;
     
     Ok(Json(channel))
 }
+
+// All of a server's channels, from the cache or from Mongo
+async fn server_channels(
+    db: &Database,
+    cache: &mut CacheManager,
+    server_id: ObjectId,
+) -> mongodb::error::Result<Vec<Channel>> {
+    // The cache is only an optimization; fall back to Mongo if Redis is unavailable
+    if let Ok(Some(cached)) = cache.get_channels(&server_id.to_hex()).await {
+        return Ok(cached);
+    }
+
+    let channels: Collection<Channel> = db.collection("channels");
+    let results: Vec<Channel> = channels
+        .find(doc! { "server_id": server_id }, None)
+        .await?
+        .try_collect()
+        .await?;
+
+    if let Err(err) = cache
+        .cache_channels(&server_id.to_hex(), &results, CHANNELS_TTL_SECONDS)
+        .await
+    {
+        eprintln!("⚠️ Failed to cache channels of server {}: {}", server_id, err);
+    }
+
+    Ok(results)
+}
+
+pub async fn update_channel(
+    State((db, _redis)): State<(mongodb::Database, redis::Client)>,
+    Extension(hub): Extension<Hub>,
+    Extension(mut cache): Extension<CacheManager>,
+    Path(channel_id): Path<String>,
+    user: AuthUser,
+    Json(payload): Json<UpdateChannelRequest>,
+) -> Result<Json<Channel>, StatusCode> {
+    user.require(Scope::ChannelsWrite)?;
+    let channel_oid = parse_id(&channel_id)?;
+    let user_oid = parse_id(&user.user_id)?;
+
+    let mut changes = Document::new();
+    if let Some(name) = &payload.name {
+        changes.insert("name", channel_name(name)?);
+    }
+    if let Some(new_topic) = &payload.topic {
+        changes.insert("topic", topic(new_topic)?);
+    }
+    if let Some(position) = payload.position {
+        changes.insert("position", position);
+    }
+
+    let channel = access::require_channel(&db, channel_oid, user_oid, VIEW_CHANNEL | MANAGE_CHANNELS).await?;
+    if changes.is_empty() {
+        return Ok(Json(channel));
+    }
+
+    let channels: Collection<Channel> = db.collection("channels");
+    let options = FindOneAndUpdateOptions::builder()
+        .return_document(ReturnDocument::After)
+        .build();
+    let channel = channels
+        .find_one_and_update(doc! { "_id": channel_oid }, doc! { "$set": changes }, options)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    cache.invalidate_channels(&channel.server_id).await;
+
+    hub.publish(&server_topic(&channel.server_id), ServerEvent::ChannelUpdate(channel.clone()))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(channel))
+}
+
+// Deletes the channel along with its messages, read states and invites
+pub async fn delete_channel(
+    State((db, _redis)): State<(mongodb::Database, redis::Client)>,
+    Extension(hub): Extension<Hub>,
+    Extension(mut cache): Extension<CacheManager>,
+    Path(channel_id): Path<String>,
+    user: AuthUser,
+) -> Result<StatusCode, StatusCode> {
+    user.require(Scope::ChannelsWrite)?;
+    let channel_oid = parse_id(&channel_id)?;
+    let user_oid = parse_id(&user.user_id)?;
+    let channel = access::require_channel(&db, channel_oid, user_oid, VIEW_CHANNEL | MANAGE_CHANNELS).await?;
+
+    let channels: Collection<Channel> = db.collection("channels");
+    let deleted = channels
+        .delete_one(doc! { "_id": channel_oid }, None)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if deleted.deleted_count == 0 {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    delete_channel_data(&db, &[channel_oid])
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    cache.invalidate_channels(&channel.server_id).await;
+
+    let event = ServerEvent::ChannelDelete(ChannelDelete::new(channel_oid, channel.server_id));
+    hub.publish(&server_topic(&channel.server_id), event)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Deletes what belongs to channels that are gone: their messages, read states and invites
+pub async fn delete_channel_data(db: &Database, channel_ids: &[ObjectId]) -> mongodb::error::Result<()> {
+    let filter = doc! { "channel_id": { "$in": channel_ids } };
+    for collection in ["messages", "read_states", "invites"] {
+        db.collection::<Document>(collection)
+            .delete_many(filter.clone(), None)
+            .await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,243 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Extension, Json,
+};
+use common::{
+    auth::Scope,
+    permissions::{CREATE_INVITE, MANAGE_SERVER, VIEW_CHANNEL},
+};
+use futures_util::TryStreamExt;
+use mongodb::{bson::doc, options::FindOptions, Collection};
+
+use crate::{access, auth::AuthUser, gateway::hub::Hub, membership, models::*};
+
+// Seconds an invite lasts when the request doesn't say, and at most unless it never expires
+const DEFAULT_INVITE_MAX_AGE: u32 = 24 * 60 * 60;
+const MAX_INVITE_MAX_AGE: u32 = 7 * 24 * 60 * 60;
+const MAX_INVITE_USES: u32 = 100;
+// Usable invites one server may have at a time
+pub const MAX_INVITES_PER_SERVER: usize = 1000;
+
+pub async fn create_invite(
+    State((db, _redis)): State<(mongodb::Database, redis::Client)>,
+    Path(channel_id): Path<String>,
+    user: AuthUser,
+    Json(payload): Json<CreateInviteRequest>,
+) -> Result<Json<Invite>, StatusCode> {
+    user.require(Scope::ServersWrite)?;
+    let channel_oid = parse_id(&channel_id)?;
+    let user_oid = parse_id(&user.user_id)?;
+    let max_age = payload.max_age.unwrap_or(DEFAULT_INVITE_MAX_AGE);
+    if max_age > MAX_INVITE_MAX_AGE || payload.max_uses > MAX_INVITE_USES {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let channel = access::require_channel(&db, channel_oid, user_oid, VIEW_CHANNEL | CREATE_INVITE).await?;
+
+    let invites: Collection<Invite> = db.collection("invites");
+    let existing: Vec<Invite> = invites
+        .find(doc! { "server_id": channel.server_id }, None)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .try_collect()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let now = chrono::Utc::now();
+    if at_invite_limit(&existing, now) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let mut invite = Invite {
+        id: None,
+        code: nanoid::nanoid!(10),
+        server_id: channel.server_id,
+        channel_id: Some(channel_oid),
+        creator_id: user_oid,
+        max_age,
+        max_uses: payload.max_uses,
+        uses: 0,
+        temporary: payload.temporary,
+        created_at: now,
+        expires_at: (max_age > 0).then(|| now + chrono::Duration::seconds(max_age.into())),
+    };
+    let result = invites
+        .insert_one(&invite, None)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    invite.id = result.inserted_id.as_object_id();
+
+    Ok(Json(invite))
+}
+
+// Expired and used up invites stay stored but don't count towards the limit
+pub fn at_invite_limit(invites: &[Invite], now: chrono::DateTime<chrono::Utc>) -> bool {
+    invites.iter().filter(|invite| invite.is_usable(now)).count() >= MAX_INVITES_PER_SERVER
+}
+
+pub async fn list_invites(
+    State((db, _redis)): State<(mongodb::Database, redis::Client)>,
+    Path(server_id): Path<String>,
+    user: AuthUser,
+) -> Result<Json<Vec<Invite>>, StatusCode> {
+    user.require(Scope::ServersRead)?;
+    let server_oid = parse_id(&server_id)?;
+    let user_oid = parse_id(&user.user_id)?;
+    access::require_server(&db, server_oid, user_oid, MANAGE_SERVER).await?;
+
+    let invites: Collection<Invite> = db.collection("invites");
+    let options = FindOptions::builder().sort(doc! { "created_at": -1 }).build();
+    let results: Vec<Invite> = invites
+        .find(doc! { "server_id": server_oid }, Some(options))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .try_collect()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    // Expired and used up invites can't be used any more, so they aren't worth listing
+    let now = chrono::Utc::now();
+    let results = results.into_iter().filter(|invite| invite.is_usable(now)).collect();
+
+    Ok(Json(results))
+}
+
+async fn find_usable(db: &mongodb::Database, code: &str) -> Result<Invite, StatusCode> {
+    let invites: Collection<Invite> = db.collection("invites");
+    invites
+        .find_one(doc! { "code": code }, None)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .filter(|invite| invite.is_usable(chrono::Utc::now()))
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+// Needs no authentication: anyone with the code may see where it leads
+pub async fn preview_invite(
+    State((db, _redis)): State<(mongodb::Database, redis::Client)>,
+    Path(invite_code): Path<String>,
+) -> Result<Json<InvitePreview>, StatusCode> {
+    let invite = find_usable(&db, &invite_code).await?;
+
+    let servers: Collection<Server> = db.collection("servers");
+    let server = servers
+        .find_one(doc! { "_id": invite.server_id }, None)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let channel = match invite.channel_id {
+        Some(channel_id) => {
+            let channels: Collection<Channel> = db.collection("channels");
+            channels
+                .find_one(doc! { "_id": channel_id }, None)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        }
+        None => None,
+    };
+    let members: Collection<Member> = db.collection("members");
+    let member_count = members
+        .count_documents(doc! { "server_id": invite.server_id }, None)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(InvitePreview {
+        code: invite.code,
+        server_id: invite.server_id,
+        server_name: server.name,
+        server_icon_url: server.icon_url,
+        channel_id: invite.channel_id,
+        channel_name: channel.map(|channel| channel.name),
+        member_count,
+        temporary: invite.temporary,
+        expires_at: invite.expires_at,
+    }))
+}
+
+// Joins the server an invite belongs to. Joining a server the user is already in changes
+// nothing and doesn't count as a use of the invite.
+pub async fn join_server(
+    State((db, _redis)): State<(mongodb::Database, redis::Client)>,
+    Extension(hub): Extension<Hub>,
+    Path(invite_code): Path<String>,
+    user: AuthUser,
+) -> Result<Json<Server>, StatusCode> {
+    user.require(Scope::ServersWrite)?;
+    let user_oid = parse_id(&user.user_id)?;
+    let invite = find_usable(&db, &invite_code).await?;
+
+    let servers: Collection<Server> = db.collection("servers");
+    let server = servers
+        .find_one(doc! { "_id": invite.server_id }, None)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    // The membership goes in first, so only the join that actually created it uses up the
+    // invite. The use is counted only if the invite is still unexpired and has uses left;
+    // otherwise the membership is taken back.
+    let member = membership::add_member(&db, invite.server_id, user_oid, invite.temporary)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if let Some(member) = member {
+        let now = mongodb::bson::to_bson(&chrono::Utc::now())
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let invites: Collection<Invite> = db.collection("invites");
+        let counted = invites
+            .update_one(
+                doc! {
+                    "_id": invite.id,
+                    "$and": [
+                        { "$or": [{ "expires_at": null }, { "expires_at": { "$gt": now } }] },
+                        { "$or": [
+                            { "max_uses": 0 },
+                            { "$expr": { "$lt": ["$uses", "$max_uses"] } },
+                        ] },
+                    ],
+                },
+                doc! { "$inc": { "uses": 1 } },
+                None,
+            )
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        if counted.matched_count == 0 {
+            membership::remove_member(&db, invite.server_id, user_oid)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            return Err(StatusCode::NOT_FOUND);
+        }
+
+        membership::announce_join(&hub, &server, &member)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+
+    Ok(Json(server))
+}
+
+// Invites may be revoked by whoever created them, and by members with MANAGE_SERVER
+pub async fn revoke_invite(
+    State((db, _redis)): State<(mongodb::Database, redis::Client)>,
+    Path(invite_code): Path<String>,
+    user: AuthUser,
+) -> Result<StatusCode, StatusCode> {
+    user.require(Scope::ServersWrite)?;
+    let user_oid = parse_id(&user.user_id)?;
+
+    let invites: Collection<Invite> = db.collection("invites");
+    let invite = invites
+        .find_one(doc! { "code": &invite_code }, None)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if invite.creator_id != user_oid {
+        access::require_server(&db, invite.server_id, user_oid, MANAGE_SERVER).await?;
+    }
+
+    invites
+        .delete_one(doc! { "_id": invite.id }, None)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
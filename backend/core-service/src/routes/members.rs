@@ -0,0 +1,119 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Extension, Json,
+};
+use common::auth::Scope;
+use futures_util::TryStreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, Document},
+    options::FindOptions,
+    Collection,
+};
+use std::collections::HashMap;
+
+use crate::{access, auth::AuthUser, gateway::hub::Hub, membership, models::*};
+
+// Members listed per page when the request doesn't say, and at most
+const DEFAULT_MEMBER_PAGE: i64 = 100;
+const MAX_MEMBER_PAGE: i64 = 1000;
+
+pub async fn list_members(
+    State((db, _redis)): State<(mongodb::Database, redis::Client)>,
+    Path(server_id): Path<String>,
+    Query(query): Query<MemberQuery>,
+    user: AuthUser,
+) -> Result<Json<Vec<MemberResponse>>, StatusCode> {
+    user.require(Scope::ServersRead)?;
+    let server_oid = parse_id(&server_id)?;
+    let user_oid = parse_id(&user.user_id)?;
+
+    // Only members see who else is in a server
+    access::require_server(&db, server_oid, user_oid, 0).await?;
+
+    let mut filter = doc! { "server_id": server_oid };
+    if let Some(after) = query.after {
+        filter.insert("user_id", doc! { "$gt": parse_id(&after)? });
+    }
+    let options = FindOptions::builder()
+        .limit(query.limit.unwrap_or(DEFAULT_MEMBER_PAGE).clamp(1, MAX_MEMBER_PAGE))
+        .sort(doc! { "user_id": 1 })
+        .build();
+
+    let members: Collection<Member> = db.collection("members");
+    let page: Vec<Member> = members
+        .find(filter, Some(options))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .try_collect()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    // Names of the page's users, fetched in one query
+    let user_ids: Vec<ObjectId> = page.iter().map(|member| member.user_id).collect();
+    let users: Collection<Document> = db.collection("users");
+    let options = FindOptions::builder()
+        .projection(doc! { "username": 1, "bot": 1 })
+        .build();
+    let profiles: HashMap<ObjectId, Document> = users
+        .find(doc! { "_id": { "$in": &user_ids } }, Some(options))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .try_collect::<Vec<Document>>()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .into_iter()
+        .filter_map(|profile| Some((profile.get_object_id("_id").ok()?, profile)))
+        .collect();
+
+    let results = page
+        .into_iter()
+        .map(|member| {
+            let profile = profiles.get(&member.user_id);
+            MemberResponse {
+                username: profile
+                    .and_then(|profile| profile.get_str("username").ok())
+                    .unwrap_or_default()
+                    .to_string(),
+                bot: profile.and_then(|profile| profile.get_bool("bot").ok()).unwrap_or(false),
+                member,
+            }
+        })
+        .collect();
+
+    Ok(Json(results))
+}
+
+pub async fn leave_server(
+    State((db, _redis)): State<(mongodb::Database, redis::Client)>,
+    Extension(hub): Extension<Hub>,
+    Path(server_id): Path<String>,
+    user: AuthUser,
+) -> Result<StatusCode, StatusCode> {
+    user.require(Scope::ServersWrite)?;
+    let server_oid = parse_id(&server_id)?;
+    let user_oid = parse_id(&user.user_id)?;
+
+    let servers: Collection<Server> = db.collection("servers");
+    let server = servers
+        .find_one(doc! { "_id": server_oid }, None)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    // A server always has its owner as a member
+    if server.owner_id == user_oid {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    if !membership::remove_member(&db, server_oid, user_oid)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    membership::announce_leave(&hub, user_oid, server_oid)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -1,14 +1,26 @@
 use axum::{
     extract::{Path, Query, State},
     http::StatusCode,
-    Json,
+    Extension, Json,
+};
+use common::{
+    auth::Scope,
+    permissions::{ATTACH_FILES, READ_MESSAGE_HISTORY, SEND_MESSAGES, VIEW_CHANNEL},
 };
 use futures_util::TryStreamExt;
 use mongodb::{bson::doc, options::FindOptions, Collection};
-use redis::AsyncCommands;
 use serde::Deserialize;
 
-use crate::{auth::AuthUser, models::*};
+use crate::{
+    access,
+    auth::AuthUser,
+    gateway::{
+        hub::Hub,
+        protocol::{self, ServerEvent},
+        topics::channel_topic,
+    },
+    models::*,
+};
 
 #[derive(Debug, Deserialize)]
 pub struct MessageQuery {
@@ -20,16 +32,17 @@ pub async fn get_messages(
     State((db, _redis)): State<(mongodb::Database, redis::Client)>,
     Path(channel_id): Path<String>,
     Query(query): Query<MessageQuery>,
-    _user: AuthUser,
+    user: AuthUser,
 ) -> Result<Json<Vec<Message>>, StatusCode> {
-    let channel_oid = mongodb::bson::oid::ObjectId::parse_str(&channel_id)
-        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    user.require(Scope::MessagesRead)?;
+    let channel_oid = parse_id(&channel_id)?;
+    let user_oid = parse_id(&user.user_id)?;
+    access::require_channel(&db, channel_oid, user_oid, VIEW_CHANNEL | READ_MESSAGE_HISTORY).await?;
     
     let mut filter = doc! { "channel_id": channel_oid };
     
     if let Some(before_id) = query.before {
-        let before_oid = mongodb::bson::oid::ObjectId::parse_str(&before_id)
-            .map_err(|_| StatusCode::BAD_REQUEST)?;
+        let before_oid = parse_id(&before_id)?;
         filter.insert("_id", doc! { "$lt": before_oid });
     }
     
@@ -53,22 +66,29 @@ pub async fn get_messages(
 }
 
 pub async fn send_message(
-    State((db, redis)): State<(mongodb::Database, redis::Client)>,
+    State((db, _redis)): State<(mongodb::Database, redis::Client)>,
+    Extension(hub): Extension<Hub>,
     Path(channel_id): Path<String>,
     user: AuthUser,
     Json(payload): Json<SendMessageRequest>,
 ) -> Result<Json<Message>, StatusCode> {
-    let channel_oid = mongodb::bson::oid::ObjectId::parse_str(&channel_id)
-        .map_err(|_| StatusCode::BAD_REQUEST)?;
-    let user_oid = mongodb::bson::oid::ObjectId::parse_str(&user.0)
-        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    let channel_oid = parse_id(&channel_id)?;
+    user.require(Scope::MessagesWrite)?;
+    let user_oid = parse_id(&user.user_id)?;
+    protocol::validate_content(&payload.content).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let attachments = payload.attachments.unwrap_or_default();
+    let mut required = VIEW_CHANNEL | SEND_MESSAGES;
+    if !attachments.is_empty() {
+        required |= ATTACH_FILES;
+    }
+    access::require_channel(&db, channel_oid, user_oid, required).await?;
     
     let message = Message {
         id: None,
         channel_id: channel_oid,
         user_id: user_oid,
         content: payload.content,
-        attachments: payload.attachments.unwrap_or_default(),
+        attachments,
         created_at: chrono::Utc::now(),
     };
     
@@ -82,9 +102,10 @@ pub async fn send_message(
     message.id = Some(result.inserted_id.as_object_id().unwrap());
     
     // Publish to Redis for real-time delivery
-    let mut conn = redis.get_async_connection().await.unwrap();
-    let message_json = serde_json::to_string(&message).unwrap();
-    let _: () = conn.publish("chat:messages", message_json).await.unwrap();
+    let event = ServerEvent::MessageCreate(message.clone());
+    hub.publish(&channel_topic(&channel_oid), event)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     
     Ok(Json(message))
 }
@@ -1,3 +1,6 @@
 pub mod channels;
+pub mod invites;
+pub mod members;
 pub mod messages;
+pub mod roles;
 pub mod servers;
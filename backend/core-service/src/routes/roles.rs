@@ -0,0 +1,456 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Extension, Json,
+};
+use common::{
+    auth::Scope,
+    permissions::{self, has, ALL, MANAGE_ROLES},
+};
+use futures_util::TryStreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, Bson, Document},
+    options::{FindOneAndUpdateOptions, ReturnDocument},
+    Collection, Database,
+};
+
+use crate::{
+    access,
+    auth::AuthUser,
+    cache::CacheManager,
+    gateway::{
+        hub::Hub,
+        protocol::{RoleDelete, RoleEvent, ServerEvent},
+        topics::server_topic,
+    },
+    models::*,
+};
+
+// Roles one server may have, @everyone included
+const MAX_ROLES: usize = 250;
+const MAX_ROLE_NAME_LENGTH: usize = 100;
+
+// A server as stored, along with a member of it who may manage its roles
+struct RoleManager {
+    server: Server,
+    // The server's `roles` field exactly as read, to detect concurrent changes when saving
+    stored_roles: Option<Bson>,
+    member: Member,
+    permissions: u64,
+}
+
+impl RoleManager {
+    async fn load(db: &Database, server_id: ObjectId, user_id: ObjectId) -> Result<Self, StatusCode> {
+        let servers: Collection<Document> = db.collection("servers");
+        let stored = servers
+            .find_one(doc! { "_id": server_id }, None)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .ok_or(StatusCode::NOT_FOUND)?;
+        let stored_roles = stored.get("roles").cloned();
+        let server: Server =
+            mongodb::bson::from_document(stored).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        let member = access::member(db, server_id, user_id)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .ok_or(StatusCode::FORBIDDEN)?;
+        let permissions = permissions::server_permissions(&server, &member);
+        if !has(permissions, MANAGE_ROLES) {
+            return Err(StatusCode::FORBIDDEN);
+        }
+
+        Ok(Self { server, stored_roles, member, permissions })
+    }
+
+    fn server_id(&self) -> ObjectId {
+        self.server.id.unwrap()
+    }
+
+    fn role(&self, role_id: ObjectId) -> Result<&Role, StatusCode> {
+        self.server
+            .roles
+            .iter()
+            .find(|role| role.role_id == role_id)
+            .ok_or(StatusCode::NOT_FOUND)
+    }
+
+    // Roles may only be given permissions the member has themselves
+    fn may_grant(&self, permissions: u64) -> bool {
+        has(self.permissions, permissions)
+    }
+
+    // Replaces the server's roles; 409 if they changed since they were loaded
+    async fn save(&self, db: &Database, roles: &[Role]) -> Result<(), StatusCode> {
+        let mut filter = doc! { "_id": self.server_id() };
+        match &self.stored_roles {
+            Some(stored) => filter.insert("roles", stored.clone()),
+            None => filter.insert("roles", doc! { "$exists": false }),
+        };
+        let roles = mongodb::bson::to_bson(roles).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        let servers: Collection<Document> = db.collection("servers");
+        let result = servers
+            .update_one(filter, doc! { "$set": { "roles": roles } }, None)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        if result.matched_count == 0 {
+            return Err(StatusCode::CONFLICT);
+        }
+        Ok(())
+    }
+
+    // Publishes ROLE_UPDATE for each role other than `except` whose position changed
+    async fn publish_moves(&self, hub: &Hub, roles: &[Role], except: Option<ObjectId>) -> Result<(), StatusCode> {
+        for role in roles {
+            let moved = self
+                .server
+                .roles
+                .iter()
+                .any(|before| before.role_id == role.role_id && before.position != role.position);
+            if moved && Some(role.role_id) != except {
+                publish(hub, self.server_id(), ServerEvent::RoleUpdate(RoleEvent {
+                    server_id: self.server_id().to_hex(),
+                    role: role.clone(),
+                }))
+                .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn publish(hub: &Hub, server_id: ObjectId, event: ServerEvent) -> Result<(), StatusCode> {
+    hub.publish(&server_topic(&server_id), event)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+fn valid_name(name: &str) -> bool {
+    let name = name.trim();
+    !name.is_empty() && name.chars().count() <= MAX_ROLE_NAME_LENGTH
+}
+
+// Empty for the default color, `#rrggbb` otherwise
+fn valid_color(color: &str) -> bool {
+    color.is_empty()
+        || (color.len() == 7
+            && color.starts_with('#')
+            && color[1..].chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+pub async fn list_roles(
+    State((db, _redis)): State<(mongodb::Database, redis::Client)>,
+    Path(server_id): Path<String>,
+    user: AuthUser,
+) -> Result<Json<Vec<Role>>, StatusCode> {
+    user.require(Scope::ServersRead)?;
+    let server_oid = parse_id(&server_id)?;
+    let user_oid = parse_id(&user.user_id)?;
+    let server = access::require_server(&db, server_oid, user_oid, 0).await?;
+
+    let mut roles = server.roles;
+    roles.sort_by_key(|role| role.position);
+    Ok(Json(roles))
+}
+
+// New roles go at the bottom, just above @everyone
+pub async fn create_role(
+    State((db, _redis)): State<(mongodb::Database, redis::Client)>,
+    Extension(hub): Extension<Hub>,
+    Path(server_id): Path<String>,
+    user: AuthUser,
+    Json(payload): Json<CreateRoleRequest>,
+) -> Result<Json<Role>, StatusCode> {
+    user.require(Scope::ServersWrite)?;
+    let server_oid = parse_id(&server_id)?;
+    let user_oid = parse_id(&user.user_id)?;
+    if !valid_name(&payload.name) || !valid_color(&payload.color) || !has(ALL, payload.permissions) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let manager = RoleManager::load(&db, server_oid, user_oid).await?;
+    if !manager.may_grant(payload.permissions) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    if manager.server.roles.len() >= MAX_ROLES {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let role_id = ObjectId::new();
+    let mut roles = manager.server.roles.clone();
+    roles.push(Role {
+        role_id,
+        name: payload.name.trim().to_string(),
+        permissions: payload.permissions,
+        color: payload.color,
+        position: i32::MAX,
+        hoist: payload.hoist,
+        mentionable: payload.mentionable,
+    });
+    let roles = permissions::reorder_roles(&roles, server_oid, &[(role_id, 1)])
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+    manager.save(&db, &roles).await?;
+
+    let role = roles.iter().find(|role| role.role_id == role_id).unwrap().clone();
+    publish(&hub, server_oid, ServerEvent::RoleCreate(RoleEvent {
+        server_id: server_id.clone(),
+        role: role.clone(),
+    }))
+    .await?;
+    manager.publish_moves(&hub, &roles, Some(role_id)).await?;
+
+    Ok(Json(role))
+}
+
+// @everyone's permissions may be changed, but not its name or position
+pub async fn update_role(
+    State((db, _redis)): State<(mongodb::Database, redis::Client)>,
+    Extension(hub): Extension<Hub>,
+    Path((server_id, role_id)): Path<(String, String)>,
+    user: AuthUser,
+    Json(payload): Json<UpdateRoleRequest>,
+) -> Result<Json<Role>, StatusCode> {
+    user.require(Scope::ServersWrite)?;
+    let server_oid = parse_id(&server_id)?;
+    let role_oid = parse_id(&role_id)?;
+    let user_oid = parse_id(&user.user_id)?;
+    if payload.name.as_deref().is_some_and(|name| !valid_name(name))
+        || payload.color.as_deref().is_some_and(|color| !valid_color(color))
+        || payload.permissions.is_some_and(|permissions| !has(ALL, permissions))
+    {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let manager = RoleManager::load(&db, server_oid, user_oid).await?;
+    let role = manager.role(role_oid)?;
+    if role_oid == server_oid && (payload.name.is_some() || payload.position.is_some()) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    if !permissions::can_manage_role(&manager.server, &manager.member, role) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    // Only permissions being added need to be the member's own
+    if payload
+        .permissions
+        .is_some_and(|permissions| !manager.may_grant(permissions & !role.permissions))
+    {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let mut updated = role.clone();
+    if let Some(name) = payload.name {
+        updated.name = name.trim().to_string();
+    }
+    if let Some(color) = payload.color {
+        updated.color = color;
+    }
+    if let Some(permissions) = payload.permissions {
+        updated.permissions = permissions;
+    }
+    if let Some(hoist) = payload.hoist {
+        updated.hoist = hoist;
+    }
+    if let Some(mentionable) = payload.mentionable {
+        updated.mentionable = mentionable;
+    }
+
+    let mut roles: Vec<Role> = manager
+        .server
+        .roles
+        .iter()
+        .map(|role| if role.role_id == role_oid { updated.clone() } else { role.clone() })
+        .collect();
+    if let Some(position) = payload.position {
+        roles = permissions::reorder_roles(&roles, server_oid, &[(role_oid, position)])
+            .ok_or(StatusCode::BAD_REQUEST)?;
+        let highest = permissions::highest_position(&manager.server, &manager.member);
+        if !permissions::keeps_outranking_roles(&manager.server.roles, &roles, highest) {
+            return Err(StatusCode::FORBIDDEN);
+        }
+    }
+    manager.save(&db, &roles).await?;
+
+    let role = roles.iter().find(|role| role.role_id == role_oid).unwrap().clone();
+    publish(&hub, server_oid, ServerEvent::RoleUpdate(RoleEvent {
+        server_id: server_id.clone(),
+        role: role.clone(),
+    }))
+    .await?;
+    manager.publish_moves(&hub, &roles, Some(role_oid)).await?;
+
+    Ok(Json(role))
+}
+
+// Moves any number of roles at once; returns all of the server's roles in their new order
+pub async fn reorder_roles(
+    State((db, _redis)): State<(mongodb::Database, redis::Client)>,
+    Extension(hub): Extension<Hub>,
+    Path(server_id): Path<String>,
+    user: AuthUser,
+    Json(payload): Json<Vec<RolePosition>>,
+) -> Result<Json<Vec<Role>>, StatusCode> {
+    user.require(Scope::ServersWrite)?;
+    let server_oid = parse_id(&server_id)?;
+    let user_oid = parse_id(&user.user_id)?;
+    let moves = payload
+        .iter()
+        .map(|entry| Ok((parse_id(&entry.id)?, entry.position)))
+        .collect::<Result<Vec<_>, StatusCode>>()?;
+
+    let manager = RoleManager::load(&db, server_oid, user_oid).await?;
+    let roles = permissions::reorder_roles(&manager.server.roles, server_oid, &moves)
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    let highest = permissions::highest_position(&manager.server, &manager.member);
+    if !permissions::keeps_outranking_roles(&manager.server.roles, &roles, highest) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    manager.save(&db, &roles).await?;
+    manager.publish_moves(&hub, &roles, None).await?;
+
+    Ok(Json(roles))
+}
+
+// Also takes the role away from every member and drops its channel overrides
+pub async fn delete_role(
+    State((db, _redis)): State<(mongodb::Database, redis::Client)>,
+    Extension(hub): Extension<Hub>,
+    Extension(mut cache): Extension<CacheManager>,
+    Path((server_id, role_id)): Path<(String, String)>,
+    user: AuthUser,
+) -> Result<StatusCode, StatusCode> {
+    user.require(Scope::ServersWrite)?;
+    let server_oid = parse_id(&server_id)?;
+    let role_oid = parse_id(&role_id)?;
+    let user_oid = parse_id(&user.user_id)?;
+
+    let manager = RoleManager::load(&db, server_oid, user_oid).await?;
+    let role = manager.role(role_oid)?;
+    if role_oid == server_oid {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    if !permissions::can_manage_role(&manager.server, &manager.member, role) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let remaining: Vec<Role> = manager
+        .server
+        .roles
+        .iter()
+        .filter(|role| role.role_id != role_oid)
+        .cloned()
+        .collect();
+    let roles = permissions::reorder_roles(&remaining, server_oid, &[])
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+    manager.save(&db, &roles).await?;
+
+    // Looked up first, so each member who had the role can be sent without it
+    let members: Collection<Member> = db.collection("members");
+    let affected: Vec<ObjectId> = members
+        .distinct("user_id", doc! { "server_id": server_oid, "roles": role_oid }, None)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .iter()
+        .filter_map(Bson::as_object_id)
+        .collect();
+    members
+        .update_many(
+            doc! { "server_id": server_oid, "roles": role_oid },
+            doc! { "$pull": { "roles": role_oid } },
+            None,
+        )
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let channels: Collection<Channel> = db.collection("channels");
+    channels
+        .update_many(
+            doc! { "server_id": server_oid, "permissions.role_id": role_oid },
+            doc! { "$pull": { "permissions": { "role_id": role_oid } } },
+            None,
+        )
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    cache.invalidate_channels(&server_oid).await;
+
+    publish(&hub, server_oid, ServerEvent::RoleDelete(RoleDelete {
+        server_id: server_id.clone(),
+        role_id: role_id.clone(),
+    }))
+    .await?;
+    manager.publish_moves(&hub, &roles, None).await?;
+
+    let updated: Vec<Member> = members
+        .find(doc! { "server_id": server_oid, "user_id": { "$in": &affected } }, None)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .try_collect()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    for member in updated {
+        publish(&hub, server_oid, ServerEvent::MemberUpdate(member)).await?;
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn add_member_role(
+    State((db, _redis)): State<(mongodb::Database, redis::Client)>,
+    Extension(hub): Extension<Hub>,
+    Path((server_id, member_id, role_id)): Path<(String, String, String)>,
+    user: AuthUser,
+) -> Result<StatusCode, StatusCode> {
+    update_member_roles(&db, &hub, user, (server_id, member_id, role_id), "$addToSet").await
+}
+
+pub async fn remove_member_role(
+    State((db, _redis)): State<(mongodb::Database, redis::Client)>,
+    Extension(hub): Extension<Hub>,
+    Path((server_id, member_id, role_id)): Path<(String, String, String)>,
+    user: AuthUser,
+) -> Result<StatusCode, StatusCode> {
+    update_member_roles(&db, &hub, user, (server_id, member_id, role_id), "$pull").await
+}
+
+// Gives a role to a member or takes it away, with `operator` on their roles. Only roles
+// below the acting member's highest one may be given or taken.
+async fn update_member_roles(
+    db: &Database,
+    hub: &Hub,
+    user: AuthUser,
+    (server_id, member_id, role_id): (String, String, String),
+    operator: &str,
+) -> Result<StatusCode, StatusCode> {
+    user.require(Scope::ServersWrite)?;
+    let server_oid = parse_id(&server_id)?;
+    let member_oid = parse_id(&member_id)?;
+    let role_oid = parse_id(&role_id)?;
+    let user_oid = parse_id(&user.user_id)?;
+
+    let manager = RoleManager::load(db, server_oid, user_oid).await?;
+    let role = manager.role(role_oid)?;
+    // Every member has @everyone implicitly
+    if role_oid == server_oid {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    if !permissions::can_manage_role(&manager.server, &manager.member, role) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let members: Collection<Member> = db.collection("members");
+    let options = FindOneAndUpdateOptions::builder()
+        .return_document(ReturnDocument::After)
+        .build();
+    let mut update = doc! { operator: { "roles": role_oid } };
+    // Given a role, a temporary member stays on
+    if operator == "$addToSet" {
+        update.insert("$set", doc! { "temporary": false });
+    }
+    let member = members
+        .find_one_and_update(doc! { "server_id": server_oid, "user_id": member_oid }, update, options)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    publish(hub, server_oid, ServerEvent::MemberUpdate(member)).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
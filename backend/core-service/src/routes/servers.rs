@@ -1,16 +1,71 @@
-use axum::{extract::State, http::StatusCode, Json};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Extension, Json,
+};
 use futures_util::TryStreamExt;
-use mongodb::{bson::doc, Collection};
+use mongodb::{
+    bson::{doc, oid::ObjectId, Bson, Document},
+    options::{FindOneAndUpdateOptions, ReturnDocument},
+    Collection,
+};
 
-use crate::{auth::AuthUser, models::*};
+use common::{
+    auth::Scope,
+    permissions::{self, MANAGE_SERVER},
+};
+
+use crate::{
+    access,
+    auth::AuthUser,
+    cache::CacheManager,
+    gateway::{
+        hub::Hub,
+        protocol::{self, ServerEvent},
+        topics::server_topic,
+    },
+    membership,
+    models::*,
+    routes::channels,
+};
+
+const MAX_SERVER_NAME_LENGTH: usize = 100;
+const MAX_DESCRIPTION_LENGTH: usize = 1024;
+const MAX_ICON_URL_LENGTH: usize = 2048;
+
+// Names are stored trimmed, and can't be empty
+fn server_name(name: &str) -> Result<String, StatusCode> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_SERVER_NAME_LENGTH {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    Ok(name.to_string())
+}
+
+// Empty descriptions are stored as none
+fn description(description: &str) -> Result<Option<String>, StatusCode> {
+    let description = description.trim();
+    if description.chars().count() > MAX_DESCRIPTION_LENGTH {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    Ok((!description.is_empty()).then(|| description.to_string()))
+}
 
 pub async fn list_servers(
     State((db, _redis)): State<(mongodb::Database, redis::Client)>,
-    _user: AuthUser,
+    user: AuthUser,
 ) -> Result<Json<Vec<Server>>, StatusCode> {
+    user.require(Scope::ServersRead)?;
+    let user_oid = parse_id(&user.user_id)?;
+    let server_ids: Vec<_> = membership::server_ids_for_user(&db, user_oid)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .into_iter()
+        .collect();
+
     let servers: Collection<Server> = db.collection("servers");
     let cursor = servers
-        .find(doc! {}, None)
+        .find(doc! { "_id": { "$in": server_ids } }, None)
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     
@@ -24,28 +79,163 @@ pub async fn list_servers(
 
 pub async fn create_server(
     State((db, _redis)): State<(mongodb::Database, redis::Client)>,
+    Extension(hub): Extension<Hub>,
     user: AuthUser,
     Json(payload): Json<CreateServerRequest>,
 ) -> Result<Json<Server>, StatusCode> {
-    let owner_id = mongodb::bson::oid::ObjectId::parse_str(&user.0)
-        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    user.require(Scope::ServersWrite)?;
+    let owner_id = parse_id(&user.user_id)?;
+    let server_id = ObjectId::new();
     
     let server = Server {
-        id: None,
-        name: payload.name,
+        id: Some(server_id),
+        name: server_name(&payload.name)?,
+        description: payload.description.as_deref().map(description).transpose()?.flatten(),
+        icon_url: None,
         owner_id,
-        invite_code: nanoid::nanoid!(10),
+        roles: vec![permissions::new_everyone_role(server_id)],
         created_at: chrono::Utc::now(),
     };
     
     let servers: Collection<Server> = db.collection("servers");
-    let result = servers
+    servers
         .insert_one(&server, None)
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    
-    let mut server = server;
-    server.id = Some(result.inserted_id.as_object_id().unwrap());
+
+    // The owner is the server's first member
+    let owner = membership::add_member(&db, server_id, owner_id, false)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if let Some(owner) = owner {
+        membership::announce_join(&hub, &server, &owner)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
     
     Ok(Json(server))
 }
+
+// Renaming the server and changing its description or icon take MANAGE_SERVER; only the owner
+// may hand it to another member
+pub async fn update_server(
+    State((db, _redis)): State<(mongodb::Database, redis::Client)>,
+    Extension(hub): Extension<Hub>,
+    Path(server_id): Path<String>,
+    user: AuthUser,
+    Json(payload): Json<UpdateServerRequest>,
+) -> Result<Json<Server>, StatusCode> {
+    user.require(Scope::ServersWrite)?;
+    let server_oid = parse_id(&server_id)?;
+    let user_oid = parse_id(&user.user_id)?;
+
+    let mut changes = Document::new();
+    if let Some(name) = &payload.name {
+        changes.insert("name", server_name(name)?);
+    }
+    if let Some(text) = &payload.description {
+        changes.insert("description", description(text)?.map_or(Bson::Null, Bson::String));
+    }
+    if let Some(icon_url) = &payload.icon_url {
+        if icon_url.is_empty() {
+            changes.insert("icon_url", Bson::Null);
+        } else if icon_url.len() <= MAX_ICON_URL_LENGTH
+            && (icon_url.starts_with("https://") || icon_url.starts_with("http://"))
+        {
+            changes.insert("icon_url", icon_url.as_str());
+        } else {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+    }
+    let required = if changes.is_empty() { 0 } else { MANAGE_SERVER };
+
+    let server = access::require_server(&db, server_oid, user_oid, required).await?;
+    if let Some(owner_id) = &payload.owner_id {
+        let owner_oid = parse_id(owner_id)?;
+        if server.owner_id != user_oid {
+            return Err(StatusCode::FORBIDDEN);
+        }
+        // The new owner must already be a member
+        access::member(&db, server_oid, owner_oid)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .ok_or(StatusCode::BAD_REQUEST)?;
+        changes.insert("owner_id", owner_oid);
+    }
+    if changes.is_empty() {
+        return Ok(Json(server));
+    }
+
+    let servers: Collection<Server> = db.collection("servers");
+    let options = FindOneAndUpdateOptions::builder()
+        .return_document(ReturnDocument::After)
+        .build();
+    let server = servers
+        .find_one_and_update(doc! { "_id": server_oid }, doc! { "$set": changes }, options)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    hub.publish(&server_topic(&server_oid), ServerEvent::ServerUpdate(server.clone()))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(server))
+}
+
+// Only the owner may delete a server. Its channels, their messages and read states, its
+// invites and its memberships go with it.
+pub async fn delete_server(
+    State((db, _redis)): State<(mongodb::Database, redis::Client)>,
+    Extension(hub): Extension<Hub>,
+    Extension(mut cache): Extension<CacheManager>,
+    Path(server_id): Path<String>,
+    user: AuthUser,
+) -> Result<StatusCode, StatusCode> {
+    user.require(Scope::ServersWrite)?;
+    let server_oid = parse_id(&server_id)?;
+    let user_oid = parse_id(&user.user_id)?;
+
+    let server = access::require_server(&db, server_oid, user_oid, 0).await?;
+    if server.owner_id != user_oid {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    // The server goes first, so nothing can be added to it while the rest is deleted
+    let servers: Collection<Server> = db.collection("servers");
+    servers
+        .delete_one(doc! { "_id": server_oid }, None)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let channels: Collection<Channel> = db.collection("channels");
+    let channel_ids: Vec<ObjectId> = channels
+        .distinct("_id", doc! { "server_id": server_oid }, None)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .iter()
+        .filter_map(Bson::as_object_id)
+        .collect();
+    channels
+        .delete_many(doc! { "server_id": server_oid }, None)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    channels::delete_channel_data(&db, &channel_ids)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    // Invites from before channels were targeted belong to no channel
+    for collection in ["invites", "members"] {
+        db.collection::<Document>(collection)
+            .delete_many(doc! { "server_id": server_oid }, None)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+    cache.invalidate_channels(&server_oid).await;
+
+    let event = ServerEvent::ServerDelete(protocol::ServerDelete { server_id: server_id.clone() });
+    hub.publish(&server_topic(&server_oid), event)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
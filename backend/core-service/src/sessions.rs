@@ -0,0 +1,54 @@
+use common::auth::{ACCESS_TOKEN_TTL_SECONDS, SESSION_ACTIVE, SESSION_REVOKED};
+use mongodb::{
+    bson::{doc, oid::ObjectId, Document},
+    options::FindOneOptions,
+    Collection, Database,
+};
+
+use crate::cache::CacheManager;
+
+// How long a session is cached as active. auth-service marks the session revoked in the cache
+// when it revokes it, so this only bounds staleness if that fails.
+const ACTIVE_SESSION_TTL_SECONDS: u64 = 300;
+
+// Whether the session an access token was issued for has been revoked, from the cache or from
+// auth-service's sessions collection
+pub async fn is_revoked(
+    db: &Database,
+    cache: &mut CacheManager,
+    user_id: &str,
+    session_id: &str,
+) -> mongodb::error::Result<bool> {
+    // The cache is only an optimization; fall back to Mongo if Redis is unavailable
+    if let Ok(Some(state)) = cache.get_session_state(session_id).await {
+        return Ok(state == SESSION_REVOKED);
+    }
+
+    let (Ok(user_oid), Ok(session_oid)) =
+        (ObjectId::parse_str(user_id), ObjectId::parse_str(session_id))
+    else {
+        return Ok(false);
+    };
+    let sessions: Collection<Document> = db.collection("sessions");
+    let options = FindOneOptions::builder().projection(doc! { "_id": 1 }).build();
+    let revoked = sessions
+        .find_one(
+            doc! { "_id": session_oid, "user_id": user_oid, "revoked_at": { "$ne": null } },
+            options,
+        )
+        .await?
+        .is_some();
+
+    // Tokens of a revoked session expire within ACCESS_TOKEN_TTL_SECONDS, so it needn't be
+    // remembered any longer
+    let (state, ttl) = if revoked {
+        (SESSION_REVOKED, ACCESS_TOKEN_TTL_SECONDS)
+    } else {
+        (SESSION_ACTIVE, ACTIVE_SESSION_TTL_SECONDS)
+    };
+    if let Err(err) = cache.cache_session_state(session_id, state, ttl).await {
+        eprintln!("⚠️ Failed to cache the state of session {}: {}", session_id, err);
+    }
+
+    Ok(revoked)
+}
@@ -0,0 +1,71 @@
+use chrono::Utc;
+use common::{
+    auth::hash_token,
+    models::{ApiToken, ApiTokenKind},
+};
+use mongodb::{
+    bson::{doc, oid::ObjectId},
+    Collection, Database,
+};
+
+// Last use of a token is recorded at most this often, so busy clients don't write on
+// every request
+const LAST_USED_RESOLUTION_SECONDS: i64 = 60;
+
+// The bot token or personal access token auth-service issued with this value, unless it
+// was deleted or has expired
+pub async fn find_active(
+    db: &Database,
+    token: &str,
+    kind: ApiTokenKind,
+) -> mongodb::error::Result<Option<ApiToken>> {
+    let tokens: Collection<ApiToken> = db.collection("api_tokens");
+    let Some(found) = tokens
+        .find_one(doc! { "token_hash": hash_token(token) }, None)
+        .await?
+    else {
+        return Ok(None);
+    };
+    if found.kind != kind || found.is_expired(Utc::now()) {
+        return Ok(None);
+    }
+
+    record_use(db, &found);
+    Ok(Some(found))
+}
+
+// Whether a token that authenticated a gateway connection was since deleted or expired
+pub async fn is_revoked(db: &Database, token_id: ObjectId) -> mongodb::error::Result<bool> {
+    let tokens: Collection<ApiToken> = db.collection("api_tokens");
+    let found = tokens.find_one(doc! { "_id": token_id }, None).await?;
+    Ok(found.is_none_or(|token| token.is_expired(Utc::now())))
+}
+
+fn record_use(db: &Database, token: &ApiToken) {
+    let now = Utc::now();
+    let recent = token
+        .last_used_at
+        .is_some_and(|at| (now - at).num_seconds() < LAST_USED_RESOLUTION_SECONDS);
+    let Some(token_id) = token.id.filter(|_| !recent) else {
+        return;
+    };
+
+    let tokens: Collection<ApiToken> = db.collection("api_tokens");
+    tokio::spawn(async move {
+        let result = match mongodb::bson::to_bson(&now) {
+            Ok(now) => tokens
+                .update_one(
+                    doc! { "_id": token_id },
+                    doc! { "$set": { "last_used_at": now } },
+                    None,
+                )
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        if let Err(err) = result {
+            eprintln!("⚠️ Failed to record use of API token {}: {}", token_id, err);
+        }
+    });
+}
@@ -1,93 +1,986 @@
 use axum::{
     extract::{
-        ws::{Message, WebSocket, WebSocketUpgrade},
-        State,
+        ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        Extension, State,
     },
-    response::{IntoResponse, Response},
+    response::IntoResponse,
     http::StatusCode,
 };
-use chrono;
-use futures_util::{SinkExt, StreamExt};
-use redis::AsyncCommands;
+use futures_util::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt, TryStreamExt,
+};
+use mongodb::{
+    bson::{doc, oid::ObjectId},
+    options::UpdateOptions,
+    Collection,
+};
+use redis::aio::ConnectionManager;
+use serde::Deserialize;
+use tokio::sync::mpsc;
 
 use axum::extract::Query;
-use std::collections::HashMap;
-use jsonwebtoken::{decode, DecodingKey, Validation, Algorithm};
-use crate::auth::Claims;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
+use crate::auth::{verify_token, Credential};
+use common::{
+    auth::Claims,
+    models::ApiTokenKind,
+    permissions::{self, has, SEND_MESSAGES, VIEW_CHANNEL},
+};
+use crate::gateway::{
+    self,
+    encoding::{Compression, Encoding, FrameEncoder},
+    error::{CloseCode, GatewayError},
+    hub::{Delivery, Hub, Subscription},
+    presence::{self, Presence},
+    protocol::{
+        ChannelDelete, ClientOp, ClientProperties, Dispatch, ErrorCode, ErrorPayload, Hello, PresenceStatus,
+        PresenceUpdate, Ready, RoleDelete, RoleEvent, ServerDelete, ServerEvent, ServerLeave,
+    },
+    replay::{self, SessionState},
+    topics::{channel_topic, server_topic, user_topic},
+    typing,
+    HEARTBEAT_INTERVAL, HEARTBEAT_TIMEOUT, IDENTIFY_TIMEOUT, RATE_LIMIT_OPS, RATE_LIMIT_WINDOW,
+};
+use crate::{access, cache::CacheManager, jwks::JwksCache, membership, models, tokens};
+
+type GatewayResult<T = ()> = Result<T, GatewayError>;
+
+// Topic events queued for a session before its subscriptions apply backpressure
+const INBOX_CAPACITY: usize = 256;
+
+// Query string accepted when opening the gateway socket
+#[derive(Debug, Deserialize)]
+pub struct GatewayParams {
+    token: Option<String>,
+    #[serde(default)]
+    encoding: Encoding,
+    compress: Option<Compression>,
+}
 
 pub async fn ws_handler(
     ws: WebSocketUpgrade,
-    Query(params): Query<HashMap<String, String>>,
-    State((_db, redis_client)): State<(mongodb::Database, redis::Client)>,
+    Query(params): Query<GatewayParams>,
+    State((db, _redis)): State<(mongodb::Database, redis::Client)>,
+    Extension(hub): Extension<Hub>,
+    Extension(mut cache): Extension<CacheManager>,
+    Extension(keys): Extension<JwksCache>,
 ) -> impl IntoResponse {
-    let token = params.get("token").cloned();
-    let mut user_id = None;
+    // A token in the query string authenticates the socket at upgrade. Without one,
+    // the socket upgrades unauthenticated and the client must send IDENTIFY.
+    let identity = match params.token.as_deref() {
+        Some(token) => match identify_token(&keys, &db, token).await {
+            Some(identity) => Some(identity),
+            None => return StatusCode::UNAUTHORIZED.into_response(),
+        },
+        None => None,
+    };
+    if let Some(identity) = &identity {
+        match identity.revoked(&db, &mut cache).await {
+            Ok(false) => {}
+            Ok(true) => return StatusCode::UNAUTHORIZED.into_response(),
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+
+    let encoder = FrameEncoder::new(params.encoding, params.compress);
+    ws.on_upgrade(move |socket| handle_socket(socket, db, hub, cache, keys, identity, encoder))
+}
+
+// User a token was issued to, and when it expires
+struct Identity {
+    user_id: ObjectId,
+    // Auth session or bot token the socket authenticated with
+    credential: Credential,
+    expires_at: usize,
+}
+
+impl Identity {
+    // Whether the user revoked the auth session the token belongs to, or the bot token was deleted
+    async fn revoked(&self, db: &mongodb::Database, cache: &mut CacheManager) -> mongodb::error::Result<bool> {
+        self.credential.revoked(db, cache, &self.user_id).await
+    }
+}
+
+// Accepts an access token, or a bot token as `Bot <token>`. Personal access tokens are
+// for the REST API only.
+async fn identify_token(keys: &JwksCache, db: &mongodb::Database, token: &str) -> Option<Identity> {
+    if let Some(token) = token.strip_prefix("Bot ") {
+        let token = tokens::find_active(db, token.trim(), ApiTokenKind::Bot).await.ok()??;
+        let expires_at = token.expires_at.map_or(usize::MAX, |at| at.timestamp() as usize);
+        return Some(Identity {
+            user_id: token.user_id,
+            credential: Credential::ApiToken(token.id?),
+            expires_at,
+        });
+    }
+
+    let claims = verify_token(keys, token).await.ok()?;
+    let user_id = claims.user_id().ok()?;
+    let Claims { sid, exp, .. } = claims;
+    Some(Identity { user_id, credential: Credential::Session(sid), expires_at: exp })
+}
+
+// How a socket opened without a token authenticated itself
+enum Handshake {
+    Identify {
+        identity: Identity,
+        properties: ClientProperties,
+        capabilities: Vec<String>,
+    },
+    Resume {
+        identity: Identity,
+        session_id: String,
+        seq: u64,
+    },
+}
+
+async fn handle_socket(
+    socket: WebSocket,
+    db: mongodb::Database,
+    hub: Hub,
+    mut cache: CacheManager,
+    keys: JwksCache,
+    identity: Option<Identity>,
+    mut encoder: FrameEncoder,
+) {
+    let (mut sender, mut receiver) = socket.split();
+
+    let hello = ServerEvent::Hello(Hello {
+        heartbeat_interval: HEARTBEAT_INTERVAL.as_millis() as u64,
+    });
+    if send_frame(&mut sender, &mut encoder, &hello).await.is_err() {
+        return;
+    }
 
-    if let Some(token_str) = token {
-        if let Ok(secret) = std::env::var("JWT_SECRET") {
-            if let Ok(token_data) = decode::<Claims>(
-                &token_str,
-                &DecodingKey::from_secret(secret.as_ref()),
-                &Validation::new(Algorithm::HS256),
-            ) {
-                user_id = Some(token_data.claims.sub);
+    // Sockets authenticated by the query string may still RESUME as their first op
+    let authenticated_at_upgrade = identity.is_some();
+    let handshake = match identity {
+        Some(identity) => Handshake::Identify {
+            identity,
+            properties: ClientProperties::default(),
+            capabilities: Vec::new(),
+        },
+        None => match await_handshake(&mut sender, &mut receiver, &mut encoder, &keys, &db).await {
+            Ok(handshake) => handshake,
+            Err(code) => {
+                let _ = close(&mut sender, code).await;
+                return;
             }
+        },
+    };
+    let (identity, properties, capabilities, resume) = match handshake {
+        Handshake::Identify { identity, properties, capabilities } => {
+            (identity, properties, capabilities, None)
+        }
+        Handshake::Resume { identity, session_id, seq } => {
+            (identity, ClientProperties::default(), Vec::new(), Some((session_id, seq)))
+        }
+    };
+
+    // Tokens sent in IDENTIFY or RESUME are only checked for revocation here
+    match identity.revoked(&db, &mut cache).await {
+        Ok(false) => {}
+        Ok(true) => {
+            let _ = close(&mut sender, CloseCode::AuthenticationFailed).await;
+            return;
+        }
+        Err(err) => {
+            eprintln!("⚠️ Gateway connection for {} failed to start: {}", identity.user_id, err);
+            let _ = close(&mut sender, CloseCode::InternalError).await;
+            return;
         }
     }
 
-    if user_id.is_none() {
-        return StatusCode::UNAUTHORIZED.into_response();
+    let servers = match membership::server_ids_for_user(&db, identity.user_id).await {
+        Ok(servers) => servers,
+        Err(err) => {
+            eprintln!("⚠️ Gateway connection for {} failed to start: {}", identity.user_id, err);
+            let _ = close(&mut sender, CloseCode::InternalError).await;
+            return;
+        }
+    };
+    let (inbox_sender, inbox) = mpsc::channel(INBOX_CAPACITY);
+
+    let mut connection = Connection {
+        db,
+        cache,
+        conn: hub.connection(),
+        hub,
+        sender,
+        encoder,
+        inbox: inbox_sender,
+        subscriptions: HashMap::new(),
+        session_id: nanoid::nanoid!(),
+        socket_id: nanoid::nanoid!(),
+        user_id: identity.user_id,
+        credential: identity.credential,
+        token_expires_at: identity.expires_at,
+        seq: 0,
+        servers,
+        joined: HashMap::new(),
+        cursors: HashMap::new(),
+        properties,
+        capabilities,
+        handled_op: !authenticated_at_upgrade,
+        last_heartbeat: Instant::now(),
+        window_start: Instant::now(),
+        window_ops: 0,
+    };
+
+    let result = connection.run(resume, receiver, inbox).await;
+
+    let close_code = match &result {
+        Ok(()) => None,
+        Err(err) => {
+            if let GatewayError::Redis(_) | GatewayError::Mongo(_) = err {
+                eprintln!("⚠️ Gateway connection for {} failed: {}", connection.user_id, err);
+            }
+            err.close_code()
+        }
+    };
+    if let Some(code) = close_code {
+        let _ = close(&mut connection.sender, code).await;
     }
 
-    ws.on_upgrade(move |socket| handle_socket(socket, redis_client, user_id.unwrap()))
+    // Keep the session around for RESUME unless the close rules it out
+    let stored = if close_code.is_none_or(CloseCode::can_resume) {
+        connection.suspend().await
+    } else {
+        SessionState::delete(&mut connection.conn, &connection.session_id).await
+    };
+    if let Err(err) = stored {
+        eprintln!("⚠️ Failed to store gateway session {}: {}", connection.session_id, err);
+    }
+
+    if let Err(err) = connection.disconnect_presence().await {
+        eprintln!("⚠️ Failed to update presence for {}: {}", connection.user_id, err);
+    }
 }
 
-async fn handle_socket(socket: WebSocket, redis_client: redis::Client, user_id: String) {
-    let (mut sender, mut receiver) = socket.split();
+// Waits for the IDENTIFY or RESUME that must open a socket upgraded without a token
+async fn await_handshake(
+    sender: &mut SplitSink<WebSocket, Message>,
+    receiver: &mut SplitStream<WebSocket>,
+    encoder: &mut FrameEncoder,
+    keys: &JwksCache,
+    db: &mongodb::Database,
+) -> Result<Handshake, CloseCode> {
+    let deadline = tokio::time::Instant::now() + IDENTIFY_TIMEOUT;
+    loop {
+        let parsed = match tokio::time::timeout_at(deadline, receiver.next()).await {
+            Ok(Some(Ok(Message::Text(text)))) => ClientOp::parse(&text),
+            Ok(Some(Ok(Message::Binary(bytes)))) if encoder.encoding() == Encoding::Msgpack => {
+                ClientOp::parse_msgpack(&bytes)
+            }
+            Ok(Some(Ok(Message::Binary(_)))) => return Err(CloseCode::InvalidPayload),
+            Ok(Some(Ok(_))) => continue,
+            Ok(Some(Err(_)) | None) | Err(_) => return Err(CloseCode::NotAuthenticated),
+        };
 
-    // Subscribe to Redis channel for real-time messages
-    let mut pubsub_conn = redis_client
-        .get_async_connection()
+        match parsed {
+            Ok(ClientOp::Heartbeat { .. }) => {
+                send_frame(sender, encoder, &ServerEvent::HeartbeatAck)
+                    .await
+                    .map_err(|_| CloseCode::NotAuthenticated)?;
+            }
+            Ok(ClientOp::Identify { token, properties, capabilities }) => {
+                let identity = identify_token(keys, db, &token)
+                    .await
+                    .ok_or(CloseCode::AuthenticationFailed)?;
+                return Ok(Handshake::Identify { identity, properties, capabilities });
+            }
+            Ok(ClientOp::Resume { token: Some(token), session_id, seq }) => {
+                let identity = identify_token(keys, db, &token)
+                    .await
+                    .ok_or(CloseCode::AuthenticationFailed)?;
+                return Ok(Handshake::Resume { identity, session_id, seq });
+            }
+            Ok(_) => return Err(CloseCode::NotAuthenticated),
+            Err(_) => return Err(CloseCode::InvalidPayload),
+        }
+    }
+}
+
+async fn send_frame(
+    sender: &mut SplitSink<WebSocket, Message>,
+    encoder: &mut FrameEncoder,
+    event: &ServerEvent,
+) -> Result<(), axum::Error> {
+    sender.send(encoder.encode(event)).await
+}
+
+async fn close(sender: &mut SplitSink<WebSocket, Message>, code: CloseCode) -> Result<(), axum::Error> {
+    sender
+        .send(Message::Close(Some(CloseFrame {
+            code: code.code(),
+            reason: code.reason().into(),
+        })))
         .await
-        .unwrap()
-        .into_pubsub();
-    pubsub_conn.subscribe("chat:messages").await.unwrap();
-    let mut pubsub_stream = pubsub_conn.on_message();
+}
 
-    // Loop to forward Redis messages to WebSocket and WebSocket messages to Redis
-    let mut conn = redis_client.get_async_connection().await.unwrap();
-    loop {
-        tokio::select! {
-            // Redis -> WebSocket
-            maybe_msg = pubsub_stream.next() => {
-                if let Some(msg) = maybe_msg {
-                    if let Ok(payload) = msg.get_payload::<String>() {
-                        if sender.send(Message::Text(payload)).await.is_err() {
-                            break;
+// A gateway session bound to a socket
+struct Connection {
+    db: mongodb::Database,
+    cache: CacheManager,
+    hub: Hub,
+    conn: ConnectionManager,
+    sender: SplitSink<WebSocket, Message>,
+    encoder: FrameEncoder,
+    // Where the session's hub subscriptions deliver topic events
+    inbox: mpsc::Sender<Delivery>,
+    subscriptions: HashMap<String, Subscription>,
+    session_id: String,
+    // Identifies the socket among the user's connected sessions; unlike the
+    // session id it doesn't change when the socket resumes another session
+    socket_id: String,
+    user_id: ObjectId,
+    // Auth session or bot token that opened the socket
+    credential: Credential,
+    // Unix time the token that opened the socket expires at
+    token_expires_at: usize,
+    // Sequence number of the last event dispatched to the client
+    seq: u64,
+    servers: HashSet<ObjectId>,
+    // Joined channels, and the server each belongs to
+    joined: HashMap<ObjectId, ObjectId>,
+    // Last topic log entry delivered, per topic
+    cursors: HashMap<String, String>,
+    properties: ClientProperties,
+    capabilities: Vec<String>,
+    // RESUME is only accepted as the first op on a socket
+    handled_op: bool,
+    last_heartbeat: Instant,
+    // Rate limit window for client ops
+    window_start: Instant,
+    window_ops: u32,
+}
+
+impl Connection {
+    async fn run(
+        &mut self,
+        resume: Option<(String, u64)>,
+        mut receiver: SplitStream<WebSocket>,
+        mut inbox: mpsc::Receiver<Delivery>,
+    ) -> GatewayResult {
+        // Subscribe to the user's topic and those of every server they belong to
+        for topic in self.topics() {
+            self.subscribe(topic).await?;
+        }
+        self.connect_presence().await?;
+
+        let resumed = match resume {
+            Some((session_id, seq)) => self.resume(session_id, seq).await?,
+            None => false,
+        };
+        if !resumed {
+            self.send_ready().await?;
+        }
+
+        let mut heartbeat_check = tokio::time::interval(HEARTBEAT_INTERVAL / 2);
+        let shutdown = gateway::shutting_down();
+        tokio::pin!(shutdown);
+
+        // Loop to forward Redis events to the WebSocket and client ops to Redis
+        loop {
+            tokio::select! {
+                _ = &mut shutdown => return Err(CloseCode::ServerRestarting.into()),
+
+                // Tear down sockets whose client stopped heartbeating or whose token expired
+                _ = heartbeat_check.tick() => {
+                    if self.last_heartbeat.elapsed() > HEARTBEAT_TIMEOUT {
+                        return Err(CloseCode::SessionTimedOut.into());
+                    }
+                    if chrono::Utc::now().timestamp() as usize >= self.token_expires_at {
+                        return Err(CloseCode::SessionInvalidated.into());
+                    }
+                    if self.credential_revoked().await? {
+                        return Err(CloseCode::SessionInvalidated.into());
+                    }
+                }
+
+                // Hub -> WebSocket
+                Some(delivery) = inbox.recv() => match delivery {
+                    Delivery::Event { topic, message } => {
+                        // Skip events still queued for a topic the session has left
+                        if self.subscriptions.contains_key(&topic) {
+                            self.deliver(&topic, message.id.clone(), message.event.clone()).await?;
                         }
                     }
-                } else {
-                    break;
+                    Delivery::Lagged { topic } => self.catch_up(&topic).await?,
+                    Delivery::Closed => return Err(CloseCode::InternalError.into()),
+                },
+
+                // WebSocket -> Redis
+                maybe_ws = receiver.next() => {
+                    match maybe_ws {
+                        Some(Ok(Message::Text(text))) => self.handle_op(ClientOp::parse(&text)).await?,
+                        Some(Ok(Message::Binary(bytes))) if self.encoder.encoding() == Encoding::Msgpack => {
+                            self.handle_op(ClientOp::parse_msgpack(&bytes)).await?
+                        }
+                        Some(Ok(Message::Binary(_))) => return Err(CloseCode::InvalidPayload.into()),
+                        Some(Ok(Message::Close(_))) | None => return Ok(()),
+                        Some(Ok(_)) => {}
+                        Some(Err(err)) => return Err(err.into()),
+                    }
                 }
             }
+        }
+    }
 
-            // WebSocket -> Redis
-            maybe_ws = receiver.next() => {
-                match maybe_ws {
-                    Some(Ok(Message::Text(text))) => {
-                        let chat_msg = serde_json::json!({
-                            "user_id": user_id,
-                            "content": text,
-                            "created_at": chrono::Utc::now(),
-                        });
-                        if let Ok(msg_json) = serde_json::to_string(&chat_msg) {
-                            let _: () = conn.publish("chat:messages", msg_json).await.unwrap();
-                        }
+    fn topics(&self) -> Vec<String> {
+        std::iter::once(user_topic(&self.user_id))
+            .chain(self.servers.iter().map(server_topic))
+            .chain(self.joined.keys().map(channel_topic))
+            .collect()
+    }
+
+    async fn subscribe(&mut self, topic: String) -> GatewayResult {
+        if self.subscriptions.contains_key(&topic) {
+            return Ok(());
+        }
+        let subscription = self.hub.subscribe(topic.clone(), self.inbox.clone()).await?;
+        self.subscriptions.insert(topic.clone(), subscription);
+        if !self.cursors.contains_key(&topic) {
+            // Anything logged after this point is new to the session
+            let latest = replay::latest_topic_id(&mut self.conn, &topic).await?;
+            self.cursors.insert(topic, latest.unwrap_or_else(|| "0-0".to_string()));
+        }
+        Ok(())
+    }
+
+    async fn unsubscribe(&mut self, topic: String) -> GatewayResult {
+        self.subscriptions.remove(&topic);
+        self.cursors.remove(&topic);
+        Ok(())
+    }
+
+    async fn send_event(&mut self, event: &ServerEvent) -> GatewayResult {
+        Ok(send_frame(&mut self.sender, &mut self.encoder, event).await?)
+    }
+
+    async fn send_error(&mut self, err: ErrorPayload) -> GatewayResult {
+        self.send_event(&ServerEvent::Error(err)).await
+    }
+
+    // Delivers an event read from a topic, skipping anything at or before the topic's cursor
+    async fn deliver(&mut self, topic: &str, id: String, event: ServerEvent) -> GatewayResult {
+        if let Some(cursor) = self.cursors.get(topic) {
+            if replay::compare_stream_ids(&id, cursor) != Ordering::Greater {
+                return Ok(());
+            }
+        }
+        self.cursors.insert(topic.to_string(), id);
+        match self.apply(event).await? {
+            Some(event) => self.dispatch(event).await,
+            None => Ok(()),
+        }
+    }
+
+    // Updates the session for an event before it is dispatched, and returns what the client
+    // should see of it, if anything
+    async fn apply(&mut self, event: ServerEvent) -> GatewayResult<Option<ServerEvent>> {
+        match &event {
+            ServerEvent::ServerJoin(server) => {
+                if let Some(server_id) = server.id {
+                    if self.servers.insert(server_id) {
+                        self.subscribe(server_topic(&server_id)).await?;
+                    }
+                }
+            }
+            ServerEvent::ServerLeave(ServerLeave { server_id })
+            | ServerEvent::ServerDelete(ServerDelete { server_id }) => {
+                if let Ok(server_id) = ObjectId::parse_str(server_id) {
+                    self.leave_server(server_id).await?;
+                }
+            }
+            // Events that may take away the user's access to channels they joined
+            ServerEvent::RoleUpdate(RoleEvent { server_id, .. })
+            | ServerEvent::RoleDelete(RoleDelete { server_id, .. }) => {
+                if let Ok(server_id) = ObjectId::parse_str(server_id) {
+                    self.recheck_server(server_id).await?;
+                }
+            }
+            ServerEvent::MemberUpdate(member) if member.user_id == self.user_id => {
+                self.recheck_server(member.server_id).await?;
+            }
+            ServerEvent::ServerUpdate(server) => {
+                if let Some(server_id) = server.id {
+                    self.recheck_server(server_id).await?;
+                }
+            }
+            // Members who can't view the channel only learn it is gone for them
+            ServerEvent::ChannelUpdate(channel) => {
+                let Some(channel_id) = channel.id else {
+                    return Ok(None);
+                };
+                return match self.authorize_channel(channel_id, VIEW_CHANNEL).await {
+                    Ok(_) => Ok(Some(event)),
+                    // Withheld rather than risk showing a channel the user can't view
+                    Err(err) if err.code == ErrorCode::Internal => Ok(None),
+                    Err(_) => {
+                        self.leave_channel(channel_id).await?;
+                        let deleted = ChannelDelete::new(channel_id, channel.server_id);
+                        Ok(Some(ServerEvent::ChannelDelete(deleted)))
+                    }
+                };
+            }
+            ServerEvent::ChannelDelete(ChannelDelete { id, .. }) => {
+                if let Ok(channel_id) = ObjectId::parse_str(id) {
+                    self.leave_channel(channel_id).await?;
+                }
+            }
+            _ => {}
+        }
+        Ok(Some(event))
+    }
+
+    // Leaves the channels joined in the server that the user may no longer view
+    async fn recheck_server(&mut self, server_id: ObjectId) -> GatewayResult {
+        for channel_id in self.joined_in(server_id) {
+            self.recheck_channel(channel_id).await?;
+        }
+        Ok(())
+    }
+
+    async fn recheck_channel(&mut self, channel_id: ObjectId) -> GatewayResult {
+        if !self.joined.contains_key(&channel_id) {
+            return Ok(());
+        }
+        match self.authorize_channel(channel_id, VIEW_CHANNEL).await {
+            // A failed lookup says nothing about the user's access, so they stay in
+            Ok(_) => Ok(()),
+            Err(err) if err.code == ErrorCode::Internal => Ok(()),
+            Err(_) => self.leave_channel(channel_id).await,
+        }
+    }
+
+    // Stops following a server the user is no longer in, and the channels joined in it
+    async fn leave_server(&mut self, server_id: ObjectId) -> GatewayResult {
+        if self.servers.remove(&server_id) {
+            self.unsubscribe(server_topic(&server_id)).await?;
+        }
+        for channel_id in self.joined_in(server_id) {
+            self.leave_channel(channel_id).await?;
+        }
+        Ok(())
+    }
+
+    fn joined_in(&self, server_id: ObjectId) -> Vec<ObjectId> {
+        self.joined
+            .iter()
+            .filter(|(_, joined_server)| **joined_server == server_id)
+            .map(|(channel_id, _)| *channel_id)
+            .collect()
+    }
+
+    async fn leave_channel(&mut self, channel_id: ObjectId) -> GatewayResult {
+        if self.joined.remove(&channel_id).is_some() {
+            self.unsubscribe(channel_topic(&channel_id)).await?;
+        }
+        Ok(())
+    }
+
+    // Delivers everything logged on a topic after the session's cursor
+    async fn catch_up(&mut self, topic: &str) -> GatewayResult {
+        let Some(cursor) = self.cursors.get(topic).cloned() else {
+            return Ok(());
+        };
+        let missed = replay::topic_log_since(&mut self.conn, topic, &cursor).await?;
+        for (id, payload) in missed {
+            if let Ok(event) = serde_json::from_str::<ServerEvent>(&payload) {
+                self.deliver(topic, id, event).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // Assigns the next sequence number, buffers the frame for resume and sends it
+    async fn dispatch(&mut self, event: ServerEvent) -> GatewayResult {
+        self.seq += 1;
+        let dispatch = Dispatch { seq: self.seq, event };
+        // Buffered as JSON whatever the connection's encoding, so any client can resume it
+        let frame = serde_json::to_string(&dispatch).expect("gateway events are serializable");
+        replay::buffer_dispatch(&mut self.conn, &self.session_id, self.seq, &frame).await?;
+        self.sender.send(self.encoder.encode(&dispatch)).await?;
+        Ok(())
+    }
+
+    // Counts a client op against the rate limit window
+    fn check_rate_limit(&mut self) -> GatewayResult {
+        if self.window_start.elapsed() > RATE_LIMIT_WINDOW {
+            self.window_start = Instant::now();
+            self.window_ops = 0;
+        }
+        self.window_ops += 1;
+        if self.window_ops > RATE_LIMIT_OPS {
+            return Err(CloseCode::RateLimited.into());
+        }
+        Ok(())
+    }
+
+    async fn handle_op(&mut self, parsed: Result<ClientOp, ErrorPayload>) -> GatewayResult {
+        let parsed = parsed
+            .and_then(|op| op.validate().map(|channel_id| (op, channel_id)));
+        let (op, channel_id) = match parsed {
+            Ok(parsed) => parsed,
+            Err(err) => return self.send_error(err).await,
+        };
+
+        match op {
+            ClientOp::Heartbeat { .. } => {
+                self.last_heartbeat = Instant::now();
+                presence::refresh(&mut self.conn, &self.user_id, &self.socket_id).await?;
+                return self.send_event(&ServerEvent::HeartbeatAck).await;
+            }
+            ClientOp::Identify { .. } => return Err(CloseCode::AlreadyAuthenticated.into()),
+            _ => self.check_rate_limit()?,
+        }
+
+        // Channel ops need permissions in the channel; leaving one never does
+        let required = match &op {
+            ClientOp::SendMessage { .. } | ClientOp::Typing { .. } => Some(VIEW_CHANNEL | SEND_MESSAGES),
+            ClientOp::LeaveChannel { .. } => None,
+            _ => Some(VIEW_CHANNEL),
+        };
+        let mut channel_server = None;
+        if let (Some(channel_id), Some(required)) = (channel_id, required) {
+            match self.authorize_channel(channel_id, required).await {
+                Ok(server_id) => channel_server = Some(server_id),
+                Err(err) => return self.send_error(err).await,
+            }
+        }
+
+        let first_op = !self.handled_op;
+        self.handled_op = true;
+
+        match (op, channel_id) {
+            (ClientOp::Resume { session_id, seq, .. }, _) => {
+                if !first_op {
+                    return self
+                        .send_error(ErrorPayload::new(
+                            ErrorCode::ResumeFailed,
+                            "resume must be the first op on a connection",
+                        ))
+                        .await;
+                }
+                self.resume(session_id, seq).await.map(|_| ())
+            }
+            (ClientOp::JoinChannel { .. }, Some(channel_id)) => {
+                if let Some(server_id) = channel_server {
+                    if self.joined.insert(channel_id, server_id).is_none() {
+                        self.subscribe(channel_topic(&channel_id)).await?;
                     }
-                    Some(Ok(_)) => {}
-                    Some(Err(_)) | None => break,
                 }
+                Ok(())
             }
+            (ClientOp::LeaveChannel { .. }, Some(channel_id)) => self.leave_channel(channel_id).await,
+            (ClientOp::SendMessage { content, .. }, Some(channel_id)) => {
+                match create_message(&self.db, channel_id, self.user_id, content).await {
+                    Ok(message) => {
+                        let topic = channel_topic(&channel_id);
+                        self.hub.publish(&topic, ServerEvent::MessageCreate(message)).await?;
+                        Ok(())
+                    }
+                    Err(err) => self.send_error(err).await,
+                }
+            }
+            (ClientOp::Typing { .. }, Some(channel_id)) => {
+                // Throttled typing ops are dropped silently; the last event is still showing
+                if typing::try_start(&mut self.conn, &channel_id, &self.user_id).await? {
+                    let typing = typing::typing_start(&self.user_id, &channel_id, chrono::Utc::now());
+                    self.hub
+                        .publish(&channel_topic(&channel_id), ServerEvent::TypingStart(typing))
+                        .await?;
+                }
+                Ok(())
+            }
+            (ClientOp::PresenceUpdate { status, custom_status }, _) => {
+                let presence = Presence { status, custom_status };
+                presence.save(&mut self.conn, &self.user_id).await?;
+                self.publish_presence(presence.visible_to_others(&self.user_id)).await
+            }
+            (ClientOp::Ack { message_id, .. }, Some(channel_id)) => {
+                match self.ack(channel_id, &message_id).await {
+                    Ok(()) => Ok(()),
+                    Err(err) => self.send_error(err).await,
+                }
+            }
+            // validate() returns a channel for every channel op
+            _ => Ok(()),
         }
     }
+
+    // The server the channel belongs to, if the user has every `required` permission in it
+    async fn authorize_channel(&self, channel_id: ObjectId, required: u64) -> Result<ObjectId, ErrorPayload> {
+        let (channel, permissions) = access::in_channel(&self.db, channel_id, self.user_id)
+            .await
+            .map_err(|_| ErrorPayload::new(ErrorCode::Internal, "failed to look up channel"))?
+            .ok_or_else(|| ErrorPayload::new(ErrorCode::UnknownChannel, "channel does not exist"))?;
+
+        match permissions {
+            None => Err(ErrorPayload::new(
+                ErrorCode::Forbidden,
+                "you are not a member of this channel's server",
+            )),
+            Some(permissions) if !has(permissions, required) => Err(ErrorPayload::new(
+                ErrorCode::Forbidden,
+                "you are missing permissions in this channel",
+            )),
+            Some(_) => Ok(channel.server_id),
+        }
+    }
+
+    // Records how far the user has read in a channel
+    async fn ack(&self, channel_id: ObjectId, message_id: &str) -> Result<(), ErrorPayload> {
+        let message_oid = ObjectId::parse_str(message_id)
+            .map_err(|_| ErrorPayload::new(ErrorCode::InvalidPayload, "message_id is not a valid id"))?;
+        let now = mongodb::bson::to_bson(&chrono::Utc::now())
+            .map_err(|_| ErrorPayload::new(ErrorCode::Internal, "failed to encode timestamp"))?;
+
+        let read_states: Collection<models::ReadState> = self.db.collection("read_states");
+        read_states
+            .update_one(
+                doc! { "user_id": self.user_id, "channel_id": channel_id },
+                doc! { "$set": { "last_message_id": message_oid, "updated_at": now } },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await
+            .map_err(|_| ErrorPayload::new(ErrorCode::Internal, "failed to store read state"))?;
+        Ok(())
+    }
+
+    // Sends READY with everything the client needs to render the user's servers
+    async fn send_ready(&mut self) -> GatewayResult {
+        let ready = self.load_ready().await?;
+        self.send_event(&ServerEvent::Ready(ready)).await
+    }
+
+    async fn load_ready(&mut self) -> GatewayResult<Ready> {
+        let server_ids: Vec<ObjectId> = self.servers.iter().copied().collect();
+
+        let servers: Collection<models::Server> = self.db.collection("servers");
+        let servers: Vec<models::Server> = servers
+            .find(doc! { "_id": { "$in": &server_ids } }, None)
+            .await?
+            .try_collect()
+            .await?;
+
+        let members: Collection<models::Member> = self.db.collection("members");
+        let members: HashMap<ObjectId, models::Member> = members
+            .find(doc! { "user_id": self.user_id }, None)
+            .await?
+            .try_collect::<Vec<models::Member>>()
+            .await?
+            .into_iter()
+            .map(|member| (member.server_id, member))
+            .collect();
+
+        // Channels hidden from the user are left out
+        let channels: Collection<models::Channel> = self.db.collection("channels");
+        let channels = channels
+            .find(doc! { "server_id": { "$in": &server_ids } }, None)
+            .await?
+            .try_collect::<Vec<models::Channel>>()
+            .await?
+            .into_iter()
+            .filter(|channel| {
+                let server = servers.iter().find(|server| server.id == Some(channel.server_id));
+                match (server, members.get(&channel.server_id)) {
+                    (Some(server), Some(member)) => permissions::channel_permissions(server, member, channel) != 0,
+                    _ => false,
+                }
+            })
+            .collect();
+
+        let read_states: Collection<models::ReadState> = self.db.collection("read_states");
+        let read_states = read_states
+            .find(doc! { "user_id": self.user_id }, None)
+            .await?
+            .try_collect()
+            .await?;
+
+        let mut member_ids = membership::member_ids(&self.db, &server_ids).await?;
+        member_ids.remove(&self.user_id);
+        let member_ids: Vec<ObjectId> = member_ids.into_iter().collect();
+        let presences = presence::load_many(&mut self.conn, &member_ids)
+            .await?
+            .into_iter()
+            .map(|(user_id, presence)| presence.visible_to_others(&user_id))
+            .filter(|update| update.status != PresenceStatus::Offline)
+            .collect();
+
+        Ok(Ready {
+            session_id: self.session_id.clone(),
+            user_id: self.user_id.to_hex(),
+            servers,
+            channels,
+            read_states,
+            presences,
+        })
+    }
+
+    // Takes over a suspended session: replays buffered frames the client did not
+    // acknowledge, then catches up on topic events published while it was away.
+    // Returns false, after sending INVALID_SESSION, if the session can't be resumed.
+    async fn resume(&mut self, session_id: String, client_seq: u64) -> GatewayResult<bool> {
+        // Another socket is resuming the same session, or already has
+        if !SessionState::claim(&mut self.conn, &session_id, &self.socket_id).await? {
+            return Err(CloseCode::SessionInvalidated.into());
+        }
+        let state = match SessionState::take(&mut self.conn, &session_id).await? {
+            // Someone else's session stays theirs to resume
+            Some(state) if state.user_id != self.user_id => {
+                state.save(&mut self.conn, &session_id).await?;
+                None
+            }
+            state => state.filter(|s| client_seq <= s.seq),
+        };
+        let Some(state) = state else {
+            return self.reject_resume(&session_id).await;
+        };
+
+        // Every frame after the client's seq must still be in the buffer
+        let buffered = replay::buffered_since(&mut self.conn, &session_id, client_seq).await?;
+        if buffered.len() as u64 != state.seq - client_seq {
+            return self.reject_resume(&session_id).await;
+        }
+
+        // Drop the fresh session in favour of the resumed one
+        for topic in self.topics() {
+            self.unsubscribe(topic).await?;
+        }
+        SessionState::delete(&mut self.conn, &self.session_id).await?;
+
+        self.session_id = session_id;
+        self.seq = state.seq;
+        self.cursors = state.cursors;
+        self.properties = state.properties;
+        self.capabilities = state.capabilities;
+        self.joined.clear();
+        for channel_id in state.joined {
+            if let Ok(server_id) = self.authorize_channel(channel_id, VIEW_CHANNEL).await {
+                self.joined.insert(channel_id, server_id);
+            }
+        }
+        for topic in self.topics() {
+            self.subscribe(topic).await?;
+        }
+
+        for (_, frame) in buffered {
+            let Ok(frame) = serde_json::from_str::<serde_json::Value>(&frame) else { continue };
+            self.sender.send(self.encoder.encode(&frame)).await?;
+        }
+        for topic in self.topics() {
+            self.catch_up(&topic).await?;
+        }
+
+        self.send_event(&ServerEvent::Resumed).await?;
+        Ok(true)
+    }
+
+    async fn reject_resume(&mut self, session_id: &str) -> GatewayResult<bool> {
+        SessionState::release(&mut self.conn, session_id).await?;
+        self.send_event(&ServerEvent::InvalidSession).await?;
+        Ok(false)
+    }
+
+    // Whether the user logged out the auth session this socket was opened with, or
+    // its bot token was deleted
+    async fn credential_revoked(&mut self) -> mongodb::error::Result<bool> {
+        self.credential.revoked(&self.db, &mut self.cache, &self.user_id).await
+    }
+
+    // Sends a presence update to everyone sharing a server with the user
+    async fn publish_presence(&self, update: PresenceUpdate) -> GatewayResult {
+        for server_id in &self.servers {
+            self.hub
+                .publish(&server_topic(server_id), ServerEvent::PresenceUpdate(update.clone()))
+                .await?;
+        }
+        Ok(())
+    }
+
+    // Registers the socket in the user's presence, announcing the user if it is their first session
+    async fn connect_presence(&mut self) -> GatewayResult {
+        let first_session = presence::live_sessions(&mut self.conn, &self.user_id).await? == 0;
+        presence::refresh(&mut self.conn, &self.user_id, &self.socket_id).await?;
+
+        let presence = match Presence::load(&mut self.conn, &self.user_id).await? {
+            Some(presence) => presence,
+            None => {
+                let presence = Presence::default();
+                presence.save(&mut self.conn, &self.user_id).await?;
+                presence
+            }
+        };
+        let update = presence.visible_to_others(&self.user_id);
+        if first_session && update.status != PresenceStatus::Offline {
+            self.publish_presence(update).await?;
+        }
+        Ok(())
+    }
+
+    // Removes the socket from the user's presence; when it was their last session, records
+    // when they were last seen, ends their temporary memberships and tells everyone they went
+    // offline
+    async fn disconnect_presence(&mut self) -> GatewayResult {
+        if !presence::disconnect(&mut self.conn, &self.user_id, &self.socket_id).await? {
+            return Ok(());
+        }
+
+        let now = mongodb::bson::to_bson(&chrono::Utc::now()).expect("timestamps are serializable");
+        let users: Collection<mongodb::bson::Document> = self.db.collection("users");
+        users
+            .update_one(doc! { "_id": self.user_id }, doc! { "$set": { "last_seen": now } }, None)
+            .await?;
+        for server_id in membership::remove_temporary(&self.db, self.user_id).await? {
+            membership::announce_leave(&self.hub, self.user_id, server_id).await?;
+        }
+
+        self.publish_presence(Presence::offline(&self.user_id)).await
+    }
+
+    // Saves the session so a reconnecting client can resume it
+    async fn suspend(&mut self) -> redis::RedisResult<()> {
+        let state = SessionState {
+            user_id: self.user_id,
+            seq: self.seq,
+            joined: self.joined.keys().copied().collect(),
+            cursors: self.cursors.clone(),
+            properties: self.properties.clone(),
+            capabilities: self.capabilities.clone(),
+        };
+        state.save(&mut self.conn, &self.session_id).await?;
+        SessionState::release(&mut self.conn, &self.session_id).await
+    }
+}
+
+async fn create_message(
+    db: &mongodb::Database,
+    channel_id: ObjectId,
+    user_id: ObjectId,
+    content: String,
+) -> Result<models::Message, ErrorPayload> {
+    let message = models::Message {
+        id: None,
+        channel_id,
+        user_id,
+        content,
+        attachments: Vec::new(),
+        created_at: chrono::Utc::now(),
+    };
+
+    let messages: Collection<models::Message> = db.collection("messages");
+    let result = messages
+        .insert_one(&message, None)
+        .await
+        .map_err(|_| ErrorPayload::new(ErrorCode::Internal, "failed to store message"))?;
+
+    let mut message = message;
+    message.id = result.inserted_id.as_object_id();
+
+    Ok(message)
 }
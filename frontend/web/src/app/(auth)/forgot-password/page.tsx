@@ -0,0 +1,75 @@
+"use client";
+
+import { useState } from "react";
+import Link from "next/link";
+import { authApi } from "@/lib/api";
+
+export default function ForgotPasswordPage() {
+    const [email, setEmail] = useState("");
+    const [sent, setSent] = useState(false);
+    const [error, setError] = useState("");
+    const [loading, setLoading] = useState(false);
+
+    const handleSubmit = async (e: React.FormEvent) => {
+        e.preventDefault();
+        setError("");
+        setLoading(true);
+
+        try {
+            await authApi("/password/forgot", {
+                method: "POST",
+                body: JSON.stringify({ email }),
+            });
+            setSent(true);
+        } catch (err: any) {
+            setError(err.message || "Request failed");
+        } finally {
+            setLoading(false);
+        }
+    };
+
+    return (
+        <div className="min-h-screen bg-gradient-to-b from-gray-900 to-black flex items-center justify-center p-4">
+            <form onSubmit={handleSubmit} className="w-full max-w-md bg-gray-800 p-8 rounded-lg shadow-xl">
+                <h1 className="text-2xl font-bold text-white mb-6">Reset your password</h1>
+
+                {error && (
+                    <div className="mb-4 p-3 bg-red-500/10 border border-red-500 rounded text-red-500 text-sm">
+                        {error}
+                    </div>
+                )}
+
+                {sent ? (
+                    <p className="text-gray-300">
+                        If {email} has an account, we sent it a link to choose a new password.
+                    </p>
+                ) : (
+                    <>
+                        <label className="block text-sm font-semibold text-gray-300 mb-2">Email</label>
+                        <input
+                            type="email"
+                            value={email}
+                            onChange={(e) => setEmail(e.target.value)}
+                            required
+                            className="w-full px-4 py-3 mb-6 bg-gray-900 text-white rounded focus:outline-none focus:ring-2 focus:ring-gaming-green"
+                            placeholder="you@example.com"
+                        />
+                        <button
+                            type="submit"
+                            disabled={loading}
+                            className="w-full bg-gaming-green text-gray-900 font-semibold py-3 rounded hover:bg-gaming-green/80 transition-colors disabled:opacity-50"
+                        >
+                            {loading ? "Sending..." : "Send reset link"}
+                        </button>
+                    </>
+                )}
+
+                <p className="mt-4 text-sm text-gray-400 text-center">
+                    <Link href="/login" className="text-gaming-green hover:underline">
+                        Back to login
+                    </Link>
+                </p>
+            </form>
+        </div>
+    );
+}
@@ -1,32 +1,66 @@
 "use client";
 
-import { useState } from "react";
+import { useEffect, useState } from "react";
 import { useAuthStore } from "@/store/authStore";
 import { useRouter } from "next/navigation";
 import { authApi } from "@/lib/api";
 import Link from "next/link";
 import { MessageSquare } from "lucide-react";
 
-export default function LoginPage() {
+export default function LoginPage({ searchParams }: { searchParams: { ticket?: string } }) {
     const [email, setEmail] = useState("");
     const [password, setPassword] = useState("");
     const [error, setError] = useState("");
+    // Set when the password was accepted and a second factor is required
+    // (an identity provider login that needs a second factor arrives with one)
+    const [mfaTicket, setMfaTicket] = useState<string | null>(searchParams.ticket ?? null);
+    const [code, setCode] = useState("");
+    const [useRecoveryCode, setUseRecoveryCode] = useState(false);
     const [loading, setLoading] = useState(false);
+    const [providers, setProviders] = useState<{ id: string; name: string }[]>([]);
     const { setUser } = useAuthStore();
     const router = useRouter();
 
+    useEffect(() => {
+        authApi("/oidc/providers").then(setProviders).catch(() => setProviders([]));
+    }, []);
+
+    const loginWith = async (provider: string) => {
+        setError("");
+        try {
+            const { authorization_url } = await authApi(`/oidc/${provider}/start`, { method: "POST" });
+            window.location.href = authorization_url;
+        } catch (err: any) {
+            setError(err.message || "Login failed");
+        }
+    };
+
     const handleSubmit = async (e: React.FormEvent) => {
         e.preventDefault();
         setError("");
         setLoading(true);
 
         try {
-            const response = await authApi("/login", {
-                method: "POST",
-                body: JSON.stringify({ email, password }),
-            });
+            const response = mfaTicket
+                ? await authApi("/login/mfa", {
+                      method: "POST",
+                      body: JSON.stringify(
+                          useRecoveryCode
+                              ? { ticket: mfaTicket, recovery_code: code }
+                              : { ticket: mfaTicket, code }
+                      ),
+                  })
+                : await authApi("/login", {
+                      method: "POST",
+                      body: JSON.stringify({ email, password }),
+                  });
 
-            setUser(response.user, response.token);
+            if (response.mfa_required) {
+                setMfaTicket(response.ticket);
+                return;
+            }
+
+            setUser(response.user, response.token, response.refresh_token);
             router.push("/");
         } catch (err: any) {
             setError(err.message || "Login failed");
@@ -56,6 +90,35 @@ export default function LoginPage() {
                         </div>
                     )}
 
+                    {mfaTicket ? (
+                    <div className="mb-6">
+                        <label className="block text-sm font-semibold text-gray-300 mb-2">
+                            {useRecoveryCode ? "Recovery code" : "Authentication code"}
+                        </label>
+                        <input
+                            type="text"
+                            value={code}
+                            onChange={(e) => setCode(e.target.value)}
+                            required
+                            autoFocus
+                            autoComplete="one-time-code"
+                            inputMode={useRecoveryCode ? "text" : "numeric"}
+                            className="w-full px-4 py-3 bg-gray-900 text-white rounded focus:outline-none focus:ring-2 focus:ring-gaming-green"
+                            placeholder={useRecoveryCode ? "xxxx-xxxx-xxxx" : "123456"}
+                        />
+                        <button
+                            type="button"
+                            onClick={() => {
+                                setUseRecoveryCode(!useRecoveryCode);
+                                setCode("");
+                            }}
+                            className="mt-2 text-sm text-gaming-green hover:underline"
+                        >
+                            {useRecoveryCode ? "Use your authenticator app" : "Use a recovery code"}
+                        </button>
+                    </div>
+                    ) : (
+                    <>
                     <div className="mb-4">
                         <label className="block text-sm font-semibold text-gray-300 mb-2">
                             Email
@@ -82,16 +145,33 @@ export default function LoginPage() {
                             className="w-full px-4 py-3 bg-gray-900 text-white rounded focus:outline-none focus:ring-2 focus:ring-gaming-green"
                             placeholder="••••••••"
                         />
+                        <Link href="/forgot-password" className="mt-2 inline-block text-sm text-gaming-green hover:underline">
+                            Forgot your password?
+                        </Link>
                     </div>
+                    </>
+                    )}
 
                     <button
                         type="submit"
                         disabled={loading}
                         className="w-full bg-gaming-green text-gray-900 font-semibold py-3 rounded hover:bg-gaming-green/80 transition-colors disabled:opacity-50"
                     >
-                        {loading ? "Logging in..." : "Login"}
+                        {loading ? "Logging in..." : mfaTicket ? "Verify" : "Login"}
                     </button>
 
+                    {!mfaTicket &&
+                        providers.map((provider) => (
+                            <button
+                                key={provider.id}
+                                type="button"
+                                onClick={() => loginWith(provider.id)}
+                                className="mt-3 w-full border border-gray-600 text-gray-200 font-semibold py-3 rounded hover:bg-gray-700 transition-colors"
+                            >
+                                Continue with {provider.name}
+                            </button>
+                        ))}
+
                     <p className="mt-4 text-sm text-gray-400 text-center">
                         Need an account?{" "}
                         <Link href="/register" className="text-gaming-green hover:underline">
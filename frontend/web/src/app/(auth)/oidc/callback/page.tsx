@@ -0,0 +1,60 @@
+"use client";
+
+import { useEffect, useRef, useState } from "react";
+import Link from "next/link";
+import { useRouter } from "next/navigation";
+import { useAuthStore } from "@/store/authStore";
+import { authApi } from "@/lib/api";
+
+// The identity provider redirects here after the user logged in there
+export default function OidcCallbackPage({
+    searchParams,
+}: {
+    searchParams: { code?: string; state?: string; error?: string; error_description?: string };
+}) {
+    const [error, setError] = useState("");
+    const { setUser } = useAuthStore();
+    const router = useRouter();
+    // Codes work once, so don't send it twice when effects run twice in development
+    const sent = useRef(false);
+
+    useEffect(() => {
+        if (sent.current) return;
+        sent.current = true;
+
+        if (searchParams.error || !searchParams.code || !searchParams.state) {
+            setError(searchParams.error_description || searchParams.error || "Login was cancelled");
+            return;
+        }
+        authApi("/oidc/callback", {
+            method: "POST",
+            body: JSON.stringify({ code: searchParams.code, state: searchParams.state }),
+        })
+            .then((response) => {
+                if (response.mfa_required) {
+                    router.replace(`/login?ticket=${encodeURIComponent(response.ticket)}`);
+                    return;
+                }
+                setUser(response.user, response.token, response.refresh_token);
+                router.replace("/");
+            })
+            .catch((err: any) => setError(err.message || "Login failed"));
+    }, [searchParams, setUser, router]);
+
+    return (
+        <div className="min-h-screen bg-gradient-to-b from-gray-900 to-black flex items-center justify-center p-4">
+            <div className="w-full max-w-md bg-gray-800 p-8 rounded-lg shadow-xl text-center">
+                {error ? (
+                    <>
+                        <p className="text-red-500">{error}</p>
+                        <Link href="/login" className="mt-6 inline-block text-gaming-green hover:underline">
+                            Back to login
+                        </Link>
+                    </>
+                ) : (
+                    <p className="text-gray-400">Logging you in...</p>
+                )}
+            </div>
+        </div>
+    );
+}
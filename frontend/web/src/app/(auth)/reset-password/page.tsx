@@ -0,0 +1,92 @@
+"use client";
+
+import { useState } from "react";
+import Link from "next/link";
+import { authApi } from "@/lib/api";
+
+export default function ResetPasswordPage({ searchParams }: { searchParams: { token?: string } }) {
+    const [password, setPassword] = useState("");
+    const [confirmPassword, setConfirmPassword] = useState("");
+    const [done, setDone] = useState(false);
+    const [error, setError] = useState("");
+    const [loading, setLoading] = useState(false);
+
+    const handleSubmit = async (e: React.FormEvent) => {
+        e.preventDefault();
+        setError("");
+
+        if (password !== confirmPassword) {
+            setError("Passwords do not match");
+            return;
+        }
+        if (password.length < 8) {
+            setError("Password must be at least 8 characters");
+            return;
+        }
+
+        setLoading(true);
+        try {
+            await authApi("/password/reset", {
+                method: "POST",
+                body: JSON.stringify({ token: searchParams.token ?? "", password }),
+            });
+            setDone(true);
+        } catch (err: any) {
+            setError(err.message || "Reset failed");
+        } finally {
+            setLoading(false);
+        }
+    };
+
+    return (
+        <div className="min-h-screen bg-gradient-to-b from-gray-900 to-black flex items-center justify-center p-4">
+            <form onSubmit={handleSubmit} className="w-full max-w-md bg-gray-800 p-8 rounded-lg shadow-xl">
+                <h1 className="text-2xl font-bold text-white mb-6">Choose a new password</h1>
+
+                {error && (
+                    <div className="mb-4 p-3 bg-red-500/10 border border-red-500 rounded text-red-500 text-sm">
+                        {error}
+                    </div>
+                )}
+
+                {done ? (
+                    <p className="text-gray-300">Your password was changed and you were logged out everywhere.</p>
+                ) : (
+                    <>
+                        <label className="block text-sm font-semibold text-gray-300 mb-2">New password</label>
+                        <input
+                            type="password"
+                            value={password}
+                            onChange={(e) => setPassword(e.target.value)}
+                            required
+                            className="w-full px-4 py-3 mb-4 bg-gray-900 text-white rounded focus:outline-none focus:ring-2 focus:ring-gaming-green"
+                            placeholder="••••••••"
+                        />
+                        <label className="block text-sm font-semibold text-gray-300 mb-2">Confirm password</label>
+                        <input
+                            type="password"
+                            value={confirmPassword}
+                            onChange={(e) => setConfirmPassword(e.target.value)}
+                            required
+                            className="w-full px-4 py-3 mb-6 bg-gray-900 text-white rounded focus:outline-none focus:ring-2 focus:ring-gaming-green"
+                            placeholder="••••••••"
+                        />
+                        <button
+                            type="submit"
+                            disabled={loading}
+                            className="w-full bg-gaming-green text-gray-900 font-semibold py-3 rounded hover:bg-gaming-green/80 transition-colors disabled:opacity-50"
+                        >
+                            {loading ? "Saving..." : "Set password"}
+                        </button>
+                    </>
+                )}
+
+                <p className="mt-4 text-sm text-gray-400 text-center">
+                    <Link href="/login" className="text-gaming-green hover:underline">
+                        Back to login
+                    </Link>
+                </p>
+            </form>
+        </div>
+    );
+}
@@ -0,0 +1,46 @@
+"use client";
+
+import { useEffect, useRef, useState } from "react";
+import Link from "next/link";
+import { authApi } from "@/lib/api";
+
+export default function UnlockAccountPage({ searchParams }: { searchParams: { token?: string } }) {
+    const [status, setStatus] = useState<"unlocking" | "unlocked" | "failed">("unlocking");
+    const [error, setError] = useState("");
+    // Tokens work once, so don't send it twice when effects run twice in development
+    const sent = useRef(false);
+
+    useEffect(() => {
+        if (sent.current) return;
+        sent.current = true;
+
+        if (!searchParams.token) {
+            setStatus("failed");
+            setError("This link is missing its token");
+            return;
+        }
+        authApi("/login/unlock", {
+            method: "POST",
+            body: JSON.stringify({ token: searchParams.token }),
+        })
+            .then(() => setStatus("unlocked"))
+            .catch((err: any) => {
+                setStatus("failed");
+                setError(err.message || "Unlock failed");
+            });
+    }, [searchParams.token]);
+
+    return (
+        <div className="min-h-screen bg-gradient-to-b from-gray-900 to-black flex items-center justify-center p-4">
+            <div className="w-full max-w-md bg-gray-800 p-8 rounded-lg shadow-xl text-center">
+                <h1 className="text-2xl font-bold text-white mb-4">Unlock your account</h1>
+                {status === "unlocking" && <p className="text-gray-400">Unlocking your account...</p>}
+                {status === "unlocked" && <p className="text-gray-300">Your account is unlocked. You can log in again.</p>}
+                {status === "failed" && <p className="text-red-500">{error}</p>}
+                <Link href="/login" className="mt-6 inline-block text-gaming-green hover:underline">
+                    Go to login
+                </Link>
+            </div>
+        </div>
+    );
+}
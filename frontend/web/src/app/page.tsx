@@ -9,6 +9,7 @@ import { useAuthStore } from "@/store/authStore";
 import { useServerStore } from "@/store/serverStore";
 import { useChannelStore } from "@/store/channelStore";
 import { useMessageStore } from "@/store/messageStore";
+import { useTypingStore } from "@/store/typingStore";
 import { useEffect, useState } from "react";
 import { useRouter } from "next/navigation";
 import websocket from "@/services/websocket";
@@ -18,6 +19,7 @@ export default function ChatPage() {
   const { fetchServers, currentServer } = useServerStore();
   const { fetchChannels, currentChannel } = useChannelStore();
   const { addMessage, fetchMessages } = useMessageStore();
+  const { startTyping, stopTyping } = useTypingStore();
   const [connected, setConnected] = useState(false);
   const router = useRouter();
 
@@ -29,8 +31,12 @@ export default function ChatPage() {
       setConnected(true);
 
       websocket.onMessage((msg) => {
-        if (msg.type === "message") {
+        if (msg.type === "MESSAGE_CREATE") {
           addMessage(msg.data);
+          stopTyping(msg.data.channel_id, msg.data.user_id);
+        }
+        if (msg.type === "TYPING_START") {
+          startTyping(msg.data.channel_id, msg.data.user_id, msg.data.expires_at);
         }
       });
 
@@ -38,7 +44,7 @@ export default function ChatPage() {
         websocket.disconnect();
       };
     }
-  }, [token, router, addMessage]);
+  }, [token, router, addMessage, startTyping, stopTyping]);
 
   useEffect(() => {
     if (user) {
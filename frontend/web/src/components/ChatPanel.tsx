@@ -3,6 +3,7 @@
 import { useChannelStore } from "@/store/channelStore";
 import { useMessageStore } from "@/store/messageStore";
 import { useAuthStore } from "@/store/authStore";
+import { useTypingStore } from "@/store/typingStore";
 import { useState, useEffect, useRef } from "react";
 import { Hash, Send } from "lucide-react";
 import { formatDate } from "@/lib/utils";
@@ -13,6 +14,7 @@ export function ChatPanel() {
     const { currentChannel } = useChannelStore();
     const { messages, loading } = useMessageStore();
     const { user } = useAuthStore();
+    const { typing } = useTypingStore();
     const [input, setInput] = useState("");
     const messagesEndRef = useRef<HTMLDivElement>(null);
 
@@ -47,6 +49,8 @@ export function ChatPanel() {
         );
     }
 
+    const typingUsers = Object.keys(typing[currentChannel.id] ?? {}).filter((id) => id !== user?.id);
+
     return (
         <div className="flex-1 bg-[#313338] flex flex-col relative">
             {/* Channel header */}
@@ -112,6 +116,10 @@ export function ChatPanel() {
                         <Send className="w-5 h-5" />
                     </button>
                 </form>
+                <div className="h-4 mt-1 px-1 text-xs text-gray-400">
+                    {typingUsers.length === 1 && "Someone is typing..."}
+                    {typingUsers.length > 1 && "Several people are typing..."}
+                </div>
             </div>
         </div>
     );
@@ -34,7 +34,29 @@ export const useAuth = () => {
         }
 
         const data = await response.json();
-        setUser(data.user, data.token);
+        // Two-factor accounts get a ticket to finish the login with completeMfa
+        if (data.mfa_required) {
+            return { mfaTicket: data.ticket as string };
+        }
+        setUser(data.user, data.token, data.refresh_token);
+        return { mfaTicket: null };
+    };
+
+    const completeMfa = async (ticket: string, code: string, isRecoveryCode = false) => {
+        const AUTH_BASE = process.env.NEXT_PUBLIC_AUTH_URL || "http://localhost:8081";
+        const response = await fetch(`${AUTH_BASE}/login/mfa`, {
+            method: "POST",
+            headers: { "Content-Type": "application/json" },
+            body: JSON.stringify(isRecoveryCode ? { ticket, recovery_code: code } : { ticket, code }),
+        });
+
+        if (!response.ok) {
+            const error = await response.text();
+            throw new Error(error || "Verification failed");
+        }
+
+        const data = await response.json();
+        setUser(data.user, data.token, data.refresh_token);
     };
 
     return {
@@ -42,6 +64,7 @@ export const useAuth = () => {
         token,
         register,
         login,
+        completeMfa,
         logout,
         loading: false, // For now
         error: null,
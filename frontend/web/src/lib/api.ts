@@ -1,15 +1,28 @@
+import { useAuthStore } from "@/store/authStore";
+
 const API_BASE = process.env.NEXT_PUBLIC_API_URL || "http://localhost:8080";
 
-export async function api(endpoint: string, options: RequestInit = {}) {
-    // Get token from localStorage if available
-    let token: string | null = null;
-    if (typeof window !== "undefined") {
-        const authStorage = localStorage.getItem("auth-storage");
-        if (authStorage) {
-            const parsed = JSON.parse(authStorage);
-            token = parsed.state?.token;
-        }
+// Exchanges the stored refresh token for new tokens; logs out if it was rejected
+async function refreshTokens(): Promise<boolean> {
+    const { user, refreshToken, setUser, logout } = useAuthStore.getState();
+    if (!refreshToken) {
+        return false;
+    }
+    try {
+        const data = await authApi("/token/refresh", {
+            method: "POST",
+            body: JSON.stringify({ refresh_token: refreshToken }),
+        });
+        setUser(data.user ?? user, data.token, data.refresh_token);
+        return true;
+    } catch {
+        logout();
+        return false;
     }
+}
+
+export async function api(endpoint: string, options: RequestInit = {}, retry = true): Promise<any> {
+    const { token } = useAuthStore.getState();
 
     const headers: HeadersInit = {
         "Content-Type": "application/json",
@@ -22,11 +35,19 @@ export async function api(endpoint: string, options: RequestInit = {}) {
         headers,
     });
 
+    // The access token expired: refresh it once and retry
+    if (res.status === 401 && retry && (await refreshTokens())) {
+        return api(endpoint, options, false);
+    }
+
     if (!res.ok) {
         const error = await res.text();
         throw new Error(error || `HTTP ${res.status}`);
     }
 
+    if (res.status === 204) {
+        return null;
+    }
     return res.json();
 }
 
@@ -1,22 +1,39 @@
 type MessageCallback = (msg: any) => void;
 
+const TYPING_THROTTLE_MS = 5000;
+
 class WebSocketManager {
     private ws: WebSocket | null = null;
     private messageCallbacks: MessageCallback[] = [];
     private token: string | null = null;
+    private heartbeatTimer: ReturnType<typeof setInterval> | null = null;
+    private lastSeq: number | null = null;
+    // Last time a typing op was sent, per channel
+    private lastTyping: Record<string, number> = {};
 
     connect(token: string) {
         this.token = token;
         const WS_URL = process.env.NEXT_PUBLIC_WS_URL || "ws://localhost:8080/ws";
-        this.ws = new WebSocket(`${WS_URL}?token=${token}`);
+        this.ws = new WebSocket(WS_URL);
 
         this.ws.onopen = () => {
             console.log("WebSocket connected");
+            this.send({ type: "identify", token, properties: { browser: navigator.userAgent } });
         };
 
         this.ws.onmessage = (event) => {
             try {
                 const message = JSON.parse(event.data);
+                if (typeof message.seq === "number") {
+                    this.lastSeq = message.seq;
+                }
+                if (message.type === "HELLO") {
+                    this.startHeartbeat(message.data.heartbeat_interval);
+                    return;
+                }
+                if (message.type === "HEARTBEAT_ACK") {
+                    return;
+                }
                 this.messageCallbacks.forEach(callback => callback(message));
             } catch (error) {
                 console.error("Failed to parse message:", error);
@@ -29,16 +46,32 @@ class WebSocketManager {
 
         this.ws.onclose = () => {
             console.log("WebSocket disconnected");
+            this.stopHeartbeat();
         };
     }
 
     disconnect() {
+        this.stopHeartbeat();
         if (this.ws) {
             this.ws.close();
             this.ws = null;
         }
     }
 
+    private startHeartbeat(interval: number) {
+        this.stopHeartbeat();
+        this.heartbeatTimer = setInterval(() => {
+            this.send({ type: "heartbeat", seq: this.lastSeq });
+        }, interval);
+    }
+
+    private stopHeartbeat() {
+        if (this.heartbeatTimer) {
+            clearInterval(this.heartbeatTimer);
+            this.heartbeatTimer = null;
+        }
+    }
+
     onMessage(callback: MessageCallback) {
         this.messageCallbacks.push(callback);
     }
@@ -56,6 +89,12 @@ class WebSocketManager {
     }
 
     sendTyping(channelId: string) {
+        // The server ignores typing ops within its 5 second throttle, so don't spend the rate limit on them
+        const now = Date.now();
+        if (now - (this.lastTyping[channelId] ?? 0) < TYPING_THROTTLE_MS) {
+            return;
+        }
+        this.lastTyping[channelId] = now;
         this.send({ type: "typing", channel_id: channelId });
     }
 
@@ -10,7 +10,8 @@ interface User {
 interface AuthState {
     user: User | null;
     token: string | null;
-    setUser: (user: User, token: string) => void;
+    refreshToken: string | null;
+    setUser: (user: User, token: string, refreshToken: string) => void;
     logout: () => void;
 }
 
@@ -19,8 +20,9 @@ export const useAuthStore = create<AuthState>()(
         (set) => ({
             user: null,
             token: null,
-            setUser: (user, token) => set({ user, token }),
-            logout: () => set({ user: null, token: null }),
+            refreshToken: null,
+            setUser: (user, token, refreshToken) => set({ user, token, refreshToken }),
+            logout: () => set({ user: null, token: null, refreshToken: null }),
         }),
         { name: "auth-storage" }
     )
@@ -4,8 +4,9 @@ import { api } from "@/lib/api";
 interface Server {
     id: string;
     name: string;
+    description: string | null;
+    icon_url: string | null;
     owner_id: string;
-    invite_code: string;
     created_at: string;
 }
 
@@ -16,6 +17,8 @@ interface ServerState {
     fetchServers: () => Promise<void>;
     setCurrentServer: (server: Server) => void;
     createServer: (name: string) => Promise<Server>;
+    joinServer: (inviteCode: string) => Promise<Server>;
+    leaveServer: (serverId: string) => Promise<void>;
 }
 
 export const useServerStore = create<ServerState>((set, get) => ({
@@ -44,4 +47,22 @@ export const useServerStore = create<ServerState>((set, get) => ({
         set({ servers: [...get().servers, server] });
         return server;
     },
+
+    joinServer: async (inviteCode: string) => {
+        const server = await api(`/invites/${encodeURIComponent(inviteCode)}`, {
+            method: "POST",
+        });
+        const servers = get().servers.filter((s) => s.id !== server.id);
+        set({ servers: [...servers, server] });
+        return server;
+    },
+
+    leaveServer: async (serverId: string) => {
+        await api(`/servers/${serverId}/members/@me`, { method: "DELETE" });
+        const { servers, currentServer } = get();
+        set({
+            servers: servers.filter((s) => s.id !== serverId),
+            currentServer: currentServer?.id === serverId ? null : currentServer,
+        });
+    },
 }));
@@ -0,0 +1,35 @@
+import { create } from "zustand";
+
+interface TypingState {
+    // Users typing per channel, with the time (ms) each indicator expires
+    typing: Record<string, Record<string, number>>;
+    startTyping: (channelId: string, userId: string, expiresAt: string) => void;
+    stopTyping: (channelId: string, userId: string) => void;
+}
+
+export const useTypingStore = create<TypingState>((set, get) => ({
+    typing: {},
+
+    startTyping: (channelId, userId, expiresAt) => {
+        const expires = new Date(expiresAt).getTime();
+        set((state) => ({
+            typing: {
+                ...state.typing,
+                [channelId]: { ...state.typing[channelId], [userId]: expires },
+            },
+        }));
+        // The server sends no stop event; drop the indicator once it expires unless refreshed
+        setTimeout(() => {
+            if (get().typing[channelId]?.[userId] === expires) {
+                get().stopTyping(channelId, userId);
+            }
+        }, Math.max(expires - Date.now(), 0));
+    },
+
+    stopTyping: (channelId, userId) => {
+        set((state) => {
+            const { [userId]: _, ...rest } = state.typing[channelId] ?? {};
+            return { typing: { ...state.typing, [channelId]: rest } };
+        });
+    },
+}));